@@ -0,0 +1,78 @@
+/// Text embeddings, and tool routing built on them: with dozens of tools,
+/// only the few whose descriptions are closest to the user's request are
+/// offered to the model, keeping prompts small.
+use async_trait::async_trait;
+
+use crate::{AgentError, Tool};
+
+/// Turns texts into embedding vectors, e.g. via an embeddings API or a
+/// local model.
+#[async_trait]
+pub trait Embedder: Send + Sync {
+    /// One vector per input text, in order.
+    async fn embed(&self, texts: &[&str]) -> Result<Vec<Vec<f32>>, AgentError>;
+}
+
+/// Picks the `top_k` tools whose descriptions are most similar (by cosine
+/// similarity) to the user input. Descriptions are embedded once, in `new`;
+/// each run embeds only the input. Tools registered on the agent that the
+/// selector wasn't built with are always offered.
+pub struct SemanticToolSelector {
+    embedder: Box<dyn Embedder>,
+    tool_embeddings: Vec<(&'static str, Vec<f32>)>,
+    top_k: usize,
+}
+
+impl SemanticToolSelector {
+    pub async fn new(embedder: Box<dyn Embedder>, tools: &[&dyn Tool], top_k: usize) -> Result<Self, AgentError> {
+        let descriptions: Vec<&str> = tools.iter().map(|t| t.description()).collect();
+        let vectors = embedder.embed(&descriptions).await?;
+        if vectors.len() != tools.len() {
+            return Err(AgentError::InvalidResponse(format!(
+                "expected {} embeddings, got {}",
+                tools.len(),
+                vectors.len()
+            )));
+        }
+        let tool_embeddings = tools.iter().map(|t| t.name()).zip(vectors).collect();
+        Ok(Self { embedder, tool_embeddings, top_k })
+    }
+
+    /// Indices into `tools` to offer for `input`, in registration order.
+    pub async fn select(&self, input: &str, tools: &[&dyn Tool]) -> Result<Vec<usize>, AgentError> {
+        let query = self
+            .embedder
+            .embed(&[input])
+            .await?
+            .pop()
+            .ok_or_else(|| AgentError::InvalidResponse("no embedding returned for input".into()))?;
+
+        let mut ranked: Vec<(&str, f32)> = self
+            .tool_embeddings
+            .iter()
+            .map(|(name, vector)| (*name, cosine_similarity(&query, vector)))
+            .collect();
+        ranked.sort_by(|a, b| b.1.total_cmp(&a.1));
+        let chosen: Vec<&str> = ranked.into_iter().take(self.top_k).map(|(name, _)| name).collect();
+
+        Ok(tools
+            .iter()
+            .enumerate()
+            .filter(|(_, tool)| {
+                chosen.contains(&tool.name()) || !self.tool_embeddings.iter().any(|(name, _)| *name == tool.name())
+            })
+            .map(|(i, _)| i)
+            .collect())
+    }
+}
+
+/// 0.0 when either vector is all zeros or the lengths differ.
+fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    if a.len() != b.len() {
+        return 0.0;
+    }
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm = |v: &[f32]| v.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let denominator = norm(a) * norm(b);
+    if denominator == 0.0 { 0.0 } else { dot / denominator }
+}
@@ -1,11 +1,28 @@
+mod clock;
+mod embeddings;
+mod partial_json;
+mod pricing;
+mod prompt_cache;
 pub mod providers;
+mod tokens;
+pub mod tools;
+mod transcript;
 
 use async_trait::async_trait;
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::fmt;
+use std::future::Future;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use thiserror::Error;
+use tokio::sync::Semaphore;
+
+use prompt_cache::PromptCache;
+use transcript::TranscriptLog;
 
 // ─────────────────────────────────────────────────────────────────────────────
 // Errors
@@ -33,18 +50,67 @@ pub enum AgentError {
 
     #[error("Provider error: {0}")]
     ProviderError(String),
+
+    /// A provider call made by the agent failed; `source` is the provider's
+    /// own error, kept so it can still be classified.
+    #[error("Provider error: [{provider}] step {step}: {source}")]
+    StepFailed { provider: String, step: usize, source: Box<AgentError> },
+
+    #[error("Run timed out after {elapsed:?} ({steps_completed} steps completed)")]
+    Timeout { elapsed: Duration, steps_completed: usize },
+
+    #[error("Invalid conversation history: {0}")]
+    InvalidHistory(String),
+
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+impl AgentError {
+    /// Whether retrying the same request might succeed.
+    ///
+    /// True for connection failures and timeouts, and responses that carry
+    /// a 429 or 5xx status, including when wrapped in `StepFailed`. False
+    /// for anything else: rejected credentials, bad requests, exceeded
+    /// limits, or problems with the history or tools themselves.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            AgentError::LlmError(e) => providers::is_transient(e),
+            AgentError::StepFailed { source, .. } => source.is_retryable(),
+            AgentError::InvalidResponse(msg) => {
+                // Provider messages look like "OpenAI 429: {body}"
+                msg.split_whitespace()
+                    .find(|word| word.ends_with(':'))
+                    .and_then(|word| word.trim_end_matches(':').parse::<u16>().ok())
+                    .and_then(|code| reqwest::StatusCode::from_u16(code).ok())
+                    .is_some_and(providers::is_retryable_status)
+            }
+            AgentError::ProviderError(_)
+            | AgentError::Json(_)
+            | AgentError::ToolNotFound(_)
+            | AgentError::ToolError(_)
+            | AgentError::MaxIterations
+            | AgentError::Timeout { .. }
+            | AgentError::InvalidHistory(_)
+            | AgentError::Io(_) => false,
+        }
+    }
 }
 
 // ─────────────────────────────────────────────────────────────────────────────
 // Message / Role
 // ─────────────────────────────────────────────────────────────────────────────
 
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
 #[serde(rename_all = "lowercase")]
 pub enum Role {
+    #[default]
     User,
     Assistant,
     Tool,
+    System,
+    /// OpenAI's replacement for `system` on newer (reasoning) models.
+    Developer,
 }
 
 impl fmt::Display for Role {
@@ -56,12 +122,18 @@ impl fmt::Display for Role {
                 Role::User => "user",
                 Role::Assistant => "assistant",
                 Role::Tool => "tool",
+                Role::System => "system",
+                Role::Developer => "developer",
             }
         )
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+/// A single conversation turn.
+///
+/// `created_at` and `metadata` are local bookkeeping only: they are kept in
+/// `history` and transcripts but never included in provider request bodies.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct Message {
     pub role: Role,
     pub content: String,
@@ -69,53 +141,346 @@ pub struct Message {
     pub tool_call_id: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub tool_calls: Option<Value>,
+    /// Name of the participant who wrote this turn, for multi-party chats.
+    /// Sent as `name` to OpenAI-compatible providers.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// Creation time in unix milliseconds.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<Value>,
+    /// Images attached to this turn, e.g. a chart returned by a tool.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub images: Vec<ImageContent>,
+}
+
+/// A base64-encoded image attached to a message.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ImageContent {
+    /// MIME type, e.g. `"image/png"`.
+    pub media_type: String,
+    pub data_base64: String,
+}
+
+impl ImageContent {
+    /// The image as a `data:` URL, as expected by OpenAI-compatible APIs.
+    pub fn data_url(&self) -> String {
+        format!("data:{};base64,{}", self.media_type, self.data_base64)
+    }
 }
 
 impl Message {
     pub fn user(content: impl Into<String>) -> Self {
-        Self { role: Role::User, content: content.into(), tool_call_id: None, tool_calls: None }
+        Self { role: Role::User, content: content.into(), created_at: Some(now_millis()), ..Default::default() }
+    }
+    pub fn system(content: impl Into<String>) -> Self {
+        Self { role: Role::System, content: content.into(), created_at: Some(now_millis()), ..Default::default() }
+    }
+    pub fn developer(content: impl Into<String>) -> Self {
+        Self { role: Role::Developer, content: content.into(), created_at: Some(now_millis()), ..Default::default() }
     }
     pub fn assistant(content: impl Into<String>) -> Self {
-        Self { role: Role::Assistant, content: content.into(), tool_call_id: None, tool_calls: None }
+        Self { role: Role::Assistant, content: content.into(), created_at: Some(now_millis()), ..Default::default() }
+    }
+    /// The assistant turn for `completion`. Its tool calls are stored in
+    /// the provider-neutral form (see `ToolCall::to_message_value`), not as
+    /// the provider sent them, so the history can be resent to any provider.
+    pub fn from_completion(completion: &Completion) -> Self {
+        Self::assistant_with_tools(
+            completion.content.clone().unwrap_or_default(),
+            ToolCall::to_message_value(&completion.tool_calls),
+        )
     }
     pub fn assistant_with_tools(content: impl Into<String>, tool_calls: Value) -> Self {
         Self {
             role: Role::Assistant,
             content: content.into(),
-            tool_call_id: None,
             tool_calls: Some(tool_calls),
+            created_at: Some(now_millis()),
+            ..Default::default()
         }
     }
+    pub fn tool(tool_call_id: impl Into<String>, content: impl Into<String>) -> Self {
+        Self {
+            role: Role::Tool,
+            content: content.into(),
+            tool_call_id: Some(tool_call_id.into()),
+            created_at: Some(now_millis()),
+            ..Default::default()
+        }
+    }
+
+    pub fn with_metadata(mut self, metadata: Value) -> Self {
+        self.metadata = Some(metadata);
+        self
+    }
+
+    /// Ids of the tool calls made by this (assistant) message.
+    pub fn tool_call_ids(&self) -> Vec<String> {
+        self.tool_calls
+            .as_ref()
+            .and_then(|tc| tc.as_array())
+            .map(|calls| {
+                calls
+                    .iter()
+                    .filter_map(|c| c.get("id").and_then(|v| v.as_str()))
+                    .map(str::to_string)
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+}
+
+/// An instruction from the agent itself to the model, stored in `history`
+/// as a user message. It is marked in `metadata` so it isn't mistaken for
+/// real user input.
+fn agent_note(text: impl Into<String>) -> Message {
+    Message::user(format!("[SYSTEM]: {}", text.into())).with_metadata(json!({ "agent_note": true }))
+}
+
+fn is_agent_note(message: &Message) -> bool {
+    message.metadata.as_ref().is_some_and(|m| m["agent_note"] == true)
+}
+
+/// The JSON object or array in `text`: the whole text, the body of a code
+/// fence, or the span from the first `{`/`[` to the last matching closer.
+fn extract_json(text: &str) -> Option<String> {
+    let is_json = |candidate: &str| serde_json::from_str::<Value>(candidate).is_ok();
+
+    let trimmed = text.trim();
+    if is_json(trimmed) {
+        return Some(trimmed.to_string());
+    }
+    if let Some(start) = text.find("```") {
+        let body = &text[start + 3..];
+        // Skip the info string, e.g. "json"
+        let body = &body[body.find('\n').map_or(0, |i| i + 1)..];
+        if let Some(end) = body.find("```") {
+            let fenced = body[..end].trim();
+            if is_json(fenced) {
+                return Some(fenced.to_string());
+            }
+        }
+    }
+    let start = text.find(['{', '['])?;
+    let closer = if text[start..].starts_with('{') { '}' } else { ']' };
+    let end = text.rfind(closer)?;
+    let span = text.get(start..=end)?;
+    is_json(span).then(|| span.to_string())
+}
+
+/// `args` with scalars converted to the types `schema` declares where the
+/// conversion is lossless: numeric strings to integers or numbers, `"true"`
+/// and `"false"` to booleans, and numbers or booleans to strings. Anything
+/// else is left for the tool to reject.
+fn coerce_args(args: Value, schema: &Value) -> Value {
+    let types: Vec<&str> = match &schema["type"] {
+        Value::String(t) => vec![t.as_str()],
+        Value::Array(ts) => ts.iter().filter_map(|t| t.as_str()).collect(),
+        _ => vec![],
+    };
+    let matches = |value: &Value, t: &str| match t {
+        "integer" => value.is_i64() || value.is_u64(),
+        "number" => value.is_number(),
+        "string" => value.is_string(),
+        "boolean" => value.is_boolean(),
+        "object" => value.is_object(),
+        "array" => value.is_array(),
+        "null" => value.is_null(),
+        _ => false,
+    };
+
+    match args {
+        Value::Object(map) => Value::Object(
+            map.into_iter()
+                .map(|(key, value)| {
+                    let value = coerce_args(value, &schema["properties"][&key]);
+                    (key, value)
+                })
+                .collect(),
+        ),
+        Value::Array(items) => Value::Array(items.into_iter().map(|item| coerce_args(item, &schema["items"])).collect()),
+        value if types.is_empty() || types.iter().any(|t| matches(&value, t)) => value,
+        value => types
+            .iter()
+            .find_map(|t| match (*t, &value) {
+                ("integer", Value::String(s)) => s.trim().parse::<i64>().ok().map(Value::from),
+                ("number", Value::String(s)) => {
+                    let s = s.trim();
+                    s.parse::<i64>().ok().map(Value::from).or_else(|| {
+                        s.parse::<f64>().ok().and_then(serde_json::Number::from_f64).map(Value::Number)
+                    })
+                }
+                ("boolean", Value::String(s)) => match s.trim() {
+                    "true" => Some(Value::Bool(true)),
+                    "false" => Some(Value::Bool(false)),
+                    _ => None,
+                },
+                ("string", Value::Number(_) | Value::Bool(_)) => Some(Value::String(value.to_string())),
+                _ => None,
+            })
+            .unwrap_or(value),
+    }
+}
+
+fn now_millis() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
 }
 
 // ─────────────────────────────────────────────────────────────────────────────
 // ToolCall / Completion
 // ─────────────────────────────────────────────────────────────────────────────
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ToolCall {
     pub id: String,
     pub name: String,
     pub args: Value,
 }
 
-#[derive(Debug)]
+impl ToolCall {
+    /// `calls` in the provider-neutral form kept in `Message::tool_calls`:
+    /// OpenAI-shaped objects with only `id`, `type` and `function.name` /
+    /// `function.arguments`, whichever provider produced them. Each provider
+    /// renders its own shape from this when sending. `Null` for no calls.
+    pub fn to_message_value(calls: &[ToolCall]) -> Value {
+        if calls.is_empty() {
+            return Value::Null;
+        }
+        calls
+            .iter()
+            .map(|call| {
+                json!({
+                    "id": call.id,
+                    "type": "function",
+                    "function": { "name": call.name, "arguments": call.args.to_string() }
+                })
+            })
+            .collect()
+    }
+}
+
+/// Record of a single tool execution, kept separately from `history`.
+#[derive(Debug, Clone)]
+pub struct ToolInvocation {
+    pub name: String,
+    pub args: Value,
+    /// Text that was sent back to the model; `None` if the tool failed.
+    pub result: Option<String>,
+    pub duration: Duration,
+    pub error: Option<String>,
+}
+
+/// One provider round-trip of a run, as reported by `run_detailed`.
+#[derive(Debug, Clone, Default)]
+pub struct StepTrace {
+    pub content: Option<String>,
+    pub reasoning: Option<String>,
+    pub tool_calls: Vec<ToolCall>,
+    /// The executions of this step's tool calls, in order.
+    pub tool_results: Vec<ToolInvocation>,
+}
+
+/// The result of `run_detailed`: the final answer plus how it was reached.
+#[derive(Debug, Clone)]
+pub struct RunOutcome {
+    pub answer: String,
+    pub steps: Vec<StepTrace>,
+}
+
+/// Token counts reported by the provider for one completion.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Usage {
+    pub prompt_tokens: u32,
+    pub completion_tokens: u32,
+    pub total_tokens: u32,
+}
+
+impl std::ops::AddAssign for Usage {
+    fn add_assign(&mut self, other: Usage) {
+        self.prompt_tokens += other.prompt_tokens;
+        self.completion_tokens += other.completion_tokens;
+        self.total_tokens += other.total_tokens;
+    }
+}
+
+/// What a model can do, as far as its provider knows. The `Default` is the
+/// conservative answer for an unknown model: nothing supported, context
+/// window unknown.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ModelCapabilities {
+    pub supports_tools: bool,
+    pub supports_vision: bool,
+    pub supports_streaming: bool,
+    /// Maximum prompt + completion tokens, if known.
+    pub context_window: Option<u32>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct Completion {
     pub content: Option<String>,
     pub tool_calls: Vec<ToolCall>,
     pub raw_tool_calls: Option<Value>,
+    /// `None` when the provider didn't report usage.
+    pub usage: Option<Usage>,
+    /// The model's separate reasoning text, for providers that return one
+    /// (`reasoning_content` on OpenAI-compatible APIs, Anthropic thinking).
+    pub reasoning: Option<String>,
+    /// Identifies the backend configuration that served the request
+    /// (OpenAI-compatible APIs). A change means the same seed may no
+    /// longer reproduce the same output.
+    pub system_fingerprint: Option<String>,
+    /// The provider's response body exactly as parsed, for reading
+    /// vendor-specific fields. `None` for streamed responses.
+    pub raw: Option<Value>,
 }
 
 // ─────────────────────────────────────────────────────────────────────────────
 // Tool trait
 // ─────────────────────────────────────────────────────────────────────────────
 
+/// What a tool hands back to the agent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ToolOutput {
+    Text(String),
+    /// An image for vision models, e.g. a rendered chart.
+    Image { media_type: String, data_base64: String },
+}
+
 #[async_trait]
 pub trait Tool: Send + Sync + 'static {
     fn name(&self) -> &'static str;
     fn description(&self) -> &'static str;
     fn parameters_schema(&self) -> Value;
     async fn execute(&self, args: Value) -> Result<String, AgentError>;
+
+    /// Rich variant of `execute` used by the agent loop. Override this to
+    /// return non-text output such as images; the default wraps `execute`.
+    async fn execute_output(&self, args: Value) -> Result<ToolOutput, AgentError> {
+        self.execute(args).await.map(ToolOutput::Text)
+    }
+
+    /// A terminal tool ends the run: once it succeeds, `run` returns its
+    /// output as the final answer without asking the model again. Useful for
+    /// a "final_answer" tool.
+    fn terminal(&self) -> bool {
+        false
+    }
+
+    /// The text the model sees for `output` in the `Role::Tool` message.
+    /// Override to render results in a friendlier shape, e.g. CSV rows as a
+    /// markdown table. The default passes text through unchanged and
+    /// describes an image with a short placeholder.
+    fn format_for_model(&self, output: &ToolOutput) -> String {
+        match output {
+            ToolOutput::Text(text) => text.clone(),
+            ToolOutput::Image { media_type, .. } => format!("[image: {media_type}]"),
+        }
+    }
 }
 
 // ─────────────────────────────────────────────────────────────────────────────
@@ -132,16 +497,86 @@ pub trait LlmProvider: Send + Sync {
         tools: &[&dyn Tool],
         model: &str,
     ) -> Result<Completion, AgentError>;
+
+    /// Like `complete`, but calls `on_delta` with each fragment of text as it
+    /// arrives. Returns the fully assembled completion once the stream ends.
+    ///
+    /// The default implementation doesn't stream: it calls `complete` and
+    /// reports the whole content as a single delta.
+    async fn complete_stream(
+        &self,
+        messages: &[Message],
+        tools: &[&dyn Tool],
+        model: &str,
+        on_delta: &mut (dyn for<'s> FnMut(&'s str) + Send),
+    ) -> Result<Completion, AgentError> {
+        let completion = self.complete(messages, tools, model).await?;
+        if let Some(content) = &completion.content {
+            on_delta(content);
+        }
+        Ok(completion)
+    }
+
+    /// The role the agent's system prompt should be sent with for `model`
+    /// (`System` or `Developer`). `None`, the default, means the provider
+    /// has no native system role, so the prompt goes out as a user message
+    /// prefixed with `[SYSTEM]:`.
+    fn system_role(&self, _model: &str) -> Option<Role> {
+        None
+    }
+
+    /// What `model` supports. The default knows nothing about any model and
+    /// returns `ModelCapabilities::default()`; built-in providers consult a
+    /// table of known models.
+    fn capabilities(&self, _model: &str) -> ModelCapabilities {
+        ModelCapabilities::default()
+    }
+
+    /// Checks that the provider is reachable and accepts our credentials.
+    ///
+    /// The default sends a one-word prompt through `complete`; providers with
+    /// a cheaper endpoint override this.
+    async fn health_check(&self) -> Result<(), AgentError> {
+        self.complete(&[Message::user("ping")], &[], "").await.map(|_| ())
+    }
 }
 
 // ─────────────────────────────────────────────────────────────────────────────
 // Re-export built-in providers
 // ─────────────────────────────────────────────────────────────────────────────
 
-pub use providers::openrouter::OpenRouterProvider;
+pub use providers::openrouter::{OpenRouterProvider, RoutingPrefs};
 pub use providers::openai::OpenAiProvider;
 pub use providers::anthropic::AnthropicProvider;
+pub use providers::groq::GroqProvider;
+pub use providers::load_balanced::LoadBalancedProvider;
 pub use providers::ollama::OllamaProvider;
+pub use providers::ollama_generate::{ChatTemplate, OllamaGenerateProvider};
+#[cfg(feature = "testing")]
+pub use providers::cassette::CassetteProvider;
+pub use providers::{ProviderConfig, ToolChoice};
+
+pub use clock::{Clock, SystemClock};
+pub use embeddings::{Embedder, SemanticToolSelector};
+#[cfg(feature = "testing")]
+pub use clock::MockClock;
+pub use partial_json::PartialJson;
+pub use pricing::{ModelPrice, PriceTable};
+pub use tokens::token_estimate;
+pub use tools::ask_user::AskUserTool;
+pub use tools::config_read::ConfigReadTool;
+pub use tools::currency::{CurrencyTool, HttpRateSource, RateSource};
+pub use tools::hash::HashTool;
+pub use tools::http_post::HttpPostTool;
+pub use tools::image_gen::ImageGenTool;
+pub use tools::json_query::JsonQueryTool;
+pub use tools::precise_math::PreciseMathTool;
+pub use tools::random::RandomTool;
+pub use tools::regex_extract::RegexExtractTool;
+#[cfg(feature = "sqlite")]
+pub use tools::sql_query::SqlQueryTool;
+pub use tools::translate::TranslateTool;
+pub use tools::typed::{schema_for, TypedTool};
 
 // ─────────────────────────────────────────────────────────────────────────────
 // Built-in Tools
@@ -226,29 +661,269 @@ impl Tool for JokeTool {
 // Agent
 // ─────────────────────────────────────────────────────────────────────────────
 
+/// How `run` handles a tool that keeps failing.
+///
+/// Failures are fed back to the model as the tool result instead of aborting
+/// the run. After `corrective_after` consecutive failures of the same tool, a
+/// note with the tool's expected schema is added; at
+/// `max_consecutive_failures` the run gives up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ToolRetryPolicy {
+    pub corrective_after: usize,
+    pub max_consecutive_failures: usize,
+}
+
+/// Predicate deciding whether a run should stop after a completion.
+pub type StopCondition = Box<StopFn>;
+type StopFn = dyn Fn(&Completion) -> bool + Send + Sync;
+
+/// Post-processing applied to the final answer of a run.
+pub type OutputTransform = Box<TransformFn>;
+type TransformFn = dyn Fn(String) -> Result<String, AgentError> + Send + Sync;
+
+/// Decides which `history` messages are sent to the provider.
+pub type SendFilter = Box<SendFilterFn>;
+type SendFilterFn = dyn Fn(&Message) -> bool + Send + Sync;
+
+/// Picks the tools to offer for a run: given the user input and every
+/// registered tool, returns the indices of the ones to send.
+pub type ToolSelector = Box<ToolSelectorFn>;
+type ToolSelectorFn = dyn Fn(&str, &[&dyn Tool]) -> Vec<usize> + Send + Sync;
+
+/// A boxed, sendable future.
+pub type BoxFuture<T> = Pin<Box<dyn Future<Output = T> + Send>>;
+
+/// Rewrites a tool's output before the model sees it, given the tool name
+/// and the output text.
+pub type ToolPostprocessor = Box<ToolPostprocessorFn>;
+type ToolPostprocessorFn = dyn Fn(&str, String) -> BoxFuture<String> + Send + Sync;
+
+/// Why a tool call produced no result.
+enum CallFailure {
+    /// Refused before the tool ran (arguments over the size limit); always
+    /// reported back to the model so it can try again.
+    Rejected(AgentError),
+    /// The tool was looked up or ran and failed.
+    Failed(AgentError),
+}
+
+impl From<AgentError> for CallFailure {
+    fn from(e: AgentError) -> Self {
+        CallFailure::Failed(e)
+    }
+}
+
+/// Clears `run_context` when dropped, so documents never outlive their run
+/// even if its future is cancelled.
+struct RunContextGuard<'a>(&'a mut Agent);
+
+impl Drop for RunContextGuard<'_> {
+    fn drop(&mut self) {
+        self.0.run_context = None;
+    }
+}
+
+impl From<CallFailure> for AgentError {
+    fn from(failure: CallFailure) -> Self {
+        match failure {
+            CallFailure::Rejected(e) | CallFailure::Failed(e) => e,
+        }
+    }
+}
+
+/// Appended to the system prompt in JSON output mode.
+const JSON_INSTRUCTION: &str = "Respond with only valid JSON: no prose, no markdown code fences, \
+     nothing before or after the JSON value.";
+
+/// Hooks called as a run progresses, e.g. for logging or metrics. `step` is
+/// the index of the current step, starting from 0 at every `run`, so each
+/// provider request can be matched with the tools it triggered.
+pub trait AgentObserver: Send + Sync {
+    /// Called before every provider request, including retries of an empty
+    /// response, with the messages about to be sent.
+    fn on_llm_request(&self, _step: usize, _messages: &[Message]) {}
+
+    /// Called before a tool call is executed.
+    fn on_tool_start(&self, _step: usize, _call: &ToolCall) {}
+
+    /// Called when the provider's `system_fingerprint` changes in the middle
+    /// of a run, a sign that a seeded run may not reproduce.
+    fn on_fingerprint_change(&self, _step: usize, _previous: &str, _current: &str) {}
+}
+
 pub struct Agent {
-    pub provider: Box<dyn LlmProvider>,
+    pub provider: Arc<dyn LlmProvider>,
     pub model: String,
-    pub tools: Vec<Box<dyn Tool>>,
+    pub tools: Vec<Arc<dyn Tool>>,
     pub history: Vec<Message>,
     pub max_steps: usize,
     pub system_prompt: String,
+    pub text_tool_parsing: bool,
+    pub retry_on_empty: usize,
+    pub run_timeout: Option<Duration>,
+    pub tool_retry: Option<ToolRetryPolicy>,
+    /// Few-shot turns sent after the system prompt on every request. They
+    /// are kept outside `history`, so trimming never removes them.
+    pub examples: Vec<Message>,
+    /// Send tools sorted by name rather than in registration order.
+    pub sorted_tools: bool,
+    /// Refuse to send tools or images to a model whose provider doesn't
+    /// report support for them.
+    pub capability_check: bool,
+    /// How many times the same call (tool name and arguments) may run in one
+    /// `run`; further repeats are answered with a note instead. `None`
+    /// disables loop detection.
+    pub loop_detection: Option<usize>,
+    /// Make the final answer a JSON document. See `with_json_output`.
+    pub json_output: bool,
+    /// Persona name attached to every assistant message this agent writes.
+    pub name: Option<String>,
+    /// Open the system prompt with "You are {name}." when a name is set.
+    pub persona_framing: bool,
+    /// Convert mistyped tool arguments to the types the tool's schema
+    /// declares before executing it. See `with_arg_coercion`.
+    pub arg_coercion: bool,
+    /// Return only the text after the last occurrence of this marker. See
+    /// `with_answer_delimiter`.
+    pub answer_delimiter: Option<String>,
+    /// Shorter system prompt used once `history` grows past
+    /// `compact_prompt_threshold`. See `with_compact_system_prompt`.
+    pub compact_system_prompt: Option<String>,
+    /// Estimated `history` tokens above which the compact system prompt is
+    /// used. `None` means three quarters of the model's context window.
+    pub compact_prompt_threshold: Option<usize>,
+    /// Largest serialized tool-call arguments, in bytes, that will be
+    /// executed. See `with_max_tool_arg_bytes`.
+    pub max_tool_arg_bytes: Option<usize>,
+    steps_completed: usize,
+    tool_invocations: Vec<ToolInvocation>,
+    usage_by_model: HashMap<String, Usage>,
+    fingerprints: Vec<String>,
+    last_raw_response: Option<Value>,
+    price_table: PriceTable,
+    step_traces: Vec<StepTrace>,
+    stop_condition: Option<Arc<StopFn>>,
+    output_transform: Option<Arc<TransformFn>>,
+    send_filter: Option<Arc<SendFilterFn>>,
+    tool_selector: Option<Arc<ToolSelectorFn>>,
+    semantic_selector: Option<Arc<SemanticToolSelector>>,
+    tool_postprocessor: Option<Arc<ToolPostprocessorFn>>,
+    /// Indices into `tools` chosen by the selector for the current run.
+    selected_tools: Option<Vec<usize>>,
+    /// When the current run started, and the time by which it must end:
+    /// the earlier of `run_timeout` and a `run_with_deadline` deadline.
+    deadline: Option<(Instant, Instant)>,
+    clock: Arc<dyn Clock>,
+    observer: Option<Arc<dyn AgentObserver>>,
+    /// Documents for the current `run_with_context`, sent but never stored.
+    run_context: Option<Message>,
+    prompt_cache: Option<PromptCache>,
+    transcript: Option<TranscriptLog>,
 }
 
 impl Agent {
     pub fn new(provider: Box<dyn LlmProvider>, model: impl Into<String>) -> Self {
         Self {
-            provider,
+            provider: Arc::from(provider),
             model: model.into(),
             tools: vec![],
             history: vec![],
             max_steps: 6,
             system_prompt: "You are a helpful assistant. Only call tools that are directly needed to answer the question. Never call unrelated tools. Once you receive a tool result, use it to give the final answer immediately.".to_string(),
+            text_tool_parsing: false,
+            retry_on_empty: 0,
+            run_timeout: None,
+            tool_retry: None,
+            examples: vec![],
+            sorted_tools: false,
+            capability_check: false,
+            loop_detection: None,
+            json_output: false,
+            name: None,
+            persona_framing: false,
+            arg_coercion: false,
+            answer_delimiter: None,
+            compact_system_prompt: None,
+            compact_prompt_threshold: None,
+            max_tool_arg_bytes: None,
+            steps_completed: 0,
+            tool_invocations: vec![],
+            usage_by_model: HashMap::new(),
+            fingerprints: vec![],
+            last_raw_response: None,
+            price_table: PriceTable::default(),
+            step_traces: vec![],
+            stop_condition: None,
+            output_transform: None,
+            send_filter: None,
+            tool_selector: None,
+            semantic_selector: None,
+            tool_postprocessor: None,
+            selected_tools: None,
+            deadline: None,
+            clock: Arc::new(SystemClock),
+            observer: None,
+            run_context: None,
+            prompt_cache: None,
+            transcript: None,
         }
     }
 
     pub fn add_tool<T: Tool + 'static>(&mut self, tool: T) {
-        self.tools.push(Box::new(tool));
+        self.tools.push(Arc::new(tool));
+    }
+
+    /// Creates an independent copy of this agent for exploring an alternative
+    /// continuation from the current point.
+    ///
+    /// `history` and configuration are deep-cloned; the provider and tools are
+    /// shared. The transcript log is not inherited, so branches never
+    /// interleave lines in the same file.
+    pub fn fork(&self) -> Agent {
+        Agent {
+            provider: Arc::clone(&self.provider),
+            model: self.model.clone(),
+            tools: self.tools.clone(),
+            history: self.history.clone(),
+            max_steps: self.max_steps,
+            system_prompt: self.system_prompt.clone(),
+            text_tool_parsing: self.text_tool_parsing,
+            retry_on_empty: self.retry_on_empty,
+            run_timeout: self.run_timeout,
+            tool_retry: self.tool_retry,
+            examples: self.examples.clone(),
+            sorted_tools: self.sorted_tools,
+            capability_check: self.capability_check,
+            loop_detection: self.loop_detection,
+            json_output: self.json_output,
+            name: self.name.clone(),
+            persona_framing: self.persona_framing,
+            arg_coercion: self.arg_coercion,
+            answer_delimiter: self.answer_delimiter.clone(),
+            compact_system_prompt: self.compact_system_prompt.clone(),
+            compact_prompt_threshold: self.compact_prompt_threshold,
+            max_tool_arg_bytes: self.max_tool_arg_bytes,
+            steps_completed: 0,
+            tool_invocations: self.tool_invocations.clone(),
+            usage_by_model: self.usage_by_model.clone(),
+            fingerprints: self.fingerprints.clone(),
+            last_raw_response: None,
+            price_table: self.price_table.clone(),
+            step_traces: vec![],
+            stop_condition: self.stop_condition.clone(),
+            output_transform: self.output_transform.clone(),
+            send_filter: self.send_filter.clone(),
+            tool_selector: self.tool_selector.clone(),
+            semantic_selector: self.semantic_selector.clone(),
+            tool_postprocessor: self.tool_postprocessor.clone(),
+            selected_tools: self.selected_tools.clone(),
+            deadline: None,
+            clock: self.clock.clone(),
+            observer: self.observer.clone(),
+            run_context: None,
+            prompt_cache: self.prompt_cache.clone(),
+            transcript: None,
+        }
     }
 
     pub fn with_max_steps(mut self, steps: usize) -> Self {
@@ -261,43 +936,408 @@ impl Agent {
         self
     }
 
+    /// Swaps in `prompt` for the system prompt while `history` is near the
+    /// context limit (see `compact_prompt_threshold`), freeing room without
+    /// trimming conversation turns. The full prompt comes back once history
+    /// shrinks again.
+    pub fn with_compact_system_prompt(mut self, prompt: impl Into<String>) -> Self {
+        self.compact_system_prompt = Some(prompt.into());
+        self
+    }
+
+    /// Uses the compact system prompt once `history` is estimated at more
+    /// than `tokens` tokens, instead of at three quarters of the model's
+    /// context window.
+    pub fn with_compact_prompt_threshold(mut self, tokens: usize) -> Self {
+        self.compact_prompt_threshold = Some(tokens);
+        self
+    }
+
+    /// Re-request up to `retries` times when a completion comes back with
+    /// neither content nor tool calls, before failing with "Empty response".
+    pub fn with_retry_on_empty(mut self, retries: usize) -> Self {
+        self.retry_on_empty = retries;
+        self
+    }
+
+    /// Feed tool errors back to the model so it can fix its arguments. See
+    /// `ToolRetryPolicy` for when the schema note is added and when to give up.
+    pub fn with_tool_retry(mut self, corrective_after: usize, max_consecutive_failures: usize) -> Self {
+        self.tool_retry = Some(ToolRetryPolicy { corrective_after, max_consecutive_failures });
+        self
+    }
+
+    /// Hard ceiling on the wall-clock time of a whole `run`, across all
+    /// provider calls and tool executions. Exceeding it returns
+    /// `AgentError::Timeout`; any half-finished tool-call turn is removed
+    /// from `history` so it stays valid for the next request.
+    pub fn with_run_timeout(mut self, timeout: Duration) -> Self {
+        self.run_timeout = Some(timeout);
+        self
+    }
+
+    /// Replaces the clock used for run timeouts, deadlines and tool timings,
+    /// e.g. with a `MockClock` in tests. The run timeout is also enforced
+    /// on the real clock, so a hung call is still cut off.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Ends the run early when `condition` returns true for a completion,
+    /// returning that completion's content. Checked after every provider call,
+    /// before any tool calls are executed.
+    pub fn with_stop_condition(mut self, condition: StopCondition) -> Self {
+        self.stop_condition = Some(Arc::from(condition));
+        self
+    }
+
+    /// Post-processes the final answer (e.g. trim it, extract a code block,
+    /// validate it) before `run` returns. An `Err` from `transform` becomes
+    /// the run's error; `history` keeps the untransformed reply.
+    pub fn with_output_transform(mut self, transform: OutputTransform) -> Self {
+        self.output_transform = Some(Arc::from(transform));
+        self
+    }
+
+    /// Prices used by `estimated_cost`, replacing the built-in table.
+    pub fn with_price_table(mut self, prices: PriceTable) -> Self {
+        self.price_table = prices;
+        self
+    }
+
+    /// Reports provider requests, tool executions and fingerprint changes to
+    /// `observer`.
+    pub fn with_observer(mut self, observer: Arc<dyn AgentObserver>) -> Self {
+        self.observer = Some(observer);
+        self
+    }
+
+    /// Sends only the `history` messages for which `filter` returns true;
+    /// the rest stay in local history but never reach the provider. An
+    /// assistant tool-call turn and its results must be kept or dropped
+    /// together, otherwise the request fails with `InvalidHistory`.
+    pub fn with_send_filter(mut self, filter: SendFilter) -> Self {
+        self.send_filter = Some(Arc::from(filter));
+        self
+    }
+
+    /// Refuses to run tool calls whose arguments serialize to more than
+    /// `limit` bytes. During a run the `ToolError` is sent back to the model
+    /// as the call's result, so it can retry with smaller arguments.
+    pub fn with_max_tool_arg_bytes(mut self, limit: usize) -> Self {
+        self.max_tool_arg_bytes = Some(limit);
+        self
+    }
+
+    /// Passes each successful tool result through `postprocessor` (e.g. to
+    /// summarize a huge HTTP body) and sends what it returns as the
+    /// `Role::Tool` message instead.
+    pub fn with_tool_postprocessor(mut self, postprocessor: ToolPostprocessor) -> Self {
+        self.tool_postprocessor = Some(Arc::from(postprocessor));
+        self
+    }
+
+    /// Offers only the tools `selector` picks for each run's input, e.g. by
+    /// keyword match, instead of every registered tool. `resume` keeps the
+    /// previous run's selection.
+    pub fn with_tool_selector(mut self, selector: ToolSelector) -> Self {
+        self.tool_selector = Some(Arc::from(selector));
+        self.semantic_selector = None;
+        self
+    }
+
+    /// Like `with_tool_selector`, but picks the tools whose descriptions
+    /// are most similar to each run's input. Replaces any tool selector.
+    pub fn with_semantic_tool_selector(mut self, selector: SemanticToolSelector) -> Self {
+        self.semantic_selector = Some(Arc::new(selector));
+        self.tool_selector = None;
+        self
+    }
+
+    /// Seeds every request with example user/assistant turns, placed right
+    /// after the system prompt and before the real conversation.
+    pub fn with_examples(mut self, examples: Vec<Message>) -> Self {
+        self.examples = examples;
+        self
+    }
+
+    /// Sorts tools by `name()` in every request, so the serialized tool list
+    /// is byte-identical regardless of the order they were registered in.
+    pub fn with_sorted_tools(mut self, enabled: bool) -> Self {
+        self.sorted_tools = enabled;
+        self
+    }
+
+    /// Stops the model from looping on one call: once the same tool has been
+    /// called with the same arguments `max_repeats` times, later repeats
+    /// aren't executed and the model is told to answer instead. Off by
+    /// default.
+    pub fn with_loop_detection(mut self, max_repeats: usize) -> Self {
+        self.loop_detection = Some(max_repeats);
+        self
+    }
+
+    /// Tags every assistant message with `name`, e.g. to tell bots apart in
+    /// a multi-bot chat.
+    pub fn with_name(mut self, name: impl Into<String>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    /// Also tell the model who it is: the system prompt is prefixed with
+    /// "You are {name}." when a name is set.
+    pub fn with_persona_framing(mut self, enabled: bool) -> Self {
+        self.persona_framing = enabled;
+        self
+    }
+
+    /// Fixes common argument type mismatches before a tool runs, guided by
+    /// its `parameters_schema`: `"5"` becomes `5` where an integer or number
+    /// is expected, `5` becomes `"5"` where a string is, and `"true"` becomes
+    /// `true` for booleans.
+    pub fn with_arg_coercion(mut self, enabled: bool) -> Self {
+        self.arg_coercion = enabled;
+        self
+    }
+
+    /// Strips scratch reasoning from the answer: `run` returns only the
+    /// (trimmed) text after the last occurrence of `delimiter`, e.g.
+    /// `"Final Answer:"`, or the whole reply if it doesn't contain one.
+    /// `history` keeps the full reply.
+    pub fn with_answer_delimiter(mut self, delimiter: impl Into<String>) -> Self {
+        self.answer_delimiter = Some(delimiter.into());
+        self
+    }
+
+    /// Forces the final answer to be JSON without relying on the provider's
+    /// `response_format`, which Ollama and older models lack: the system
+    /// prompt asks for JSON only, and the answer is reduced to the JSON value
+    /// it contains (dropping surrounding prose or code fences). If there is
+    /// none, the model is asked once more before failing with
+    /// `InvalidResponse`.
+    pub fn with_json_output(mut self, enabled: bool) -> Self {
+        self.json_output = enabled;
+        self
+    }
+
+    /// Check `LlmProvider::capabilities` before each request and fail with
+    /// `ProviderError` instead of sending tools or images the model can't
+    /// handle. Models the provider doesn't know count as unsupported.
+    pub fn with_capability_check(mut self, enabled: bool) -> Self {
+        self.capability_check = enabled;
+        self
+    }
+
+    /// Fallback for models that ignore the `tools` field and instead describe
+    /// the call in a fenced ```json block. When enabled and a completion has no
+    /// native tool calls, the block is parsed and executed as a tool call.
+    pub fn with_text_tool_parsing(mut self, enabled: bool) -> Self {
+        self.text_tool_parsing = enabled;
+        self
+    }
+
+    /// Appends every message added to `history` to `path` as one JSON line.
+    /// The file is opened in append mode, so successive runs accumulate.
+    pub fn with_transcript_log(mut self, path: impl AsRef<Path>) -> Result<Self, AgentError> {
+        self.transcript = Some(TranscriptLog::open(path)?);
+        Ok(self)
+    }
+
+    /// Stops transcript logging and waits, without blocking the runtime,
+    /// until every logged message is on disk. Dropping the agent also ends
+    /// logging but doesn't wait for the background writer, so lines still
+    /// queued can be lost if the process exits right after.
+    pub async fn close_transcript(&mut self) -> Result<(), AgentError> {
+        match self.transcript.take() {
+            Some(log) => log.close().await,
+            None => Ok(()),
+        }
+    }
+
+    /// Runs the agent loop until the model gives a final answer.
+    ///
+    /// Every message is appended to `history` as soon as it is produced, so
+    /// when this returns `Err` the history still holds everything up to the
+    /// failure — including tool results from earlier steps. Use
+    /// `last_partial_content` to recover the last text the model produced.
+    /// The one exception is a run timeout, which drops an assistant turn
+    /// whose tool calls were left unanswered.
     pub async fn run(&mut self, user_input: &str) -> Result<String, AgentError> {
-        self.history.push(Message::user(user_input));
+        let model = self.model.clone();
+        self.run_with_model(user_input, &model).await
+    }
+
+    /// Like `run`, but also returns a trace of every step: what the model
+    /// said and reasoned, the tools it called, and what they returned.
+    pub async fn run_detailed(&mut self, user_input: &str) -> Result<RunOutcome, AgentError> {
+        let answer = self.run(user_input).await?;
+        Ok(RunOutcome { answer, steps: std::mem::take(&mut self.step_traces) })
+    }
+
+    /// Like `run`, but also returns the messages the run appended to
+    /// `history`: the user input, every assistant turn and tool result, and
+    /// the final answer.
+    ///
+    /// On error nothing is returned but the error. Whatever the failed run
+    /// did add is still in `history`; callers that need it should note
+    /// `history.len()` before the call and slice from there themselves.
+    pub async fn run_capturing(&mut self, user_input: &str) -> Result<(String, Vec<Message>), AgentError> {
+        let start = self.history.len();
+        let answer = self.run(user_input).await?;
+        let added = self.history.get(start..).unwrap_or_default().to_vec();
+        Ok((answer, added))
+    }
+
+    /// Like `run`, with `docs` (e.g. retrieved passages) made available to
+    /// the model for this run only. They go out with every request of the
+    /// run, just before the latest user message, but are never added to
+    /// `history`.
+    pub async fn run_with_context(&mut self, user_input: &str, docs: Vec<String>) -> Result<String, AgentError> {
+        if !docs.is_empty() {
+            let numbered: Vec<String> =
+                docs.iter().enumerate().map(|(i, doc)| format!("[{}] {}", i + 1, doc.trim())).collect();
+            self.run_context = Some(Message::user(format!(
+                "[SYSTEM]: Use these documents to answer the next question where relevant:\n\n{}",
+                numbered.join("\n\n")
+            )));
+        }
+        let guard = RunContextGuard(self);
+        guard.0.run(user_input).await
+    }
+
+    /// Like `run`, but fails with `AgentError::Timeout` once `deadline` has
+    /// passed. Unlike `with_run_timeout`, which bounds a run from its own
+    /// start, this is a fixed point in time, e.g. the end of a request's SLA
+    /// that other work has already eaten into. It is checked before every
+    /// provider call and tool execution; a call already in flight is not
+    /// interrupted. As with a run timeout, an assistant turn whose tool calls
+    /// were left unanswered is dropped from `history`. `deadline` is
+    /// compared against the agent's clock (see `with_clock`).
+    pub async fn run_with_deadline(&mut self, user_input: &str, deadline: Instant) -> Result<String, AgentError> {
+        let model = self.model.clone();
+        self.drive(Some(user_input), &model, Some(deadline)).await
+    }
+
+    /// Same as `run`, but sends `model` to the provider for every step of this
+    /// run instead of the agent's configured model.
+    pub async fn run_with_model(&mut self, user_input: &str, model: &str) -> Result<String, AgentError> {
+        self.drive(Some(user_input), model, None).await
+    }
+
+    /// Continues the previous run for up to another `max_steps` steps
+    /// without adding a user message — e.g. after `MaxIterations`, since the
+    /// history then already ends with the last round of tool results.
+    pub async fn resume(&mut self) -> Result<String, AgentError> {
+        if self.history.is_empty() {
+            return Err(AgentError::InvalidHistory("there is no run to resume".into()));
+        }
+        let model = self.model.clone();
+        self.drive(None, &model, None).await
+    }
+
+    /// Runs the loop and post-processes its answer, enforcing `run_timeout`
+    /// and `deadline` across all of it, including a JSON retry.
+    async fn drive(
+        &mut self,
+        user_input: Option<&str>,
+        model: &str,
+        deadline: Option<Instant>,
+    ) -> Result<String, AgentError> {
+        self.step_traces.clear();
+        let started = self.clock.now();
+        let timeout_at = self.run_timeout.map(|limit| started + limit);
+        // Set afresh by every run, so a cancelled run can't leave one behind
+        self.deadline = deadline.into_iter().chain(timeout_at).min().map(|end| (started, end));
+
+        let result = match self.run_timeout {
+            Some(limit) => match tokio::time::timeout(limit, self.answer(user_input, model)).await {
+                Ok(result) => result,
+                Err(_) => Err(self.timeout_error(started)),
+            },
+            None => self.answer(user_input, model).await,
+        };
+        if matches!(result, Err(AgentError::Timeout { .. })) {
+            self.drop_dangling_tool_calls();
+        }
+        result
+    }
+
+    fn timeout_error(&self, started: Instant) -> AgentError {
+        AgentError::Timeout {
+            elapsed: self.clock.now().saturating_duration_since(started),
+            steps_completed: self.steps_completed,
+        }
+    }
+
+    /// Runs the loop and applies the answer delimiter, JSON mode and output
+    /// transform to its answer.
+    async fn answer(&mut self, user_input: Option<&str>, model: &str) -> Result<String, AgentError> {
+        let mut answer = self.run_loop(user_input, model).await?;
+        if let Some(delimiter) = self.answer_delimiter.as_deref().filter(|d| !d.is_empty()) {
+            if let Some(at) = answer.rfind(delimiter) {
+                answer = answer[at + delimiter.len()..].trim().to_string();
+            }
+        }
+        if self.json_output {
+            answer = self.ensure_json(answer, model).await?;
+        }
+        match &self.output_transform {
+            Some(transform) => transform(answer),
+            None => Ok(answer),
+        }
+    }
+
+    /// Extracts the JSON value from `answer`, asking the model once to fix a
+    /// reply that has none.
+    async fn ensure_json(&mut self, answer: String, model: &str) -> Result<String, AgentError> {
+        if let Some(json) = extract_json(&answer) {
+            return Ok(json);
+        }
+        self.push_history(agent_note(format!("Your previous reply was not valid JSON. {JSON_INSTRUCTION}")));
+        let retry = self.run_loop(None, model).await?;
+        extract_json(&retry)
+            .ok_or_else(|| AgentError::InvalidResponse(format!("model did not return valid JSON: {retry}")))
+    }
+
+    async fn run_loop(&mut self, user_input: Option<&str>, model: &str) -> Result<String, AgentError> {
+        self.steps_completed = 0;
+        if let Some(input) = user_input {
+            self.select_tools(input).await?;
+            self.push_history(Message::user(input));
+        }
         let mut executed_tool_calls = HashSet::new();
+        let mut consecutive_failures: HashMap<String, usize> = HashMap::new();
+        // Keyed on name and args, since repeats come with fresh ids. Object
+        // keys serialize sorted, so key order doesn't matter
+        let mut call_counts: HashMap<(String, String), usize> = HashMap::new();
 
         for step in 0..self.max_steps {
-            let tool_refs: Vec<&dyn Tool> = self.tools.iter().map(|t| t.as_ref()).collect();
+            self.steps_completed = step;
+            let cache = self.refresh_prompt_cache(model);
+            let tool_refs: Vec<&dyn Tool> = cache.tools.iter().map(|t| t.as_ref()).collect();
+            let messages = self.request_messages(&cache)?;
 
-            // Inject system prompt as first message on every call
-            let mut messages = vec![Message {
-                role: Role::User,
-                content: format!("[SYSTEM]: {}", self.system_prompt),
-                tool_call_id: None,
-                tool_calls: None,
-            }];
-            messages.extend(self.history.clone());
-
-            let completion = self
-                .provider
-                .complete(&messages, &tool_refs, &self.model)
-                .await
-                .map_err(|e| {
-                    AgentError::ProviderError(format!(
-                        "[{}] step {}: {}",
-                        self.provider.provider_name(),
-                        step,
-                        e
-                    ))
-                })?;
+            let completion = self.complete_step(&messages, &tool_refs, model, step).await?;
 
             let content = completion.content.clone().unwrap_or_default();
-            let tool_calls = completion.tool_calls.clone();
-            let raw_tool_calls = completion.raw_tool_calls.clone().unwrap_or(Value::Null);
+            let tool_calls = self.resolve_tool_calls(&completion, step);
+            self.step_traces.push(StepTrace {
+                content: completion.content.clone(),
+                reasoning: completion.reasoning.clone(),
+                tool_calls: tool_calls.clone(),
+                tool_results: vec![],
+            });
+
+            // A turn with neither text nor tool calls carries nothing worth keeping
+            if !content.is_empty() || !tool_calls.is_empty() {
+                self.push_history(self.assistant_message(content.clone(), &tool_calls));
+            }
 
-            self.history.push(Message::assistant_with_tools(
-                content.clone(),
-                raw_tool_calls,
-            ));
+            // Custom stop condition — end the run with whatever we have
+            if self.stop_condition.as_ref().is_some_and(|stop| stop(&completion)) {
+                return Ok(content);
+            }
 
             // No tool calls — final answer
             if tool_calls.is_empty() {
@@ -309,28 +1349,81 @@ impl Agent {
 
             // Execute tools
             let mut executed_any = false;
+            let mut corrective_notes = vec![];
+            let mut terminal_output = None;
+            let invocations_before = self.tool_invocations.len();
             for call in &tool_calls {
                 if executed_tool_calls.contains(&call.id) {
                     continue;
                 }
 
+                let key = (call.name.clone(), call.args.to_string());
+                let count = call_counts.entry(key).or_default();
+                *count += 1;
+                if let Some(max_repeats) = self.loop_detection.filter(|max| *count > *max) {
+                    self.push_history(Message::tool(
+                        call.id.clone(),
+                        format!(
+                            "[SYSTEM]: You already called `{}` with these arguments {max_repeats} times; \
+                             calling it again won't change the result. Answer using the results you have.",
+                            call.name
+                        ),
+                    ));
+                    executed_tool_calls.insert(call.id.clone());
+                    executed_any = true;
+                    continue;
+                }
+
+                self.check_deadline()?;
                 println!(
                     "[{}] Executing tool: {}",
                     self.provider.provider_name(),
                     call.name
                 );
-                let result = self.execute_tool(call).await?;
+                match self.call_tool(call).await {
+                    Ok(output) => {
+                        consecutive_failures.remove(&call.name);
+                        if terminal_output.is_none() && self.is_terminal(&call.name) {
+                            terminal_output = Some(output);
+                        }
+                    }
+                    Err(CallFailure::Rejected(e)) => {
+                        self.push_history(Message::tool(call.id.clone(), format!("Error: {e}")));
+                    }
+                    Err(CallFailure::Failed(e)) => {
+                        let Some(policy) = self.tool_retry else { return Err(e) };
+                        let failures = consecutive_failures.entry(call.name.clone()).or_default();
+                        *failures += 1;
+                        if *failures >= policy.max_consecutive_failures {
+                            return Err(AgentError::ToolError(format!(
+                                "tool '{}' failed {} times in a row, giving up: {e}",
+                                call.name, failures
+                            )));
+                        }
+                        if *failures == policy.corrective_after {
+                            corrective_notes.extend(self.schema_note(&call.name, *failures));
+                        }
+                        self.push_history(Message::tool(call.id.clone(), format!("Error: {e}")));
+                    }
+                }
                 executed_tool_calls.insert(call.id.clone());
 
-                self.history.push(Message {
-                    role: Role::Tool,
-                    content: result,
-                    tool_call_id: Some(call.id.clone()),
-                    tool_calls: None,
-                });
-
                 executed_any = true;
             }
+            let results = self.tool_invocations[invocations_before..].to_vec();
+            if let Some(trace) = self.step_traces.last_mut() {
+                trace.tool_results = results;
+            }
+
+            // Notes go after every tool result so the call/result group stays intact
+            for note in corrective_notes {
+                self.push_history(note);
+            }
+
+            // The rest of the turn's calls still ran, so every call has a result
+            if let Some(output) = terminal_output {
+                return Ok(output);
+            }
 
             if !executed_any {
                 // All were duplicates
@@ -348,12 +1441,616 @@ impl Agent {
         Err(AgentError::MaxIterations)
     }
 
-    async fn execute_tool(&self, call: &ToolCall) -> Result<String, AgentError> {
+    /// `Timeout` once the current run's deadline has passed.
+    fn check_deadline(&self) -> Result<(), AgentError> {
+        match self.deadline {
+            Some((started, deadline)) if self.clock.now() >= deadline => Err(self.timeout_error(started)),
+            _ => Ok(()),
+        }
+    }
+
+    fn is_terminal(&self, tool_name: &str) -> bool {
+        self.tools.iter().any(|t| t.name() == tool_name && t.terminal())
+    }
+
+    /// A system-style note reminding the model of `tool_name`'s schema.
+    fn schema_note(&self, tool_name: &str, failures: usize) -> Option<Message> {
+        let tool = self.tools.iter().find(|t| t.name() == tool_name)?;
+        Some(agent_note(format!(
+            "The tool `{tool_name}` has failed {failures} times in a row. \
+             Its arguments must match this JSON schema exactly: {}",
+            tool.parameters_schema()
+        )))
+    }
+
+    /// If the last assistant turn requested tools whose results never made it
+    /// into `history` (e.g. the run was cancelled mid-execution), remove that
+    /// turn and its partial results so the history can be resent as-is.
+    fn drop_dangling_tool_calls(&mut self) {
+        let Some(last_assistant) = self.history.iter().rposition(|m| m.role == Role::Assistant) else {
+            return;
+        };
+        let answered: HashSet<&str> = self.history[last_assistant + 1..]
+            .iter()
+            .filter_map(|m| m.tool_call_id.as_deref())
+            .collect();
+        let complete = self.history[last_assistant]
+            .tool_call_ids()
+            .iter()
+            .all(|id| answered.contains(id.as_str()));
+        if !complete {
+            self.history.truncate(last_assistant);
+        }
+    }
+
+    /// Checks that every tool called by an assistant message in `history` is
+    /// still registered, e.g. before continuing a conversation loaded from
+    /// disk. The `InvalidHistory` error lists each missing tool with the
+    /// index of the first message that calls it.
+    pub fn validate_history(&self) -> Result<(), AgentError> {
+        let mut missing: Vec<(String, usize)> = vec![];
+        for (index, message) in self.history.iter().enumerate().filter(|(_, m)| m.role == Role::Assistant) {
+            for call in providers::normalize_tool_calls(message.tool_calls.as_ref()) {
+                let registered = self.tools.iter().any(|t| t.name() == call.name);
+                if !registered && !missing.iter().any(|(name, _)| *name == call.name) {
+                    missing.push((call.name, index));
+                }
+            }
+        }
+        if missing.is_empty() {
+            return Ok(());
+        }
+        let list: Vec<String> = missing.iter().map(|(name, index)| format!("'{name}' (message {index})")).collect();
+        Err(AgentError::InvalidHistory(format!("history calls unregistered tools: {}", list.join(", "))))
+    }
+
+    /// Makes a reloaded history valid to resend after a crash left tool calls
+    /// unanswered: every assistant tool call without a matching `Role::Tool`
+    /// result gets a placeholder error result, inserted after the results
+    /// that did arrive. The tools are not re-run, since they may have had
+    /// side effects. Returns how many results were added.
+    pub fn heal_history(&mut self) -> usize {
+        let mut healed = Vec::with_capacity(self.history.len());
+        let mut added = 0;
+        let mut messages = std::mem::take(&mut self.history).into_iter().peekable();
+        while let Some(msg) = messages.next() {
+            let mut pending = if msg.role == Role::Assistant { msg.tool_call_ids() } else { vec![] };
+            healed.push(msg);
+            while let Some(result) = messages.next_if(|m| m.role == Role::Tool) {
+                pending.retain(|id| result.tool_call_id.as_ref() != Some(id));
+                healed.push(result);
+            }
+            for id in pending {
+                healed.push(Message::tool(id, "Error: the tool call was interrupted before it returned a result"));
+                added += 1;
+            }
+        }
+        self.history = healed;
+        added
+    }
+
+    /// Runs every input as its own conversation, with at most `concurrency`
+    /// in flight at once. Each run starts from this agent's configuration
+    /// with an empty history; `self` is left untouched. Results are returned
+    /// in input order.
+    pub async fn run_batch(&self, inputs: Vec<String>, concurrency: usize) -> Vec<Result<String, AgentError>> {
+        let permits = Arc::new(Semaphore::new(concurrency.max(1)));
+        let handles: Vec<_> = inputs
+            .into_iter()
+            .map(|input| {
+                let mut agent = self.fork();
+                agent.history.clear();
+                agent.tool_invocations.clear();
+                agent.usage_by_model.clear();
+                agent.fingerprints.clear();
+                let permits = Arc::clone(&permits);
+                tokio::spawn(async move {
+                    let _permit = permits.acquire_owned().await.expect("semaphore is never closed");
+                    agent.run(&input).await
+                })
+            })
+            .collect();
+
+        let mut results = Vec::with_capacity(handles.len());
+        for handle in handles {
+            results.push(handle.await.unwrap_or_else(|e| {
+                Err(AgentError::ProviderError(format!("batch run panicked: {e}")))
+            }));
+        }
+        results
+    }
+
+    /// Asks the model for its next move without executing anything.
+    ///
+    /// Makes a single provider call, appends the user input and the assistant
+    /// reply to `history`, and returns the proposed tool calls (empty if the
+    /// model answered directly). Run them selectively with `execute_call`.
+    pub async fn plan(&mut self, user_input: &str) -> Result<Vec<ToolCall>, AgentError> {
+        self.steps_completed = 0;
+        self.deadline = None;
+        self.select_tools(user_input).await?;
+        self.push_history(Message::user(user_input));
+
+        let model = self.model.clone();
+        let cache = self.refresh_prompt_cache(&model);
+        let tool_refs: Vec<&dyn Tool> = cache.tools.iter().map(|t| t.as_ref()).collect();
+        let messages = self.request_messages(&cache)?;
+        let completion = self.complete_step(&messages, &tool_refs, &model, 0).await?;
+
+        let content = completion.content.clone().unwrap_or_default();
+        let tool_calls = self.resolve_tool_calls(&completion, 0);
+        if !content.is_empty() || !tool_calls.is_empty() {
+            self.push_history(self.assistant_message(content, &tool_calls));
+        }
+
+        Ok(tool_calls)
+    }
+
+    /// Runs a single tool call and appends its `Role::Tool` result to
+    /// `history`. Together with `plan` this lets callers drive the loop manually.
+    ///
+    /// The message content comes from the tool's `format_for_model`, then the
+    /// tool postprocessor if one is set, and is also what this method
+    /// returns. Image outputs are attached to the tool message as well.
+    pub async fn execute_call(&mut self, call: &ToolCall) -> Result<String, AgentError> {
+        Ok(self.call_tool(call).await?)
+    }
+
+    /// `execute_call`, keeping apart calls that were refused before the tool ran.
+    async fn call_tool(&mut self, call: &ToolCall) -> Result<String, CallFailure> {
+        if let Some(observer) = &self.observer {
+            observer.on_tool_start(self.steps_completed, call);
+        }
+        let started = self.clock.now();
+        let output = self.execute_tool(call).await;
+        let mut invocation = ToolInvocation {
+            name: call.name.clone(),
+            args: call.args.clone(),
+            result: None,
+            duration: self.clock.now().saturating_duration_since(started),
+            error: None,
+        };
+        let (output, content) = match output {
+            Ok(output) => output,
+            Err(failure) => {
+                let (CallFailure::Rejected(e) | CallFailure::Failed(e)) = &failure;
+                invocation.error = Some(e.to_string());
+                self.tool_invocations.push(invocation);
+                return Err(failure);
+            }
+        };
+
+        let mut message = Message::tool(call.id.clone(), content);
+        if let ToolOutput::Image { media_type, data_base64 } = output {
+            message.images.push(ImageContent { media_type, data_base64 });
+        }
+        let result = message.content.clone();
+        invocation.result = Some(result.clone());
+        self.tool_invocations.push(invocation);
+        self.push_history(message);
+        Ok(result)
+    }
+
+    /// Every tool executed by this agent, in order, including failed ones.
+    pub fn tool_invocations(&self) -> &[ToolInvocation] {
+        &self.tool_invocations
+    }
+
+    /// The registered tools as the OpenAI-shaped array sent to providers
+    /// (see `providers::build_openai_tools`), in request order — e.g. to
+    /// publish the agent's tool contract.
+    pub fn export_tool_schemas(&self) -> Value {
+        Value::Array(self.tool_definitions())
+    }
+
+    /// Same as `export_tool_schemas`, one tool definition per line.
+    pub fn export_tool_schemas_jsonl(&self) -> String {
+        self.tool_definitions().iter().map(|tool| format!("{tool}\n")).collect()
+    }
+
+    fn tool_definitions(&self) -> Vec<Value> {
+        let mut tools: Vec<&dyn Tool> = self.tools.iter().map(|t| t.as_ref()).collect();
+        if self.sorted_tools {
+            tools.sort_by(|a, b| a.name().cmp(b.name()));
+        }
+        providers::build_openai_tools(&tools)
+    }
+
+    /// Token usage summed over every completion this agent has received,
+    /// as reported by the provider.
+    pub fn usage(&self) -> Usage {
+        let mut total = Usage::default();
+        for usage in self.usage_by_model.values() {
+            total += *usage;
+        }
+        total
+    }
+
+    /// The `system_fingerprint` of every completion that reported one, in
+    /// order.
+    pub fn fingerprints(&self) -> Vec<String> {
+        self.fingerprints.clone()
+    }
+
+    /// The raw provider response body of the most recent completion, if the
+    /// provider supplied one.
+    pub fn last_raw_response(&self) -> Option<&Value> {
+        self.last_raw_response.as_ref()
+    }
+
+    /// Estimated dollar cost of `usage`, priced per model with the agent's
+    /// `PriceTable`. `None` if no usage has been reported yet or any model
+    /// used so far has no price.
+    pub fn estimated_cost(&self) -> Option<f64> {
+        if self.usage_by_model.is_empty() {
+            return None;
+        }
+        self.usage_by_model
+            .iter()
+            .map(|(model, usage)| self.price_table.cost(model, *usage))
+            .sum()
+    }
+
+    /// A readable snapshot of the agent for debugging: configuration, system
+    /// prompt, registered tools with their schemas, and the full history.
+    pub fn debug_dump(&self) -> String {
+        use std::fmt::Write as _;
+
+        let mut out = String::new();
+        let _ = writeln!(out, "=== Agent ===");
+        let _ = writeln!(out, "provider:  {}", self.provider.provider_name());
+        let _ = writeln!(out, "model:     {}", self.model);
+        let _ = writeln!(out, "max_steps: {}", self.max_steps);
+
+        let _ = writeln!(out, "\n=== System prompt ===");
+        let _ = writeln!(out, "{}", self.system_prompt);
+
+        let _ = writeln!(out, "\n=== Tools ({}) ===", self.tools.len());
+        for tool in &self.tools {
+            let _ = writeln!(out, "- {}: {}", tool.name(), tool.description());
+            let _ = writeln!(out, "  schema: {}", tool.parameters_schema());
+        }
+
+        let _ = writeln!(out, "\n=== History ({} messages) ===", self.history.len());
+        for (i, msg) in self.history.iter().enumerate() {
+            let _ = writeln!(out, "[{i}] {}: {}", msg.role, msg.content);
+            if let Some(id) = &msg.tool_call_id {
+                let _ = writeln!(out, "    tool_call_id: {id}");
+            }
+            if let Some(calls) = msg.tool_calls.as_ref().filter(|tc| !tc.is_null()) {
+                let _ = writeln!(out, "    tool_calls: {calls}");
+            }
+            if !msg.images.is_empty() {
+                let _ = writeln!(out, "    images: {}", msg.images.len());
+            }
+        }
+        out
+    }
+
+    /// Removes the message at `index` and returns everything removed.
+    ///
+    /// Tool-call pairs are kept consistent: removing an assistant turn that
+    /// made tool calls also removes their results, and removing a tool result
+    /// removes the whole assistant tool-call group it belongs to.
+    pub fn remove_message(&mut self, index: usize) -> Result<Vec<Message>, AgentError> {
+        let Some(msg) = self.history.get(index) else {
+            return Err(AgentError::InvalidHistory(format!(
+                "cannot remove message {index}: history has {} messages",
+                self.history.len()
+            )));
+        };
+
+        // For a tool result, operate on the assistant turn that requested it.
+        let owner = if msg.role == Role::Tool {
+            self.history[..index]
+                .iter()
+                .rposition(|m| m.role == Role::Assistant)
+                .unwrap_or(index)
+        } else {
+            index
+        };
+
+        let call_ids: HashSet<String> = self.history[owner].tool_call_ids().into_iter().collect();
+
+        let mut removed = vec![];
+        let mut i = 0;
+        self.history.retain(|m| {
+            let remove = i == owner
+                || i == index
+                || (i > owner
+                    && m.role == Role::Tool
+                    && m.tool_call_id.as_ref().is_some_and(|id| call_ids.contains(id)));
+            if remove {
+                removed.push(m.clone());
+            }
+            i += 1;
+            !remove
+        });
+        Ok(removed)
+    }
+
+    /// Text of the most recent assistant turn that had any, e.g. the
+    /// reasoning the model gave before a failed step.
+    pub fn last_partial_content(&self) -> Option<&str> {
+        self.history
+            .iter()
+            .rev()
+            .find(|m| m.role == Role::Assistant && !m.content.is_empty())
+            .map(|m| m.content.as_str())
+    }
+
+    /// Removes the most recent turn — the last user message and everything
+    /// after it — and returns the removed messages. Useful for discarding a
+    /// bad exchange before retrying. Instructions the agent added mid-turn
+    /// (e.g. the JSON retry note) don't count as the start of a turn.
+    pub fn pop_last_turn(&mut self) -> Vec<Message> {
+        let start = self
+            .history
+            .iter()
+            .rposition(|m| m.role == Role::User && !is_agent_note(m))
+            .unwrap_or(0);
+        self.history.split_off(start)
+    }
+
+    /// Drops the oldest messages so at most `max_messages` remain in
+    /// `history`, and returns them. The cut is moved forward past any tool
+    /// results so a tool-call group is never split.
+    pub fn trim_history(&mut self, max_messages: usize) -> Vec<Message> {
+        let mut start = self.history.len().saturating_sub(max_messages);
+        while self.history.get(start).is_some_and(|m| m.role == Role::Tool) {
+            start += 1;
+        }
+        self.history.drain(..start).collect()
+    }
+
+    /// Returns the cached system message and tool definitions, rebuilding
+    /// them only if the system prompt, its role or the tool set changed
+    /// since last time.
+    fn refresh_prompt_cache(&mut self, model: &str) -> PromptCache {
+        let role = self.provider.system_role(model);
+        let base_prompt = self.compact_system_prompt.as_ref().filter(|_| self.near_context_limit(model));
+        let base_prompt = base_prompt.unwrap_or(&self.system_prompt);
+        let mut system_prompt = match self.name.as_ref().filter(|_| self.persona_framing) {
+            Some(name) => format!("You are {name}. {base_prompt}"),
+            None => base_prompt.clone(),
+        };
+        if self.json_output {
+            system_prompt = format!("{system_prompt}\n\n{JSON_INSTRUCTION}");
+        }
+        let mut tools = match &self.selected_tools {
+            Some(indices) => indices.iter().filter_map(|&i| self.tools.get(i)).cloned().collect(),
+            None => self.tools.clone(),
+        };
+        if self.sorted_tools {
+            tools.sort_by(|a, b| a.name().cmp(b.name()));
+        }
+        let cache = PromptCache::refresh(self.prompt_cache.take(), &system_prompt, role, &tools);
+        self.prompt_cache = Some(cache.clone());
+        cache
+    }
+
+    /// Whether `history` is past `compact_prompt_threshold`.
+    fn near_context_limit(&self, model: &str) -> bool {
+        let threshold = self.compact_prompt_threshold.or_else(|| {
+            let window = self.provider.capabilities(model).context_window?;
+            Some(window as usize * 3 / 4)
+        });
+        threshold.is_some_and(|threshold| token_estimate(&self.history, model) > threshold)
+    }
+
+    /// Runs the tool selector, if any, on the input of a new run.
+    async fn select_tools(&mut self, input: &str) -> Result<(), AgentError> {
+        let tools: Vec<&dyn Tool> = self.tools.iter().map(|t| t.as_ref()).collect();
+        let selected = match (&self.semantic_selector, &self.tool_selector) {
+            (Some(semantic), _) => Some(semantic.select(input, &tools).await?),
+            (None, Some(select)) => Some(select(input, &tools)),
+            (None, None) => None,
+        };
+        self.selected_tools = selected;
+        Ok(())
+    }
+
+    /// The messages sent to the provider: the system prompt, any few-shot
+    /// examples, then `history` as the send filter allows, with the run
+    /// context just before the latest user message.
+    fn request_messages(&self, cache: &PromptCache) -> Result<Vec<Message>, AgentError> {
+        // Inject system prompt as first message on every call
+        let mut messages = vec![cache.system.clone()];
+        messages.extend(self.examples.clone());
+        let mut history = self.sent_history()?;
+        if let Some(context) = &self.run_context {
+            let at = history
+                .iter()
+                .rposition(|m| m.role == Role::User && !is_agent_note(m))
+                .unwrap_or(history.len());
+            history.insert(at, context.clone());
+        }
+        messages.extend(history);
+        Ok(messages)
+    }
+
+    /// `history` minus the messages the send filter drops.
+    fn sent_history(&self) -> Result<Vec<Message>, AgentError> {
+        let Some(filter) = &self.send_filter else {
+            return Ok(self.history.clone());
+        };
+        let keep: Vec<bool> = self.history.iter().map(|m| filter(m)).collect();
+
+        // A tool result must share the fate of the assistant turn that asked for it
+        let mut group: Option<(usize, Vec<String>)> = None;
+        for (i, msg) in self.history.iter().enumerate() {
+            if msg.role != Role::Tool {
+                group = (msg.role == Role::Assistant).then(|| (i, msg.tool_call_ids()));
+                continue;
+            }
+            let Some((owner, ids)) = &group else { continue };
+            if msg.tool_call_id.as_ref().is_some_and(|id| ids.contains(id)) && keep[i] != keep[*owner] {
+                return Err(AgentError::InvalidHistory(format!(
+                    "send filter {} tool result {i} but {} the assistant turn {owner} that requested it",
+                    if keep[i] { "keeps" } else { "drops" },
+                    if keep[*owner] { "keeps" } else { "drops" },
+                )));
+            }
+        }
+        Ok(self.history.iter().zip(keep).filter(|(_, keep)| *keep).map(|(m, _)| m.clone()).collect())
+    }
+
+    /// Returns the completion's tool calls, falling back to text parsing
+    /// when enabled.
+    fn resolve_tool_calls(&self, completion: &Completion, step: usize) -> Vec<ToolCall> {
+        if completion.tool_calls.is_empty() && self.text_tool_parsing && !self.tools.is_empty() {
+            let content = completion.content.as_deref().unwrap_or_default();
+            if let Some(call) = self.parse_text_tool_call(content, step) {
+                return vec![call];
+            }
+        }
+        completion.tool_calls.clone()
+    }
+
+    fn check_capabilities(&self, messages: &[Message], tools: &[&dyn Tool], model: &str) -> Result<(), AgentError> {
+        let caps = self.provider.capabilities(model);
+        let unsupported = if !tools.is_empty() && !caps.supports_tools {
+            "tools"
+        } else if messages.iter().any(|m| !m.images.is_empty()) && !caps.supports_vision {
+            "images"
+        } else {
+            return Ok(());
+        };
+        Err(AgentError::ProviderError(format!(
+            "[{}] model '{model}' does not support {unsupported}",
+            self.provider.provider_name()
+        )))
+    }
+
+    /// Calls the provider once, re-requesting up to `retry_on_empty` times
+    /// when the model returns neither content nor tool calls.
+    async fn complete_step(
+        &mut self,
+        messages: &[Message],
+        tools: &[&dyn Tool],
+        model: &str,
+        step: usize,
+    ) -> Result<Completion, AgentError> {
+        providers::validate_tool_call_ids(messages)?;
+        if self.capability_check {
+            self.check_capabilities(messages, tools, model)?;
+        }
+
+        let mut attempt = 0;
+        loop {
+            self.check_deadline()?;
+            if let Some(observer) = &self.observer {
+                observer.on_llm_request(step, messages);
+            }
+            let completion = self
+                .provider
+                .complete(messages, tools, model)
+                .await
+                .map_err(|e| AgentError::StepFailed {
+                    provider: self.provider.provider_name().to_string(),
+                    step,
+                    source: Box::new(e),
+                })?;
+            if let Some(usage) = completion.usage {
+                *self.usage_by_model.entry(model.to_string()).or_default() += usage;
+            }
+            if let Some(fingerprint) = &completion.system_fingerprint {
+                self.record_fingerprint(fingerprint, step);
+            }
+            self.last_raw_response = completion.raw.clone();
+
+            let is_empty = completion.tool_calls.is_empty()
+                && completion.content.as_deref().unwrap_or("").is_empty();
+            if !is_empty || attempt >= self.retry_on_empty {
+                return Ok(completion);
+            }
+            attempt += 1;
+        }
+    }
+
+    fn record_fingerprint(&mut self, fingerprint: &str, step: usize) {
+        // Step 0 starts a new run, so a change there is expected
+        if let Some(previous) = self.fingerprints.last().filter(|_| step > 0) {
+            if let Some(observer) = self.observer.as_ref().filter(|_| previous != fingerprint) {
+                observer.on_fingerprint_change(step, previous, fingerprint);
+            }
+        }
+        self.fingerprints.push(fingerprint.to_string());
+    }
+
+    /// Extracts a tool call from a fenced ```json block in `content`.
+    /// Accepts `{"name", "arguments"}`, `{"tool", "args"}` and the OpenAI
+    /// `{"function": {...}}` shapes; the name must match a registered tool.
+    fn parse_text_tool_call(&self, content: &str, step: usize) -> Option<ToolCall> {
+        let start = content.find("```json")? + "```json".len();
+        let end = start + content[start..].find("```")?;
+        let block: Value = serde_json::from_str(content[start..end].trim()).ok()?;
+        let call = block.get("function").unwrap_or(&block);
+
+        let name = ["name", "tool", "tool_name"]
+            .iter()
+            .find_map(|k| call.get(*k).and_then(|v| v.as_str()))?;
+        if !self.tools.iter().any(|t| t.name() == name) {
+            return None;
+        }
+
+        let args = ["arguments", "args", "parameters", "input"]
+            .iter()
+            .find_map(|k| call.get(*k))
+            .cloned()
+            .unwrap_or_else(|| json!({}));
+        let args = match args.as_str() {
+            Some(s) => serde_json::from_str(s).ok()?,
+            None => args,
+        };
+
+        Some(ToolCall { id: format!("text_call_{step}"), name: name.to_string(), args })
+    }
+
+    fn assistant_message(&self, content: String, tool_calls: &[ToolCall]) -> Message {
+        let tool_calls = ToolCall::to_message_value(tool_calls);
+        Message { name: self.name.clone(), ..Message::assistant_with_tools(content, tool_calls) }
+    }
+
+    fn push_history(&mut self, message: Message) {
+        if let Some(log) = &self.transcript {
+            log.append(&message);
+        }
+        self.history.push(message);
+    }
+
+    /// Enforces `max_tool_arg_bytes`.
+    fn check_arg_size(&self, call: &ToolCall) -> Result<(), AgentError> {
+        let Some(limit) = self.max_tool_arg_bytes else { return Ok(()) };
+        let size = call.args.to_string().len();
+        if size > limit {
+            return Err(AgentError::ToolError(format!(
+                "arguments for '{}' are {size} bytes, over the {limit}-byte limit",
+                call.name
+            )));
+        }
+        Ok(())
+    }
+
+    /// Runs the tool and returns its output along with the text the model
+    /// will see for it.
+    async fn execute_tool(&self, call: &ToolCall) -> Result<(ToolOutput, String), CallFailure> {
+        self.check_arg_size(call).map_err(CallFailure::Rejected)?;
         let tool = self
             .tools
             .iter()
             .find(|t| t.name() == call.name)
             .ok_or_else(|| AgentError::ToolNotFound(call.name.clone()))?;
-        tool.execute(call.args.clone()).await
+        let args = if self.arg_coercion {
+            coerce_args(call.args.clone(), &tool.parameters_schema())
+        } else {
+            call.args.clone()
+        };
+        let output = tool.execute_output(args).await?;
+        let mut content = tool.format_for_model(&output);
+        if let Some(postprocess) = &self.tool_postprocessor {
+            content = postprocess(&call.name, content).await;
+        }
+        Ok((output, content))
     }
 }
\ No newline at end of file
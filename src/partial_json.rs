@@ -0,0 +1,126 @@
+/// Incremental parsing of a JSON document that is still streaming in, so a
+/// UI can show fields of a structured answer as they arrive.
+use serde_json::Value;
+
+/// Accumulates streamed text and parses the JSON received so far on a
+/// best-effort basis: open strings, arrays and objects are closed, and a
+/// trailing member that can't be completed yet (a half-written key, a
+/// partial number or literal) is left out. Text before the first `{` or `[`,
+/// such as a code fence, is ignored. Feed it from `complete_stream`'s
+/// `on_delta` callback.
+#[derive(Debug, Clone, Default)]
+pub struct PartialJson {
+    buffer: String,
+}
+
+impl PartialJson {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends a streamed fragment and returns the best-effort parse of
+    /// everything received so far.
+    pub fn push(&mut self, delta: &str) -> Option<Value> {
+        self.buffer.push_str(delta);
+        self.value()
+    }
+
+    /// Everything received so far.
+    pub fn text(&self) -> &str {
+        &self.buffer
+    }
+
+    /// The best-effort parse of the text so far, or `None` before the
+    /// document has started.
+    pub fn value(&self) -> Option<Value> {
+        let start = self.buffer.find(['{', '['])?;
+        let mut text = &self.buffer[start..];
+        // Ignore whatever follows a finished document, e.g. a closing fence
+        let mut depth = 0usize;
+        let mut end = None;
+        scan(text, |i, c| match c {
+            '{' | '[' => depth += 1,
+            '}' | ']' => {
+                depth = depth.saturating_sub(1);
+                if depth == 0 && end.is_none() {
+                    end = Some(i + 1);
+                }
+            }
+            _ => {}
+        });
+        if let Some(end) = end {
+            text = &text[..end];
+        }
+
+        if let Some(value) = close(text) {
+            return Some(value);
+        }
+        // Drop the incomplete trailing member and try again, one member at
+        // a time.
+        boundaries(text).into_iter().rev().find_map(|end| close(&text[..end]))
+    }
+}
+
+/// Calls `on_char` with each character of `text` outside string literals
+/// (quotes excluded) and returns the state at the end: whether a string is
+/// still open, and whether it ends in an unfinished escape.
+fn scan(text: &str, mut on_char: impl FnMut(usize, char)) -> (bool, bool) {
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, c) in text.char_indices() {
+        if in_string {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => in_string = false,
+                _ => {}
+            }
+        } else if c == '"' {
+            in_string = true;
+        } else {
+            on_char(i, c);
+        }
+    }
+    (in_string, escaped)
+}
+
+/// Where `text` may be cut to drop a trailing member: just before each
+/// comma and just after each opening bracket.
+fn boundaries(text: &str) -> Vec<usize> {
+    let mut cuts = vec![];
+    scan(text, |i, c| match c {
+        ',' => cuts.push(i),
+        '{' | '[' => cuts.push(i + 1),
+        _ => {}
+    });
+    cuts
+}
+
+/// Parses `text` after closing its open string and containers.
+fn close(text: &str) -> Option<Value> {
+    let mut open = vec![];
+    let (in_string, escaped) = scan(text, |_, c| match c {
+        '{' => open.push('}'),
+        '[' => open.push(']'),
+        '}' | ']' => {
+            open.pop();
+        }
+        _ => {}
+    });
+
+    let mut completed = text.to_string();
+    if in_string {
+        if escaped {
+            completed.pop();
+        }
+        // A \u escape cut short can't be closed
+        if let Some(escape) = completed.rfind("\\u").filter(|&i| completed.len() - i < 6) {
+            completed.truncate(escape);
+        }
+        completed.push('"');
+    }
+    let trimmed_len = completed.trim_end().trim_end_matches(',').len();
+    completed.truncate(trimmed_len);
+    completed.extend(open.iter().rev());
+    serde_json::from_str(&completed).ok()
+}
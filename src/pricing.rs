@@ -0,0 +1,99 @@
+/// Per-model token prices, used to turn reported usage into an estimated
+/// dollar cost.
+///
+/// The built-in table covers common hosted models at their published list
+/// prices; providers change these, so override them with `with_price` when
+/// exact numbers matter.
+use std::collections::HashMap;
+
+use crate::Usage;
+
+/// USD per 1,000 tokens.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ModelPrice {
+    pub input_per_1k: f64,
+    pub output_per_1k: f64,
+}
+
+impl ModelPrice {
+    pub fn new(input_per_1k: f64, output_per_1k: f64) -> Self {
+        Self { input_per_1k, output_per_1k }
+    }
+
+    /// Cost of `usage` at this price.
+    pub fn cost(&self, usage: Usage) -> f64 {
+        f64::from(usage.prompt_tokens) / 1000.0 * self.input_per_1k
+            + f64::from(usage.completion_tokens) / 1000.0 * self.output_per_1k
+    }
+}
+
+const DEFAULT_PRICES: &[(&str, f64, f64)] = &[
+    // OpenAI
+    ("gpt-4o", 0.0025, 0.01),
+    ("gpt-4o-mini", 0.00015, 0.0006),
+    ("gpt-4.1", 0.002, 0.008),
+    ("gpt-4.1-mini", 0.0004, 0.0016),
+    ("gpt-4.1-nano", 0.0001, 0.0004),
+    ("gpt-4-turbo", 0.01, 0.03),
+    ("gpt-3.5-turbo", 0.0005, 0.0015),
+    ("o1", 0.015, 0.06),
+    ("o3-mini", 0.0011, 0.0044),
+    ("o4-mini", 0.0011, 0.0044),
+    // Anthropic
+    ("claude-3-haiku", 0.00025, 0.00125),
+    ("claude-3-5-haiku", 0.0008, 0.004),
+    ("claude-3-5-sonnet", 0.003, 0.015),
+    ("claude-3-7-sonnet", 0.003, 0.015),
+    ("claude-sonnet-4", 0.003, 0.015),
+    ("claude-3-opus", 0.015, 0.075),
+    ("claude-opus-4", 0.015, 0.075),
+    // Groq
+    ("llama-3.3-70b-versatile", 0.00059, 0.00079),
+    ("llama-3.1-8b-instant", 0.00005, 0.00008),
+];
+
+/// Maps model names to prices. A model matches the longest entry it starts
+/// with, so `"gpt-4o-2024-08-06"` is priced as `"gpt-4o"` and
+/// `"gpt-4o-mini"` as itself; provider-prefixed slugs such as
+/// `"openai/gpt-4o"` are looked up by their final path segment.
+#[derive(Debug, Clone)]
+pub struct PriceTable {
+    prices: HashMap<String, ModelPrice>,
+}
+
+impl Default for PriceTable {
+    /// The built-in table of common models.
+    fn default() -> Self {
+        DEFAULT_PRICES
+            .iter()
+            .fold(Self::empty(), |table, &(model, input, output)| table.with_price(model, input, output))
+    }
+}
+
+impl PriceTable {
+    /// A table with no prices at all.
+    pub fn empty() -> Self {
+        Self { prices: HashMap::new() }
+    }
+
+    /// Sets (or replaces) the price of `model`, in USD per 1K tokens.
+    pub fn with_price(mut self, model: impl Into<String>, input_per_1k: f64, output_per_1k: f64) -> Self {
+        self.prices.insert(model.into(), ModelPrice::new(input_per_1k, output_per_1k));
+        self
+    }
+
+    /// The price for `model`, or `None` if no entry matches.
+    pub fn price(&self, model: &str) -> Option<ModelPrice> {
+        let bare_model = model.rsplit('/').next().unwrap_or(model);
+        self.prices
+            .iter()
+            .filter(|(name, _)| bare_model.starts_with(name.as_str()))
+            .max_by_key(|(name, _)| name.len())
+            .map(|(_, price)| *price)
+    }
+
+    /// Cost of `usage` on `model`, or `None` if the model has no price.
+    pub fn cost(&self, model: &str, usage: Usage) -> Option<f64> {
+        self.price(model).map(|price| price.cost(usage))
+    }
+}
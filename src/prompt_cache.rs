@@ -0,0 +1,90 @@
+/// Cache of the stable request prefix — the system message and the tool
+/// definitions — so repeated `run` calls don't rebuild them.
+///
+/// The entry is keyed by a hash of the system prompt, the role it is sent
+/// with, and the identity of each tool `Arc`; changing any of them (e.g.
+/// `add_tool`, or replacing a tool with one of the same name) rebuilds it.
+/// The entry keeps the tools it wraps alive, so a replaced tool's address
+/// can't be reused while it still matches.
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use serde_json::Value;
+
+use crate::{AgentError, Message, Role, Tool, ToolOutput};
+
+#[derive(Clone)]
+pub(crate) struct PromptCache {
+    key: u64,
+    pub(crate) system: Message,
+    pub(crate) tools: Vec<Arc<dyn Tool>>,
+}
+
+impl PromptCache {
+    /// Returns `cache` if it still matches, otherwise a freshly built entry.
+    /// `role` is the provider's native system role; without one the prompt is
+    /// sent as a `[SYSTEM]:`-prefixed user message.
+    pub(crate) fn refresh(
+        cache: Option<PromptCache>,
+        system_prompt: &str,
+        role: Option<Role>,
+        tools: &[Arc<dyn Tool>],
+    ) -> PromptCache {
+        let key = prefix_hash(system_prompt, role, tools);
+        if let Some(cache) = cache.filter(|c| c.key == key) {
+            return cache;
+        }
+        let system = match role {
+            Some(role) => Message { role, content: system_prompt.to_string(), ..Default::default() },
+            None => Message {
+                role: Role::User,
+                content: format!("[SYSTEM]: {system_prompt}"),
+                ..Default::default()
+            },
+        };
+        PromptCache {
+            key,
+            system,
+            tools: tools
+                .iter()
+                .map(|tool| {
+                    Arc::new(CachedSchemaTool { schema: tool.parameters_schema(), inner: Arc::clone(tool) })
+                        as Arc<dyn Tool>
+                })
+                .collect(),
+        }
+    }
+}
+
+fn prefix_hash(system_prompt: &str, role: Option<Role>, tools: &[Arc<dyn Tool>]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    system_prompt.hash(&mut hasher);
+    role.hash(&mut hasher);
+    for tool in tools {
+        (Arc::as_ptr(tool) as *const () as usize).hash(&mut hasher);
+    }
+    hasher.finish()
+}
+
+/// Wraps a tool with its schema computed once up front.
+struct CachedSchemaTool {
+    inner: Arc<dyn Tool>,
+    schema: Value,
+}
+
+#[async_trait]
+impl Tool for CachedSchemaTool {
+    fn name(&self) -> &'static str { self.inner.name() }
+    fn description(&self) -> &'static str { self.inner.description() }
+    fn parameters_schema(&self) -> Value { self.schema.clone() }
+    async fn execute(&self, args: Value) -> Result<String, AgentError> {
+        self.inner.execute(args).await
+    }
+    async fn execute_output(&self, args: Value) -> Result<ToolOutput, AgentError> {
+        self.inner.execute_output(args).await
+    }
+    fn terminal(&self) -> bool { self.inner.terminal() }
+    fn format_for_model(&self, output: &ToolOutput) -> String { self.inner.format_for_model(output) }
+}
@@ -0,0 +1,98 @@
+//! Known-model capability tables used by the built-in providers'
+//! `LlmProvider::capabilities`. Entries are matched by model-name prefix,
+//! first match wins, so more specific prefixes come first.
+
+use crate::ModelCapabilities;
+
+const fn caps(tools: bool, vision: bool, context_window: u32) -> ModelCapabilities {
+    ModelCapabilities {
+        supports_tools: tools,
+        supports_vision: vision,
+        supports_streaming: true,
+        context_window: Some(context_window),
+    }
+}
+
+const OPENAI: &[(&str, ModelCapabilities)] = &[
+    ("gpt-4.1", caps(true, true, 1_047_576)),
+    ("gpt-4o", caps(true, true, 128_000)),
+    ("gpt-4-turbo", caps(true, true, 128_000)),
+    ("gpt-4", caps(true, false, 8_192)),
+    ("gpt-3.5-turbo", caps(true, false, 16_385)),
+    ("gpt-5", caps(true, true, 400_000)),
+    ("o1-mini", caps(false, false, 128_000)),
+    ("o1", caps(true, true, 200_000)),
+    ("o3", caps(true, true, 200_000)),
+    ("o4-mini", caps(true, true, 200_000)),
+];
+
+const ANTHROPIC: &[(&str, ModelCapabilities)] = &[
+    ("claude-2", caps(false, false, 100_000)),
+    ("claude-instant", caps(false, false, 100_000)),
+    ("claude-", caps(true, true, 200_000)),
+];
+
+const GROQ: &[(&str, ModelCapabilities)] = &[
+    ("llama-3.3-70b-versatile", caps(true, false, 131_072)),
+    ("llama-3.1-8b-instant", caps(true, false, 131_072)),
+    ("meta-llama/llama-4", caps(true, true, 131_072)),
+    ("gemma2-9b-it", caps(false, false, 8_192)),
+];
+
+const OLLAMA: &[(&str, ModelCapabilities)] = &[
+    ("llama3.2-vision", caps(false, true, 131_072)),
+    ("llama3.1", caps(true, false, 131_072)),
+    ("llama3.2", caps(true, false, 131_072)),
+    ("llama3.3", caps(true, false, 131_072)),
+    ("qwen2.5", caps(true, false, 32_768)),
+    ("mistral", caps(true, false, 32_768)),
+    ("llava", caps(false, true, 4_096)),
+    ("llama3", caps(false, false, 8_192)),
+];
+
+/// Families that spend output tokens on hidden reasoning before answering.
+const REASONING_FAMILIES: &[&str] = &["o1", "o3", "o4", "gpt-5"];
+
+pub(crate) fn is_reasoning_model(model: &str) -> bool {
+    let bare_model = model.rsplit('/').next().unwrap_or(model);
+    REASONING_FAMILIES.iter().any(|family| bare_model.starts_with(family))
+}
+
+/// Output budget for a request that doesn't set `max_tokens`: plenty for
+/// reasoning models, otherwise a quarter of the context window capped at
+/// 4,096, and 1,024 when the window is unknown.
+pub(crate) fn default_max_tokens(model: &str, caps: ModelCapabilities) -> u32 {
+    if is_reasoning_model(model) {
+        return 16_384;
+    }
+    caps.context_window.map_or(1_024, |window| (window / 4).clamp(256, 4_096))
+}
+
+fn lookup(table: &[(&str, ModelCapabilities)], model: &str) -> Option<ModelCapabilities> {
+    table.iter().find(|(prefix, _)| model.starts_with(prefix)).map(|(_, caps)| *caps)
+}
+
+pub(crate) fn openai(model: &str) -> ModelCapabilities {
+    lookup(OPENAI, model).unwrap_or_default()
+}
+
+pub(crate) fn anthropic(model: &str) -> ModelCapabilities {
+    lookup(ANTHROPIC, model).unwrap_or_default()
+}
+
+pub(crate) fn groq(model: &str) -> ModelCapabilities {
+    lookup(GROQ, model).unwrap_or_default()
+}
+
+pub(crate) fn ollama(model: &str) -> ModelCapabilities {
+    lookup(OLLAMA, model).unwrap_or_default()
+}
+
+/// OpenRouter ids are `vendor/model`; known vendors reuse their own table.
+pub(crate) fn openrouter(model: &str) -> ModelCapabilities {
+    match model.split_once('/') {
+        Some(("openai", name)) => openai(name),
+        Some(("anthropic", name)) => anthropic(name),
+        _ => ModelCapabilities::default(),
+    }
+}
@@ -0,0 +1,117 @@
+/// VCR-style provider for deterministic tests: completions are recorded to a
+/// JSON "cassette" file the first time a request is seen and replayed from
+/// it afterwards, so test suites can run offline. Only available with the
+/// `testing` feature.
+///
+/// Requests are matched by a stable hash of the model, the messages (role,
+/// content, tool call ids and calls — not timestamps) and the tool schemas.
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use async_trait::async_trait;
+use serde_json::json;
+
+use crate::{AgentError, Completion, LlmProvider, Message, Tool};
+
+pub struct CassetteProvider {
+    inner: Option<Box<dyn LlmProvider>>,
+    path: PathBuf,
+    entries: Mutex<BTreeMap<String, Completion>>,
+}
+
+impl CassetteProvider {
+    /// Replays from the cassette at `path` when it has the request, and
+    /// otherwise forwards to `inner` and records the answer. The file is
+    /// created on the first recording.
+    pub fn new(inner: Box<dyn LlmProvider>, path: impl AsRef<Path>) -> Result<Self, AgentError> {
+        Self::open(Some(inner), path.as_ref())
+    }
+
+    /// Replays from the cassette at `path` only; a request that was never
+    /// recorded fails with `ProviderError`.
+    pub fn replay(path: impl AsRef<Path>) -> Result<Self, AgentError> {
+        Self::open(None, path.as_ref())
+    }
+
+    fn open(inner: Option<Box<dyn LlmProvider>>, path: &Path) -> Result<Self, AgentError> {
+        let entries = match std::fs::read_to_string(path) {
+            Ok(text) => serde_json::from_str(&text)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound && inner.is_some() => BTreeMap::new(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Self { inner, path: path.to_path_buf(), entries: Mutex::new(entries) })
+    }
+
+    fn save(&self, entries: &BTreeMap<String, Completion>) -> Result<(), AgentError> {
+        std::fs::write(&self.path, serde_json::to_string_pretty(entries)?)?;
+        Ok(())
+    }
+}
+
+/// FNV-1a over the canonical request JSON — unlike `DefaultHasher`, stable
+/// across Rust releases, which matters for files checked into a repo.
+/// Images are only part of the JSON when a message has some, so keys
+/// recorded for text-only requests stay valid.
+fn request_key(messages: &[Message], tools: &[&dyn Tool], model: &str) -> String {
+    let request = json!({
+        "model": model,
+        "messages": messages
+            .iter()
+            .map(|m| {
+                let mut message = json!({
+                    "role": m.role,
+                    "content": m.content,
+                    "tool_call_id": m.tool_call_id,
+                    "tool_calls": m.tool_calls,
+                });
+                if !m.images.is_empty() {
+                    message["images"] = json!(m.images);
+                }
+                message
+            })
+            .collect::<Vec<_>>(),
+        "tools": tools
+            .iter()
+            .map(|t| json!({ "name": t.name(), "parameters": t.parameters_schema() }))
+            .collect::<Vec<_>>(),
+    });
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in request.to_string().bytes() {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    format!("{hash:016x}")
+}
+
+#[async_trait]
+impl LlmProvider for CassetteProvider {
+    fn provider_name(&self) -> &str {
+        self.inner.as_ref().map_or("Cassette", |inner| inner.provider_name())
+    }
+
+    async fn complete(
+        &self,
+        messages: &[Message],
+        tools: &[&dyn Tool],
+        model: &str,
+    ) -> Result<Completion, AgentError> {
+        let key = request_key(messages, tools, model);
+        if let Some(recorded) = self.entries.lock().unwrap().get(&key) {
+            return Ok(recorded.clone());
+        }
+
+        let Some(inner) = &self.inner else {
+            return Err(AgentError::ProviderError(format!(
+                "no recorded response for request {key} in {}",
+                self.path.display()
+            )));
+        };
+        let completion = inner.complete(messages, tools, model).await?;
+
+        let mut entries = self.entries.lock().unwrap();
+        entries.insert(key, completion.clone());
+        self.save(&entries)?;
+        Ok(completion)
+    }
+}
@@ -0,0 +1,191 @@
+/// Spreads requests across several providers — typically the same API under
+/// different keys or accounts — for throughput. Each `complete` call goes
+/// to exactly one of them; a failure is returned as-is, not retried on
+/// another provider, but a provider that keeps failing is skipped for a
+/// while (see `with_circuit_breaker`).
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use crate::{AgentError, Clock, Completion, LlmProvider, Message, ModelCapabilities, Role, SystemClock, Tool};
+
+/// Consecutive failures and, once tripped, when the provider may be tried
+/// again.
+#[derive(Debug, Default)]
+struct Health {
+    failures: u32,
+    open_until: Option<Instant>,
+}
+
+/// Picks a provider per request, in proportion to their weights: at random
+/// by default, or in a fixed weighted rotation with `with_round_robin`.
+/// Providers with weight 0 are never picked, and neither are providers
+/// whose circuit breaker is open.
+///
+/// `system_role` and `capabilities` come from the first provider, so the
+/// providers should serve the same models.
+pub struct LoadBalancedProvider {
+    providers: Vec<(Box<dyn LlmProvider>, u32)>,
+    health: Vec<Mutex<Health>>,
+    failure_threshold: u32,
+    cooldown: Duration,
+    clock: Arc<dyn Clock>,
+    round_robin: bool,
+    rng: Mutex<StdRng>,
+    requests: AtomicU64,
+}
+
+impl LoadBalancedProvider {
+    pub fn new() -> Self {
+        Self {
+            providers: vec![],
+            health: vec![],
+            failure_threshold: 3,
+            cooldown: Duration::from_secs(30),
+            clock: Arc::new(SystemClock),
+            round_robin: false,
+            rng: Mutex::new(StdRng::from_entropy()),
+            requests: AtomicU64::new(0),
+        }
+    }
+
+    /// Adds `provider`, receiving a `weight` share of the requests.
+    pub fn with_provider(mut self, provider: Box<dyn LlmProvider>, weight: u32) -> Self {
+        self.providers.push((provider, weight));
+        self.health.push(Mutex::default());
+        self
+    }
+
+    /// Rotates through the providers instead of picking at random: with
+    /// weights 2 and 1, every three requests go A, A, B.
+    pub fn with_round_robin(mut self) -> Self {
+        self.round_robin = true;
+        self
+    }
+
+    /// Seeds the random choice, for reproducible tests.
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.rng = Mutex::new(StdRng::seed_from_u64(seed));
+        self
+    }
+
+    /// Takes a provider out of rotation for `cooldown` once it has failed
+    /// `failures` requests in a row (3 and 30 seconds by default). After the
+    /// cooldown it gets traffic again; one more failure trips it again, a
+    /// success resets its count.
+    pub fn with_circuit_breaker(mut self, failures: u32, cooldown: Duration) -> Self {
+        self.failure_threshold = failures.max(1);
+        self.cooldown = cooldown;
+        self
+    }
+
+    /// Replaces the clock used for circuit breaker cooldowns, e.g. with a
+    /// `MockClock` in tests.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    fn pick(&self) -> Result<usize, AgentError> {
+        let now = self.clock.now();
+        let weights: Vec<u64> = self
+            .providers
+            .iter()
+            .zip(&self.health)
+            .map(|((_, weight), health)| {
+                let open = health.lock().unwrap().open_until.is_some_and(|until| until > now);
+                if open { 0 } else { u64::from(*weight) }
+            })
+            .collect();
+        let total: u64 = weights.iter().sum();
+        if total == 0 {
+            let reason = if self.providers.iter().any(|(_, weight)| *weight > 0) {
+                "every provider is cooling down after repeated failures"
+            } else {
+                "load balancer has no provider with a non-zero weight"
+            };
+            return Err(AgentError::ProviderError(reason.into()));
+        }
+        let mut slot = if self.round_robin {
+            self.requests.fetch_add(1, Ordering::Relaxed) % total
+        } else {
+            self.rng.lock().unwrap().gen_range(0..total)
+        };
+        for (index, weight) in weights.into_iter().enumerate() {
+            if slot < weight {
+                return Ok(index);
+            }
+            slot -= weight;
+        }
+        unreachable!("slot is below the total weight")
+    }
+
+    /// Updates provider `index`'s breaker with the outcome of a request.
+    fn record(&self, index: usize, result: &Result<Completion, AgentError>) {
+        let mut health = self.health[index].lock().unwrap();
+        if result.is_ok() {
+            *health = Health::default();
+            return;
+        }
+        health.failures += 1;
+        if health.failures >= self.failure_threshold {
+            health.open_until = Some(self.clock.now() + self.cooldown);
+        }
+    }
+}
+
+impl Default for LoadBalancedProvider {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait]
+impl LlmProvider for LoadBalancedProvider {
+    fn provider_name(&self) -> &str { "LoadBalanced" }
+
+    async fn complete(
+        &self,
+        messages: &[Message],
+        tools: &[&dyn Tool],
+        model: &str,
+    ) -> Result<Completion, AgentError> {
+        let index = self.pick()?;
+        let result = self.providers[index].0.complete(messages, tools, model).await;
+        self.record(index, &result);
+        result
+    }
+
+    async fn complete_stream(
+        &self,
+        messages: &[Message],
+        tools: &[&dyn Tool],
+        model: &str,
+        on_delta: &mut (dyn for<'s> FnMut(&'s str) + Send),
+    ) -> Result<Completion, AgentError> {
+        let index = self.pick()?;
+        let result = self.providers[index].0.complete_stream(messages, tools, model, on_delta).await;
+        self.record(index, &result);
+        result
+    }
+
+    fn system_role(&self, model: &str) -> Option<Role> {
+        self.providers.first().and_then(|(provider, _)| provider.system_role(model))
+    }
+
+    fn capabilities(&self, model: &str) -> ModelCapabilities {
+        self.providers.first().map(|(provider, _)| provider.capabilities(model)).unwrap_or_default()
+    }
+
+    /// Checks every provider, failing on the first that is unhealthy.
+    async fn health_check(&self) -> Result<(), AgentError> {
+        for (provider, _) in &self.providers {
+            provider.health_check().await?;
+        }
+        Ok(())
+    }
+}
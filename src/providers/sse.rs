@@ -0,0 +1,78 @@
+//! Incremental parser for `text/event-stream` response bodies, shared by
+//! the streaming implementations of each provider.
+
+/// One server-sent event: the optional `event:` name and its `data:` payload
+/// (multiple `data:` lines are joined with `\n`).
+#[derive(Debug, Clone, PartialEq)]
+pub struct SseEvent {
+    pub event: Option<String>,
+    pub data: String,
+}
+
+/// Feed raw body chunks in as they arrive; complete events come back out.
+/// Chunks may split lines, events or multi-byte UTF-8 characters anywhere.
+#[derive(Debug, Default)]
+pub struct SseDecoder {
+    buffer: String,
+    /// Trailing bytes of a UTF-8 character whose rest hasn't arrived yet.
+    partial_char: Vec<u8>,
+    event: Option<String>,
+    data: Vec<String>,
+}
+
+impl SseDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, chunk: &[u8]) -> Vec<SseEvent> {
+        self.decode_utf8(chunk);
+
+        let mut events = vec![];
+        while let Some(pos) = self.buffer.find('\n') {
+            let line: String = self.buffer.drain(..=pos).collect();
+            let line = line.trim_end_matches(['\n', '\r']);
+
+            if line.is_empty() {
+                // Blank line terminates the event
+                if !self.data.is_empty() {
+                    events.push(SseEvent { event: self.event.take(), data: self.data.join("\n") });
+                    self.data.clear();
+                }
+                self.event = None;
+            } else if let Some(value) = line.strip_prefix("data:") {
+                self.data.push(value.strip_prefix(' ').unwrap_or(value).to_string());
+            } else if let Some(value) = line.strip_prefix("event:") {
+                self.event = Some(value.trim_start().to_string());
+            }
+            // Comments (`:`) and `id:`/`retry:` fields are ignored
+        }
+        events
+    }
+
+    /// Appends `chunk` to the text buffer, holding back an incomplete
+    /// character at the end until the next chunk completes it. Bytes that
+    /// can never be valid become U+FFFD.
+    fn decode_utf8(&mut self, chunk: &[u8]) {
+        self.partial_char.extend_from_slice(chunk);
+        let mut bytes = std::mem::take(&mut self.partial_char);
+        loop {
+            match std::str::from_utf8(&bytes) {
+                Ok(text) => {
+                    self.buffer.push_str(text);
+                    return;
+                }
+                Err(e) => {
+                    let (valid, rest) = bytes.split_at(e.valid_up_to());
+                    self.buffer.push_str(&String::from_utf8_lossy(valid));
+                    let Some(invalid_len) = e.error_len() else {
+                        self.partial_char = rest.to_vec();
+                        return;
+                    };
+                    self.buffer.push(char::REPLACEMENT_CHARACTER);
+                    bytes = rest[invalid_len..].to_vec();
+                }
+            }
+        }
+    }
+}
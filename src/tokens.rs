@@ -0,0 +1,52 @@
+/// Token counting helpers used to budget context before sending a request.
+///
+/// OpenAI models are counted exactly with `tiktoken-rs`; every other model
+/// falls back to the common "one token per four characters" heuristic.
+use tiktoken_rs::tokenizer::{get_tokenizer, Tokenizer};
+use tiktoken_rs::{cl100k_base_singleton, o200k_base_singleton, p50k_base_singleton, r50k_base_singleton};
+
+use crate::Message;
+
+/// Fixed per-message framing cost of the chat format (`<|start|>role ... <|end|>`).
+const TOKENS_PER_MESSAGE: usize = 3;
+/// Every reply is primed with `<|start|>assistant<|message|>`.
+const REPLY_PRIMING_TOKENS: usize = 3;
+
+/// Estimates how many prompt tokens `messages` will cost for `model`.
+///
+/// Provider-prefixed slugs such as `"openai/gpt-4o"` are recognised by their
+/// final path segment. Unknown models use a `chars / 4` heuristic, which is
+/// usually within ~10–20% for English text.
+pub fn token_estimate(messages: &[Message], model: &str) -> usize {
+    let bare_model = model.rsplit('/').next().unwrap_or(model);
+    let count: Box<dyn Fn(&str) -> usize> = match get_tokenizer(bare_model) {
+        Some(tokenizer) => {
+            let bpe = match tokenizer {
+                Tokenizer::O200kBase => o200k_base_singleton(),
+                Tokenizer::Cl100kBase => cl100k_base_singleton(),
+                Tokenizer::P50kBase | Tokenizer::P50kEdit => p50k_base_singleton(),
+                Tokenizer::R50kBase | Tokenizer::Gpt2 => r50k_base_singleton(),
+            };
+            Box::new(move |text: &str| bpe.lock().encode_ordinary(text).len())
+        }
+        None => Box::new(heuristic_tokens),
+    };
+
+    let mut total = REPLY_PRIMING_TOKENS;
+    for m in messages {
+        total += TOKENS_PER_MESSAGE;
+        total += count(&m.role.to_string());
+        total += count(&m.content);
+        if let Some(id) = &m.tool_call_id {
+            total += count(id);
+        }
+        if let Some(tc) = m.tool_calls.as_ref().filter(|tc| !tc.is_null()) {
+            total += count(&tc.to_string());
+        }
+    }
+    total
+}
+
+fn heuristic_tokens(text: &str) -> usize {
+    text.chars().count().div_ceil(4)
+}
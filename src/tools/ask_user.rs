@@ -0,0 +1,47 @@
+/// Ask-user tool — lets the model pause a run to ask the human a clarifying
+/// question, for interactive agents.
+use async_trait::async_trait;
+use serde_json::{json, Value};
+
+use crate::{AgentError, BoxFuture, Tool};
+
+type AskFn = dyn Fn(&str) -> BoxFuture<String> + Send + Sync;
+
+/// Passes the model's question to a callback (e.g. one that prompts on the
+/// terminal or in a chat UI) and returns the user's reply as the tool
+/// result. The run waits until the callback resolves.
+pub struct AskUserTool {
+    ask: Box<AskFn>,
+}
+
+impl AskUserTool {
+    pub fn new(ask: impl Fn(&str) -> BoxFuture<String> + Send + Sync + 'static) -> Self {
+        Self { ask: Box::new(ask) }
+    }
+}
+
+#[async_trait]
+impl Tool for AskUserTool {
+    fn name(&self) -> &'static str { "ask_user" }
+    fn description(&self) -> &'static str {
+        "Asks the user a clarifying question and returns their reply. Use only when the request is ambiguous \
+         and you cannot proceed without their input"
+    }
+    fn parameters_schema(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "question": { "type": "string", "description": "The question to ask the user" }
+            },
+            "required": ["question"],
+            "additionalProperties": false
+        })
+    }
+    async fn execute(&self, args: Value) -> Result<String, AgentError> {
+        let question = args["question"]
+            .as_str()
+            .filter(|q| !q.trim().is_empty())
+            .ok_or_else(|| AgentError::ToolError("Missing 'question'".into()))?;
+        Ok((self.ask)(question).await)
+    }
+}
@@ -0,0 +1,53 @@
+/// Config read tool — lets ops agents look up environment settings without
+/// exposing the whole environment.
+use async_trait::async_trait;
+use serde_json::{json, Value};
+
+use crate::{AgentError, Tool};
+
+/// Returns the value of an environment variable, or `"not set"`. Only keys
+/// on the allowlist can be read, so secrets such as API keys stay out of
+/// the model's reach unless explicitly listed.
+pub struct ConfigReadTool {
+    allowed_keys: Vec<String>,
+}
+
+impl ConfigReadTool {
+    /// `allowed_keys` are exact, case-sensitive variable names.
+    pub fn new<I, S>(allowed_keys: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        Self { allowed_keys: allowed_keys.into_iter().map(Into::into).collect() }
+    }
+}
+
+#[async_trait]
+impl Tool for ConfigReadTool {
+    fn name(&self) -> &'static str { "read_config" }
+    fn description(&self) -> &'static str {
+        "Reads a configuration value from the environment. Only permitted keys can be read"
+    }
+    fn parameters_schema(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "key": {
+                    "type": "string",
+                    "enum": self.allowed_keys,
+                    "description": "Name of the environment variable"
+                }
+            },
+            "required": ["key"],
+            "additionalProperties": false
+        })
+    }
+    async fn execute(&self, args: Value) -> Result<String, AgentError> {
+        let key = args["key"].as_str().ok_or_else(|| AgentError::ToolError("Missing 'key'".into()))?;
+        if !self.allowed_keys.iter().any(|allowed| allowed == key) {
+            return Err(AgentError::ToolError(format!("key '{key}' is not on the allowlist")));
+        }
+        Ok(std::env::var(key).unwrap_or_else(|_| "not set".to_string()))
+    }
+}
@@ -0,0 +1,165 @@
+/// Currency conversion with live exchange rates.
+///
+/// Rates come from a pluggable [`RateSource`] (by default the free, keyless
+/// open.er-api.com endpoint) and are cached for a short TTL so repeated
+/// conversions don't hammer the API.
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use reqwest::Client;
+use serde_json::{json, Value};
+
+use crate::{AgentError, Clock, SystemClock, Tool};
+
+/// Where exchange rates come from. Implement this to plug in another API
+/// or a fixed table in tests.
+#[async_trait]
+pub trait RateSource: Send + Sync {
+    /// How many units of `to` one unit of `from` buys. Codes are ISO 4217, upper-case.
+    async fn rate(&self, from: &str, to: &str) -> Result<f64, AgentError>;
+}
+
+/// Fetches rates from an open.er-api.com compatible endpoint
+/// (`GET {base_url}/latest/{FROM}` → `{ "rates": { "EUR": 0.92, ... } }`).
+pub struct HttpRateSource {
+    client: Client,
+    base_url: String,
+}
+
+impl HttpRateSource {
+    pub fn new() -> Self {
+        Self::with_base_url("https://open.er-api.com/v6")
+    }
+
+    pub fn with_base_url(base_url: impl Into<String>) -> Self {
+        Self {
+            client: Client::new(),
+            base_url: base_url.into().trim_end_matches('/').to_string(),
+        }
+    }
+}
+
+impl Default for HttpRateSource {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait]
+impl RateSource for HttpRateSource {
+    async fn rate(&self, from: &str, to: &str) -> Result<f64, AgentError> {
+        let url = format!("{}/latest/{}", self.base_url, from);
+        let response = self
+            .client
+            .get(&url)
+            .send()
+            .await
+            .map_err(|e| AgentError::ToolError(format!("rate lookup failed: {e}")))?;
+        if !response.status().is_success() {
+            return Err(AgentError::ToolError(format!("rate API returned {}", response.status())));
+        }
+        let json: Value = response
+            .json()
+            .await
+            .map_err(|e| AgentError::ToolError(format!("bad rate API response: {e}")))?;
+        json["rates"][to]
+            .as_f64()
+            .ok_or_else(|| AgentError::ToolError(format!("no rate for {from} → {to}")))
+    }
+}
+
+pub struct CurrencyTool {
+    source: Arc<dyn RateSource>,
+    ttl: Duration,
+    clock: Arc<dyn Clock>,
+    cache: Mutex<HashMap<(String, String), (f64, Instant)>>,
+}
+
+impl CurrencyTool {
+    /// Uses live rates from [`HttpRateSource`], cached for five minutes.
+    pub fn new() -> Self {
+        Self::with_source(Arc::new(HttpRateSource::new()))
+    }
+
+    pub fn with_source(source: Arc<dyn RateSource>) -> Self {
+        Self {
+            source,
+            ttl: Duration::from_secs(300),
+            clock: Arc::new(SystemClock),
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// How long a fetched rate is reused before asking the source again.
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// Replaces the clock used to expire cached rates, e.g. with a
+    /// `MockClock` in tests.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    async fn cached_rate(&self, from: &str, to: &str) -> Result<f64, AgentError> {
+        let key = (from.to_string(), to.to_string());
+        if let Some((rate, fetched_at)) = self.cache.lock().unwrap().get(&key) {
+            if self.clock.now().saturating_duration_since(*fetched_at) < self.ttl {
+                return Ok(*rate);
+            }
+        }
+        let rate = self.source.rate(from, to).await?;
+        self.cache.lock().unwrap().insert(key, (rate, self.clock.now()));
+        Ok(rate)
+    }
+}
+
+impl Default for CurrencyTool {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn currency_code(args: &Value, key: &str) -> Result<String, AgentError> {
+    let code = args[key]
+        .as_str()
+        .ok_or_else(|| AgentError::ToolError(format!("Missing '{key}'")))?
+        .trim()
+        .to_ascii_uppercase();
+    if code.len() != 3 || !code.chars().all(|c| c.is_ascii_alphabetic()) {
+        return Err(AgentError::ToolError(format!("'{key}' must be a 3-letter currency code")));
+    }
+    Ok(code)
+}
+
+#[async_trait]
+impl Tool for CurrencyTool {
+    fn name(&self) -> &'static str { "convert_currency" }
+    fn description(&self) -> &'static str {
+        "Converts an amount between currencies using live exchange rates"
+    }
+    fn parameters_schema(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "amount": { "type": "number" },
+                "from": { "type": "string", "description": "ISO 4217 code, e.g. \"USD\"" },
+                "to": { "type": "string", "description": "ISO 4217 code, e.g. \"EUR\"" }
+            },
+            "required": ["amount", "from", "to"],
+            "additionalProperties": false
+        })
+    }
+    async fn execute(&self, args: Value) -> Result<String, AgentError> {
+        let amount = args["amount"].as_f64().ok_or_else(|| AgentError::ToolError("Missing 'amount'".into()))?;
+        let from = currency_code(&args, "from")?;
+        let to = currency_code(&args, "to")?;
+
+        let rate = if from == to { 1.0 } else { self.cached_rate(&from, &to).await? };
+        Ok(format!("{:.2} {}", amount * rate, to))
+    }
+}
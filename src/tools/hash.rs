@@ -0,0 +1,47 @@
+/// Hash tool — digests and checksums for integrity checks.
+use async_trait::async_trait;
+use md5::Md5;
+use serde_json::{json, Value};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+
+use crate::{AgentError, Tool};
+
+/// Returns the lowercase hex digest of `data` (hashed as UTF-8) with
+/// `sha256`, `sha1` or `md5`. SHA-1 and MD5 are for checksums only, not for
+/// anything security-sensitive.
+pub struct HashTool;
+
+#[async_trait]
+impl Tool for HashTool {
+    fn name(&self) -> &'static str { "hash" }
+    fn description(&self) -> &'static str {
+        "Computes the hex digest of a string with sha256, sha1 or md5"
+    }
+    fn parameters_schema(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "algorithm": { "type": "string", "enum": ["sha256", "sha1", "md5"] },
+                "data": { "type": "string", "description": "Text to hash, as UTF-8" }
+            },
+            "required": ["algorithm", "data"],
+            "additionalProperties": false
+        })
+    }
+    async fn execute(&self, args: Value) -> Result<String, AgentError> {
+        let algorithm = args["algorithm"]
+            .as_str()
+            .ok_or_else(|| AgentError::ToolError("Missing 'algorithm'".into()))?;
+        let data = args["data"].as_str().ok_or_else(|| AgentError::ToolError("Missing 'data'".into()))?;
+
+        match algorithm.to_ascii_lowercase().as_str() {
+            "sha256" => Ok(format!("{:x}", Sha256::digest(data))),
+            "sha1" => Ok(format!("{:x}", Sha1::digest(data))),
+            "md5" => Ok(format!("{:x}", Md5::digest(data))),
+            other => Err(AgentError::ToolError(format!(
+                "unsupported algorithm '{other}'; use sha256, sha1 or md5"
+            ))),
+        }
+    }
+}
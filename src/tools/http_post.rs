@@ -0,0 +1,223 @@
+/// HTTP POST tool — lets the agent call webhooks and JSON APIs on an
+/// explicit list of hosts.
+use std::net::{IpAddr, SocketAddr};
+
+use async_trait::async_trait;
+use reqwest::header::{HeaderName, HeaderValue, CONTENT_TYPE};
+use reqwest::redirect::Policy;
+use reqwest::{Client, Url};
+use serde_json::{json, Value};
+
+use crate::{AgentError, Tool};
+
+/// Largest response body returned to the model, in bytes.
+const MAX_RESPONSE_BYTES: usize = 100_000;
+
+/// POSTs `body` to `url` and returns the response body. An object `body` is
+/// sent as JSON, a string as-is (`text/plain` unless a `Content-Type`
+/// header is given). A response body over 100 KB is cut off there, with a
+/// note saying so.
+///
+/// Only hosts on the allowlist can be reached. Hosts that resolve to
+/// loopback, private, link-local, multicast or other non-public addresses
+/// are refused as well unless `with_private_network(true)` is set, and
+/// redirects are never followed, so a permitted host can't bounce the
+/// request somewhere internal. The
+/// addresses that passed the check are the ones connected to: the host is
+/// not resolved a second time, so DNS rebinding can't swap them.
+pub struct HttpPostTool {
+    client: Client,
+    allowed_hosts: Vec<String>,
+    allow_private_network: bool,
+}
+
+impl HttpPostTool {
+    /// `allowed_hosts` are exact host names (`api.example.com`) or
+    /// subdomain wildcards (`*.example.com`), compared case-insensitively.
+    pub fn new<I, S>(allowed_hosts: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        Self {
+            // A default client would follow redirects past the host check
+            client: Client::builder()
+                .redirect(Policy::none())
+                .build()
+                .expect("failed to build the HTTP client (TLS backend unavailable?)"),
+            allowed_hosts: allowed_hosts.into_iter().map(|h| h.into().to_ascii_lowercase()).collect(),
+            allow_private_network: false,
+        }
+    }
+
+    /// Allows allowlisted hosts that resolve to loopback or private
+    /// addresses, e.g. a service on the same machine.
+    pub fn with_private_network(mut self, enabled: bool) -> Self {
+        self.allow_private_network = enabled;
+        self
+    }
+
+    fn host_allowed(&self, host: &str) -> bool {
+        self.allowed_hosts.iter().any(|allowed| match allowed.strip_prefix("*.") {
+            Some(domain) => host.strip_suffix(domain).is_some_and(|sub| sub.ends_with('.')),
+            None => host == allowed,
+        })
+    }
+
+    /// Parses `url` and checks it against the allowlist and, unless
+    /// allowed, the addresses its host resolves to. Returns those vetted
+    /// addresses, if any were checked.
+    async fn check_url(&self, url: &str) -> Result<(Url, Option<Vec<SocketAddr>>), AgentError> {
+        let url = Url::parse(url).map_err(|e| AgentError::ToolError(format!("invalid url '{url}': {e}")))?;
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err(AgentError::ToolError(format!("scheme '{}' is not allowed", url.scheme())));
+        }
+        let host = url.host_str().unwrap_or_default().to_ascii_lowercase();
+        if !self.host_allowed(&host) {
+            return Err(AgentError::ToolError(format!("host '{host}' is not on the allowlist")));
+        }
+
+        if self.allow_private_network {
+            return Ok((url, None));
+        }
+        let port = url.port_or_known_default().unwrap_or(80);
+        let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host.trim_matches(['[', ']']), port))
+            .await
+            .map_err(|e| AgentError::ToolError(format!("cannot resolve '{host}': {e}")))?
+            .collect();
+        if let Some(addr) = addrs.iter().find(|addr| is_private(addr.ip())) {
+            return Err(AgentError::ToolError(format!("host '{host}' resolves to private address {}", addr.ip())));
+        }
+        Ok((url, Some(addrs)))
+    }
+
+    /// The shared client, or for vetted addresses a client pinned to them.
+    fn client_for(&self, url: &Url, addrs: Option<&[SocketAddr]>) -> Result<Client, AgentError> {
+        let (Some(host), Some(addrs)) = (url.host_str(), addrs) else {
+            return Ok(self.client.clone());
+        };
+        Client::builder()
+            .redirect(Policy::none())
+            .resolve_to_addrs(host, addrs)
+            .build()
+            .map_err(|e| AgentError::ToolError(format!("cannot build HTTP client: {e}")))
+    }
+}
+
+fn is_private(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(v4) => {
+            let octets = v4.octets();
+            v4.is_loopback()
+                || v4.is_private()
+                || v4.is_link_local()
+                || v4.is_broadcast()
+                || v4.is_multicast()
+                // "This network" (0.0.0.0/8), carrier-grade NAT (100.64.0.0/10)
+                // and benchmarking (198.18.0.0/15)
+                || octets[0] == 0
+                || (octets[0] == 100 && (octets[1] & 0xc0) == 64)
+                || (octets[0] == 198 && (octets[1] & 0xfe) == 18)
+        }
+        IpAddr::V6(v6) => {
+            let segments = v6.segments();
+            v6.is_loopback()
+                || v6.is_unspecified()
+                || v6.is_multicast()
+                // Unique local (fc00::/7) and link-local (fe80::/10)
+                || (segments[0] & 0xfe00) == 0xfc00
+                || (segments[0] & 0xffc0) == 0xfe80
+                // NAT64 (64:ff9b::/96) reaches whatever IPv4 address it embeds
+                || segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0]
+                || v6.to_ipv4_mapped().is_some_and(|v4| is_private(IpAddr::V4(v4)))
+        }
+    }
+}
+
+#[async_trait]
+impl Tool for HttpPostTool {
+    fn name(&self) -> &'static str { "http_post" }
+    fn description(&self) -> &'static str {
+        "Sends an HTTP POST request with a JSON or text body to an allowed host and returns the response body"
+    }
+    fn parameters_schema(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "url": { "type": "string", "description": "Full http(s) URL" },
+                "body": {
+                    "type": ["object", "string"],
+                    "description": "An object is sent as JSON, a string as-is"
+                },
+                "headers": {
+                    "type": "object",
+                    "additionalProperties": { "type": "string" },
+                    "description": "Extra request headers"
+                }
+            },
+            "required": ["url", "body"],
+            "additionalProperties": false
+        })
+    }
+    async fn execute(&self, args: Value) -> Result<String, AgentError> {
+        let url = args["url"].as_str().ok_or_else(|| AgentError::ToolError("Missing 'url'".into()))?;
+        let (url, addrs) = self.check_url(url).await?;
+
+        let mut request = self.client_for(&url, addrs.as_deref())?.post(url);
+        let mut has_content_type = false;
+        if let Some(headers) = args.get("headers").filter(|h| !h.is_null()) {
+            let headers = headers
+                .as_object()
+                .ok_or_else(|| AgentError::ToolError("'headers' must be an object".into()))?;
+            for (name, value) in headers {
+                let value = value
+                    .as_str()
+                    .ok_or_else(|| AgentError::ToolError(format!("header '{name}' must be a string")))?;
+                let name = HeaderName::from_bytes(name.as_bytes())
+                    .map_err(|e| AgentError::ToolError(format!("bad header name '{name}': {e}")))?;
+                let value = HeaderValue::from_str(value)
+                    .map_err(|e| AgentError::ToolError(format!("bad value for header '{name}': {e}")))?;
+                has_content_type |= name == CONTENT_TYPE;
+                request = request.header(name, value);
+            }
+        }
+
+        request = match &args["body"] {
+            Value::String(text) if has_content_type => request.body(text.clone()),
+            Value::String(text) => request.header(CONTENT_TYPE, "text/plain; charset=utf-8").body(text.clone()),
+            Value::Object(_) if has_content_type => request.body(args["body"].to_string()),
+            body @ Value::Object(_) => request.json(body),
+            _ => return Err(AgentError::ToolError("'body' must be an object or a string".into())),
+        };
+
+        let mut response = request
+            .send()
+            .await
+            .map_err(|e| AgentError::ToolError(format!("POST failed: {e}")))?;
+        let status = response.status();
+
+        let mut body = vec![];
+        let mut truncated = false;
+        while let Some(chunk) = response
+            .chunk()
+            .await
+            .map_err(|e| AgentError::ToolError(format!("reading response failed: {e}")))?
+        {
+            body.extend_from_slice(&chunk);
+            if body.len() > MAX_RESPONSE_BYTES {
+                body.truncate(MAX_RESPONSE_BYTES);
+                truncated = true;
+                break;
+            }
+        }
+        let mut text = String::from_utf8_lossy(&body).into_owned();
+        if truncated {
+            text.push_str(&format!("\n[response truncated at {MAX_RESPONSE_BYTES} bytes]"));
+        }
+
+        if !status.is_success() {
+            return Err(AgentError::ToolError(format!("server returned {}: {text}", status.as_u16())));
+        }
+        Ok(text)
+    }
+}
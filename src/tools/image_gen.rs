@@ -0,0 +1,92 @@
+/// Image generation through the OpenAI images API.
+use async_trait::async_trait;
+use reqwest::Client;
+use serde_json::{json, Value};
+
+use crate::{AgentError, Tool};
+
+/// Calls `POST {base_url}/images/generations` and returns the image URL, or
+/// the base64 data if the API responded with `b64_json` instead.
+pub struct ImageGenTool {
+    client: Client,
+    api_key: String,
+    base_url: String,
+    model: String,
+}
+
+impl ImageGenTool {
+    /// Uses `dall-e-3` on `https://api.openai.com/v1`.
+    pub fn new(api_key: impl Into<String>) -> Self {
+        Self {
+            client: Client::new(),
+            api_key: api_key.into(),
+            base_url: "https://api.openai.com/v1".to_string(),
+            model: "dall-e-3".to_string(),
+        }
+    }
+
+    pub fn with_model(mut self, model: impl Into<String>) -> Self {
+        self.model = model.into();
+        self
+    }
+
+    /// Points the tool at another OpenAI-compatible endpoint.
+    pub fn with_base_url(mut self, base_url: impl Into<String>) -> Self {
+        self.base_url = base_url.into().trim_end_matches('/').to_string();
+        self
+    }
+}
+
+#[async_trait]
+impl Tool for ImageGenTool {
+    fn name(&self) -> &'static str { "generate_image" }
+    fn description(&self) -> &'static str {
+        "Generates an image from a text prompt and returns its URL"
+    }
+    fn parameters_schema(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "prompt": { "type": "string", "description": "Description of the image to generate" },
+                "size": { "type": "string", "description": "Image size, e.g. \"1024x1024\"" }
+            },
+            "required": ["prompt"],
+            "additionalProperties": false
+        })
+    }
+    async fn execute(&self, args: Value) -> Result<String, AgentError> {
+        let prompt = args["prompt"].as_str().ok_or_else(|| AgentError::ToolError("Missing 'prompt'".into()))?;
+        let size = args["size"].as_str().unwrap_or("1024x1024");
+
+        let response = self
+            .client
+            .post(format!("{}/images/generations", self.base_url))
+            .bearer_auth(&self.api_key)
+            .json(&json!({ "model": self.model, "prompt": prompt, "size": size, "n": 1 }))
+            .send()
+            .await
+            .map_err(|e| AgentError::ToolError(format!("image request failed: {e}")))?;
+
+        let status = response.status();
+        if !status.is_success() {
+            let text = response.text().await.unwrap_or_default();
+            // Prefer the API's own message; proxies and gateways answer with plain text
+            let message = serde_json::from_str::<Value>(&text)
+                .ok()
+                .and_then(|json| json["error"]["message"].as_str().map(str::to_string))
+                .unwrap_or(text);
+            return Err(AgentError::ToolError(format!("images API returned {}: {message}", status.as_u16())));
+        }
+        let json: Value = response
+            .json()
+            .await
+            .map_err(|e| AgentError::ToolError(format!("bad images API response: {e}")))?;
+
+        let image = &json["data"][0];
+        image["url"]
+            .as_str()
+            .or_else(|| image["b64_json"].as_str())
+            .map(str::to_string)
+            .ok_or_else(|| AgentError::ToolError("images API returned no image".into()))
+    }
+}
@@ -0,0 +1,119 @@
+/// JSON query tool — lets the model pull fields out of JSON returned by
+/// other tools instead of reading through the whole document.
+use async_trait::async_trait;
+use serde_json::{json, Value};
+
+use crate::{AgentError, Tool};
+
+/// Evaluates a JSONPath expression against a JSON document. Supported
+/// syntax: `$` (optional), `.field`, `['field']`, `[index]` (negative counts
+/// from the end) and the `*` wildcard. A path with a wildcard returns an
+/// array of every match; otherwise the single matched value is returned.
+pub struct JsonQueryTool;
+
+#[derive(Debug, PartialEq)]
+enum Segment {
+    Key(String),
+    Index(i64),
+    Wildcard,
+}
+
+fn path_error(path: &str, message: &str) -> AgentError {
+    AgentError::ToolError(format!("invalid path '{path}': {message}"))
+}
+
+fn parse_path(path: &str) -> Result<Vec<Segment>, AgentError> {
+    let mut rest = path.trim().strip_prefix('$').unwrap_or(path.trim());
+    let mut segments = vec![];
+    // Allow a bare leading field, e.g. "user.name"
+    let mut expect_separator = false;
+
+    while !rest.is_empty() {
+        if let Some(after) = rest.strip_prefix('[') {
+            let end = after.find(']').ok_or_else(|| path_error(path, "unclosed '['"))?;
+            let inner = after[..end].trim();
+            segments.push(if inner == "*" {
+                Segment::Wildcard
+            } else if let Some(key) = inner
+                .strip_prefix('\'')
+                .and_then(|k| k.strip_suffix('\''))
+                .or_else(|| inner.strip_prefix('"').and_then(|k| k.strip_suffix('"')))
+            {
+                Segment::Key(key.to_string())
+            } else {
+                let index = inner.parse().map_err(|_| path_error(path, &format!("bad index '{inner}'")))?;
+                Segment::Index(index)
+            });
+            rest = &after[end + 1..];
+        } else {
+            let field = match rest.strip_prefix('.') {
+                Some(field) => field,
+                None if !expect_separator => rest,
+                None => return Err(path_error(path, &format!("unexpected '{rest}'"))),
+            };
+            let end = field.find(['.', '[']).unwrap_or(field.len());
+            let name = &field[..end];
+            if name.is_empty() {
+                return Err(path_error(path, "empty field name"));
+            }
+            segments.push(if name == "*" { Segment::Wildcard } else { Segment::Key(name.to_string()) });
+            rest = &field[end..];
+        }
+        expect_separator = true;
+    }
+    Ok(segments)
+}
+
+fn select<'a>(values: Vec<&'a Value>, segment: &Segment) -> Vec<&'a Value> {
+    values
+        .into_iter()
+        .flat_map(|value| -> Vec<&'a Value> {
+            match (segment, value) {
+                (Segment::Key(key), Value::Object(map)) => map.get(key).into_iter().collect(),
+                (Segment::Index(index), Value::Array(items)) => {
+                    let position = if *index < 0 { items.len() as i64 + index } else { *index };
+                    usize::try_from(position).ok().and_then(|i| items.get(i)).into_iter().collect()
+                }
+                (Segment::Wildcard, Value::Array(items)) => items.iter().collect(),
+                (Segment::Wildcard, Value::Object(map)) => map.values().collect(),
+                _ => vec![],
+            }
+        })
+        .collect()
+}
+
+#[async_trait]
+impl Tool for JsonQueryTool {
+    fn name(&self) -> &'static str { "json_query" }
+    fn description(&self) -> &'static str {
+        "Extracts a value from a JSON document with a JSONPath expression, e.g. $.items[0].name or $.items[*].id"
+    }
+    fn parameters_schema(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "json": { "type": "string", "description": "The JSON document" },
+                "path": { "type": "string", "description": "JSONPath expression, e.g. \"$.user.address.city\"" }
+            },
+            "required": ["json", "path"],
+            "additionalProperties": false
+        })
+    }
+    async fn execute(&self, args: Value) -> Result<String, AgentError> {
+        let document = args["json"].as_str().ok_or_else(|| AgentError::ToolError("Missing 'json'".into()))?;
+        let path = args["path"].as_str().ok_or_else(|| AgentError::ToolError("Missing 'path'".into()))?;
+
+        let document: Value =
+            serde_json::from_str(document).map_err(|e| AgentError::ToolError(format!("invalid JSON: {e}")))?;
+        let segments = parse_path(path)?;
+
+        let matches = segments.iter().fold(vec![&document], select);
+        if segments.contains(&Segment::Wildcard) {
+            return Ok(json!(matches).to_string());
+        }
+        match matches.first() {
+            Some(value) => Ok(value.to_string()),
+            None => Err(AgentError::ToolError(format!("path '{path}' matched nothing"))),
+        }
+    }
+}
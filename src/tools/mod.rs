@@ -0,0 +1,17 @@
+//! Additional built-in tools that need their own dependencies or state.
+//! The simple arithmetic/joke tools still live in `lib.rs`.
+
+pub mod ask_user;
+pub mod config_read;
+pub mod currency;
+pub mod hash;
+pub mod http_post;
+pub mod image_gen;
+pub mod json_query;
+pub mod precise_math;
+pub mod random;
+pub mod regex_extract;
+#[cfg(feature = "sqlite")]
+pub mod sql_query;
+pub mod translate;
+pub mod typed;
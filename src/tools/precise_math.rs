@@ -0,0 +1,57 @@
+/// Exact decimal arithmetic for when `f64` rounding or `i64` overflow matters,
+/// e.g. money. Numbers travel as strings so no precision is lost in JSON.
+use std::str::FromStr;
+
+use async_trait::async_trait;
+use rust_decimal::Decimal;
+use serde_json::{json, Value};
+
+use crate::{AgentError, Tool};
+
+/// Adds, subtracts, multiplies or divides two decimals (up to 28 significant
+/// digits) and returns the exact result.
+pub struct PreciseMathTool;
+
+fn parse_operand(args: &Value, key: &str) -> Result<Decimal, AgentError> {
+    let raw = args[key].as_str().ok_or_else(|| AgentError::ToolError(format!("Missing '{key}'")))?;
+    Decimal::from_str(raw.trim())
+        .or_else(|_| Decimal::from_scientific(raw.trim()))
+        .map_err(|_| AgentError::ToolError(format!("'{key}' is not a decimal number: {raw}")))
+}
+
+#[async_trait]
+impl Tool for PreciseMathTool {
+    fn name(&self) -> &'static str { "precise_math" }
+    fn description(&self) -> &'static str {
+        "Exact decimal arithmetic on numbers given as strings; use for money and other values where rounding matters"
+    }
+    fn parameters_schema(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "a": { "type": "string", "description": "First operand, e.g. \"19.99\"" },
+                "b": { "type": "string", "description": "Second operand, e.g. \"0.075\"" },
+                "op": { "type": "string", "enum": ["+", "-", "*", "/"] }
+            },
+            "required": ["a", "b", "op"],
+            "additionalProperties": false
+        })
+    }
+    async fn execute(&self, args: Value) -> Result<String, AgentError> {
+        let a = parse_operand(&args, "a")?;
+        let b = parse_operand(&args, "b")?;
+        let op = args["op"].as_str().ok_or_else(|| AgentError::ToolError("Missing 'op'".into()))?;
+
+        let result = match op {
+            "+" => a.checked_add(b),
+            "-" => a.checked_sub(b),
+            "*" => a.checked_mul(b),
+            "/" if b.is_zero() => return Err(AgentError::ToolError("division by zero".into())),
+            "/" => a.checked_div(b),
+            other => return Err(AgentError::ToolError(format!("unknown op '{other}'; expected +, -, * or /"))),
+        };
+        result
+            .map(|r| r.to_string())
+            .ok_or_else(|| AgentError::ToolError(format!("result of {a} {op} {b} is out of range")))
+    }
+}
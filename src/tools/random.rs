@@ -0,0 +1,60 @@
+/// Random number tool — dice rolls and sampling, reproducible when seeded.
+use std::sync::Mutex;
+
+use async_trait::async_trait;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde_json::{json, Value};
+
+use crate::{AgentError, Tool};
+
+/// Returns a uniformly random integer in `[min, max]`, both inclusive.
+pub struct RandomTool {
+    rng: Mutex<StdRng>,
+}
+
+impl RandomTool {
+    /// Seeded from the operating system; results differ on every run.
+    pub fn new() -> Self {
+        Self { rng: Mutex::new(StdRng::from_entropy()) }
+    }
+
+    /// Same seed, same sequence of results — for tests and replays.
+    pub fn with_seed(seed: u64) -> Self {
+        Self { rng: Mutex::new(StdRng::seed_from_u64(seed)) }
+    }
+}
+
+impl Default for RandomTool {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait]
+impl Tool for RandomTool {
+    fn name(&self) -> &'static str { "random_integer" }
+    fn description(&self) -> &'static str {
+        "Returns a random integer between min and max, inclusive (e.g. a dice roll is min 1, max 6)"
+    }
+    fn parameters_schema(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "min": { "type": "integer", "description": "Smallest possible value" },
+                "max": { "type": "integer", "description": "Largest possible value" }
+            },
+            "required": ["min", "max"],
+            "additionalProperties": false
+        })
+    }
+    async fn execute(&self, args: Value) -> Result<String, AgentError> {
+        let min = args["min"].as_i64().ok_or_else(|| AgentError::ToolError("Missing or non-integer 'min'".into()))?;
+        let max = args["max"].as_i64().ok_or_else(|| AgentError::ToolError("Missing or non-integer 'max'".into()))?;
+        if min > max {
+            return Err(AgentError::ToolError(format!("min ({min}) must not be greater than max ({max})")));
+        }
+        let value = self.rng.lock().unwrap().gen_range(min..=max);
+        Ok(value.to_string())
+    }
+}
@@ -0,0 +1,70 @@
+/// Regex extraction tool — pulls structured pieces out of free text.
+use async_trait::async_trait;
+use regex::RegexBuilder;
+use serde_json::{json, Value};
+
+use crate::{AgentError, Tool};
+
+/// Largest `text` input accepted, in bytes.
+const MAX_TEXT_BYTES: usize = 100_000;
+/// Upper bound on the compiled program size, so pathological patterns
+/// (e.g. huge counted repetitions) are rejected instead of eating memory.
+const MAX_PATTERN_SIZE: usize = 1 << 20;
+
+/// Returns every match of `pattern` in `text`, one per line. If the pattern
+/// has capture groups, each line holds that match's groups separated by tabs.
+pub struct RegexExtractTool;
+
+#[async_trait]
+impl Tool for RegexExtractTool {
+    fn name(&self) -> &'static str { "regex_extract" }
+    fn description(&self) -> &'static str {
+        "Extracts all matches of a regular expression from text; with capture groups, returns the groups of each match"
+    }
+    fn parameters_schema(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "pattern": { "type": "string", "description": "Regular expression (Rust regex syntax)" },
+                "text": { "type": "string", "description": "Text to search" }
+            },
+            "required": ["pattern", "text"],
+            "additionalProperties": false
+        })
+    }
+    async fn execute(&self, args: Value) -> Result<String, AgentError> {
+        let pattern = args["pattern"].as_str().ok_or_else(|| AgentError::ToolError("Missing 'pattern'".into()))?;
+        let text = args["text"].as_str().ok_or_else(|| AgentError::ToolError("Missing 'text'".into()))?;
+
+        if text.len() > MAX_TEXT_BYTES {
+            return Err(AgentError::ToolError(format!(
+                "text is {} bytes; the limit is {MAX_TEXT_BYTES}",
+                text.len()
+            )));
+        }
+
+        let re = RegexBuilder::new(pattern)
+            .size_limit(MAX_PATTERN_SIZE)
+            .build()
+            .map_err(|e| AgentError::ToolError(format!("invalid pattern: {e}")))?;
+
+        let lines: Vec<String> = if re.captures_len() > 1 {
+            re.captures_iter(text)
+                .map(|caps| {
+                    caps.iter()
+                        .skip(1)
+                        .map(|group| group.map_or("", |m| m.as_str()))
+                        .collect::<Vec<_>>()
+                        .join("\t")
+                })
+                .collect()
+        } else {
+            re.find_iter(text).map(|m| m.as_str().to_string()).collect()
+        };
+
+        if lines.is_empty() {
+            return Ok("No matches".to_string());
+        }
+        Ok(lines.join("\n"))
+    }
+}
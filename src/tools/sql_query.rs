@@ -0,0 +1,120 @@
+/// SQLite query tool — lets an agent inspect a local database without being
+/// able to change it. Only available with the `sqlite` feature.
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use rusqlite::types::ValueRef;
+use rusqlite::{Connection, OpenFlags};
+use serde_json::{json, Map, Value};
+
+use crate::{AgentError, Tool};
+
+const DEFAULT_MAX_ROWS: usize = 100;
+
+/// Runs a single read-only `SELECT` (or `WITH … SELECT`) and returns
+/// `{ "rows": [...], "truncated": bool }`, each row an object keyed by
+/// column name. Anything that would write is rejected before it runs.
+pub struct SqlQueryTool {
+    conn: Arc<Mutex<Connection>>,
+    max_rows: usize,
+}
+
+impl SqlQueryTool {
+    /// Opens the database at `path` (a file path or a `file:` URI) read-only.
+    pub fn open(path: &str) -> Result<Self, AgentError> {
+        let flags = OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_URI | OpenFlags::SQLITE_OPEN_NO_MUTEX;
+        let conn = Connection::open_with_flags(path, flags)
+            .map_err(|e| AgentError::ToolError(format!("cannot open database '{path}': {e}")))?;
+        Self::from_connection(conn)
+    }
+
+    /// Wraps an existing connection, e.g. an in-memory database. The
+    /// connection is switched to `query_only` so it can't be written through.
+    pub fn from_connection(conn: Connection) -> Result<Self, AgentError> {
+        conn.pragma_update(None, "query_only", true)
+            .map_err(|e| AgentError::ToolError(format!("cannot make database read-only: {e}")))?;
+        Ok(Self { conn: Arc::new(Mutex::new(conn)), max_rows: DEFAULT_MAX_ROWS })
+    }
+
+    /// Maximum rows returned per query (default 100); extra rows are dropped
+    /// and `truncated` is set.
+    pub fn with_max_rows(mut self, max_rows: usize) -> Self {
+        self.max_rows = max_rows;
+        self
+    }
+}
+
+/// Runs `sql` and serializes at most `max_rows` of its rows. Blocking.
+fn run_query(conn: &Connection, sql: &str, max_rows: usize) -> Result<String, AgentError> {
+    let mut stmt = conn.prepare(sql).map_err(|e| AgentError::ToolError(format!("invalid query: {e}")))?;
+    // Catches writes hidden behind a CTE, e.g. `WITH … DELETE`
+    if !stmt.readonly() {
+        return Err(AgentError::ToolError("only read-only queries are allowed".into()));
+    }
+
+    let columns: Vec<String> = stmt.column_names().into_iter().map(str::to_string).collect();
+    let mut rows = stmt.query([]).map_err(|e| AgentError::ToolError(format!("query failed: {e}")))?;
+
+    let mut out = vec![];
+    let mut truncated = false;
+    while let Some(row) = rows.next().map_err(|e| AgentError::ToolError(format!("query failed: {e}")))? {
+        if out.len() == max_rows {
+            truncated = true;
+            break;
+        }
+        let mut obj = Map::new();
+        for (i, name) in columns.iter().enumerate() {
+            let value = row.get_ref(i).map_err(|e| AgentError::ToolError(e.to_string()))?;
+            obj.insert(name.clone(), column_json(value));
+        }
+        out.push(Value::Object(obj));
+    }
+
+    Ok(json!({ "rows": out, "truncated": truncated }).to_string())
+}
+
+fn column_json(value: ValueRef<'_>) -> Value {
+    match value {
+        ValueRef::Null => Value::Null,
+        ValueRef::Integer(i) => json!(i),
+        ValueRef::Real(f) => json!(f),
+        ValueRef::Text(t) => json!(String::from_utf8_lossy(t)),
+        ValueRef::Blob(b) => json!(format!("<blob, {} bytes>", b.len())),
+    }
+}
+
+#[async_trait]
+impl Tool for SqlQueryTool {
+    fn name(&self) -> &'static str { "sql_query" }
+    fn description(&self) -> &'static str {
+        "Runs a read-only SQL SELECT query against a SQLite database and returns the rows as JSON"
+    }
+    fn parameters_schema(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "sql": { "type": "string", "description": "A single SELECT statement" }
+            },
+            "required": ["sql"],
+            "additionalProperties": false
+        })
+    }
+    async fn execute(&self, args: Value) -> Result<String, AgentError> {
+        let sql = args["sql"].as_str().ok_or_else(|| AgentError::ToolError("Missing 'sql'".into()))?;
+
+        let keyword = sql.split_whitespace().next().unwrap_or("").to_ascii_uppercase();
+        if keyword != "SELECT" && keyword != "WITH" {
+            return Err(AgentError::ToolError(format!(
+                "only SELECT queries are allowed, got '{keyword}'"
+            )));
+        }
+
+        // rusqlite is synchronous; keep the query off the async executor
+        let conn = self.conn.clone();
+        let sql = sql.to_string();
+        let max_rows = self.max_rows;
+        tokio::task::spawn_blocking(move || run_query(&conn.lock().unwrap(), &sql, max_rows))
+            .await
+            .map_err(|e| AgentError::ToolError(format!("query task failed: {e}")))?
+    }
+}
@@ -0,0 +1,61 @@
+/// Translation tool that delegates to an LLM provider — an example of a tool
+/// that itself makes a model call.
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use serde_json::{json, Value};
+
+use crate::{AgentError, LlmProvider, Message, Tool};
+
+pub struct TranslateTool {
+    provider: Arc<dyn LlmProvider>,
+    model: String,
+}
+
+impl TranslateTool {
+    /// `model` may be empty to use the provider's default model.
+    pub fn new(provider: Arc<dyn LlmProvider>, model: impl Into<String>) -> Self {
+        Self { provider, model: model.into() }
+    }
+}
+
+#[async_trait]
+impl Tool for TranslateTool {
+    fn name(&self) -> &'static str { "translate" }
+    fn description(&self) -> &'static str {
+        "Translates text into the target language and returns only the translation"
+    }
+    fn parameters_schema(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "text": { "type": "string", "description": "Text to translate" },
+                "target_lang": { "type": "string", "description": "Target language, e.g. \"French\" or \"de\"" }
+            },
+            "required": ["text", "target_lang"],
+            "additionalProperties": false
+        })
+    }
+    async fn execute(&self, args: Value) -> Result<String, AgentError> {
+        let text = args["text"].as_str().ok_or_else(|| AgentError::ToolError("Missing 'text'".into()))?;
+        let target = args["target_lang"]
+            .as_str()
+            .ok_or_else(|| AgentError::ToolError("Missing 'target_lang'".into()))?;
+
+        let prompt = format!(
+            "Translate the following text into {target}. \
+             Reply with the translation only, no explanations or quotes.\n\n{text}"
+        );
+        let completion = self
+            .provider
+            .complete(&[Message::user(prompt)], &[], &self.model)
+            .await
+            .map_err(|e| AgentError::ToolError(format!("translation failed: {e}")))?;
+
+        completion
+            .content
+            .map(|c| c.trim().to_string())
+            .filter(|c| !c.is_empty())
+            .ok_or_else(|| AgentError::ToolError("translation returned no text".into()))
+    }
+}
@@ -0,0 +1,60 @@
+/// Typed tools: derive `parameters_schema` from an argument struct instead
+/// of writing the JSON schema by hand.
+use std::future::Future;
+use std::marker::PhantomData;
+
+use async_trait::async_trait;
+use schemars::JsonSchema;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+
+use crate::{AgentError, Tool};
+
+/// JSON schema for `T`, in the shape `Tool::parameters_schema` expects.
+/// The `$schema` and `title` keys schemars adds are dropped.
+pub fn schema_for<T: JsonSchema>() -> Value {
+    let mut schema = schemars::schema_for!(T).to_value();
+    if let Some(obj) = schema.as_object_mut() {
+        obj.remove("$schema");
+        obj.remove("title");
+    }
+    schema
+}
+
+/// A tool built from an argument type `A` and an async handler `F`.
+///
+/// `args` are deserialized into `A` before `F` runs; a mismatch is reported
+/// as `AgentError::ToolError` so the model sees what was wrong.
+pub struct TypedTool<A, F> {
+    name: &'static str,
+    description: &'static str,
+    handler: F,
+    _args: PhantomData<fn(A)>,
+}
+
+impl<A, F, Fut> TypedTool<A, F>
+where
+    F: Fn(A) -> Fut,
+    Fut: Future<Output = Result<String, AgentError>>,
+{
+    pub fn new(name: &'static str, description: &'static str, handler: F) -> Self {
+        Self { name, description, handler, _args: PhantomData }
+    }
+}
+
+#[async_trait]
+impl<A, F, Fut> Tool for TypedTool<A, F>
+where
+    A: JsonSchema + DeserializeOwned + Send + 'static,
+    F: Fn(A) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<String, AgentError>> + Send,
+{
+    fn name(&self) -> &'static str { self.name }
+    fn description(&self) -> &'static str { self.description }
+    fn parameters_schema(&self) -> Value { schema_for::<A>() }
+    async fn execute(&self, args: Value) -> Result<String, AgentError> {
+        let args: A = serde_json::from_value(args)
+            .map_err(|e| AgentError::ToolError(format!("invalid arguments for '{}': {e}", self.name)))?;
+        (self.handler)(args).await
+    }
+}
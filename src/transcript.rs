@@ -0,0 +1,65 @@
+/// Append-only JSONL transcript of an agent's conversation.
+///
+/// Each message added to `Agent::history` is serialized as one JSON line.
+/// Writes happen on a dedicated background thread behind a buffered writer,
+/// so logging never blocks the async agent loop.
+use std::fs::OpenOptions;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::sync::mpsc::{self, Sender};
+use std::thread::JoinHandle;
+
+use crate::{AgentError, Message};
+
+/// Dropping the log closes the channel without waiting: the writer thread
+/// drains what is queued and exits on its own. Use `close` to wait for it.
+pub(crate) struct TranscriptLog {
+    tx: Sender<String>,
+    writer: JoinHandle<io::Result<()>>,
+}
+
+impl TranscriptLog {
+    /// Opens (or creates) `path` in append mode and starts the writer thread.
+    pub(crate) fn open(path: impl AsRef<Path>) -> Result<Self, AgentError> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let (tx, rx) = mpsc::channel::<String>();
+
+        let writer = std::thread::spawn(move || {
+            let mut out = BufWriter::new(file);
+            while let Ok(line) = rx.recv() {
+                writeln!(out, "{line}")?;
+                // Drain whatever is already queued, then flush once the
+                // queue is empty so the file stays close to real time.
+                while let Ok(line) = rx.try_recv() {
+                    writeln!(out, "{line}")?;
+                }
+                out.flush()?;
+            }
+            out.flush()
+        });
+
+        Ok(Self { tx, writer })
+    }
+
+    /// Queues `message` for writing. Never blocks on disk I/O.
+    pub(crate) fn append(&self, message: &Message) {
+        match serde_json::to_string(message) {
+            Ok(line) => {
+                let _ = self.tx.send(line);
+            }
+            Err(e) => eprintln!("[transcript] failed to serialize message: {e}"),
+        }
+    }
+
+    /// Closes the channel and waits for the writer thread to flush, off the
+    /// async runtime. Returns the first write error, if any.
+    pub(crate) async fn close(self) -> Result<(), AgentError> {
+        let Self { tx, writer } = self;
+        drop(tx);
+        tokio::task::spawn_blocking(move || writer.join())
+            .await
+            .map_err(|e| AgentError::Io(io::Error::other(e)))?
+            .map_err(|_| AgentError::Io(io::Error::other("transcript writer thread panicked")))??;
+        Ok(())
+    }
+}
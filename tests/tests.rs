@@ -1,3 +1,4 @@
+#![allow(clippy::empty_line_after_doc_comments)]
 /// tests/tests.rs — Test suite for mini-agent
 ///
 /// Run with: cargo test
@@ -10,6 +11,109 @@
 ///   - Provider helpers (build_openai_messages, build_openai_tools, parse_openai_completion)
 ///   - Agent error handling and loop logic via a mock provider
 
+// ─────────────────────────────────────────────────────────────────────────────
+// Minimal HTTP mock server shared by provider/tool tests
+// ─────────────────────────────────────────────────────────────────────────────
+
+#[cfg(test)]
+mod mock_http {
+    use std::sync::{Arc, Mutex};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// A raw HTTP request as received by the mock server.
+    #[derive(Debug, Clone)]
+    pub struct Captured {
+        pub request_line: String,
+        pub headers: Vec<(String, String)>,
+        pub body: String,
+    }
+
+    impl Captured {
+        pub fn header(&self, name: &str) -> Option<&str> {
+            self.headers
+                .iter()
+                .find(|(k, _)| k.eq_ignore_ascii_case(name))
+                .map(|(_, v)| v.as_str())
+        }
+    }
+
+    /// Serves `responses` (status, body) in order, one per connection; the
+    /// last response repeats. Returns the base URL and the captured requests.
+    pub async fn serve(responses: Vec<(u16, String)>) -> (String, Arc<Mutex<Vec<Captured>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let captured = Arc::new(Mutex::new(vec![]));
+        let log = captured.clone();
+
+        tokio::spawn(async move {
+            let mut served = 0;
+            loop {
+                let Ok((mut socket, _)) = listener.accept().await else { return };
+                let Some(request) = read_request(&mut socket).await else { continue };
+                log.lock().unwrap().push(request);
+
+                let (status, body) = responses[served.min(responses.len() - 1)].clone();
+                served += 1;
+                let response = format!(
+                    "HTTP/1.1 {status} Mock\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                    body.len()
+                );
+                let _ = socket.write_all(response.as_bytes()).await;
+                let _ = socket.shutdown().await;
+            }
+        });
+
+        (format!("http://{addr}"), captured)
+    }
+
+    async fn read_request(socket: &mut tokio::net::TcpStream) -> Option<Captured> {
+        let mut buf = vec![];
+        let mut chunk = [0u8; 4096];
+        let header_end = loop {
+            let n = socket.read(&mut chunk).await.ok()?;
+            if n == 0 {
+                return None;
+            }
+            buf.extend_from_slice(&chunk[..n]);
+            if let Some(pos) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+                break pos + 4;
+            }
+        };
+
+        let head = String::from_utf8_lossy(&buf[..header_end]).to_string();
+        let mut lines = head.split("\r\n");
+        let request_line = lines.next()?.to_string();
+        let headers: Vec<(String, String)> = lines
+            .filter_map(|l| l.split_once(": "))
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        let content_length = headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case("content-length"))
+            .and_then(|(_, v)| v.parse::<usize>().ok())
+            .unwrap_or(0);
+
+        while buf.len() < header_end + content_length {
+            let n = socket.read(&mut chunk).await.ok()?;
+            if n == 0 {
+                break;
+            }
+            buf.extend_from_slice(&chunk[..n]);
+        }
+        let body = String::from_utf8_lossy(&buf[header_end..]).to_string();
+        Some(Captured { request_line, headers, body })
+    }
+
+    /// A minimal successful OpenAI-style chat completion body.
+    pub fn chat_completion(content: &str) -> String {
+        serde_json::json!({
+            "choices": [{ "message": { "role": "assistant", "content": content } }]
+        })
+        .to_string()
+    }
+}
+
 #[cfg(test)]
 mod tool_tests {
     use mini_agent::{AddNumbersTool, AgentError, JokeTool, MultiplyNumbersTool, Tool};
@@ -187,6 +291,7 @@ mod message_tests {
             content: "42".to_string(),
             tool_call_id: Some("call_1".to_string()),
             tool_calls: None,
+            ..Default::default()
         };
         assert_eq!(msg.role, Role::Tool);
         assert_eq!(msg.content, "42");
@@ -199,6 +304,34 @@ mod message_tests {
         assert_eq!(Role::Assistant.to_string(), "assistant");
         assert_eq!(Role::Tool.to_string(), "tool");
     }
+
+    #[test]
+    fn constructors_set_created_at() {
+        assert!(Message::user("a").created_at.is_some());
+        assert!(Message::assistant("b").created_at.is_some());
+        let tool = Message::tool("call_1", "42");
+        assert_eq!(tool.role, Role::Tool);
+        assert_eq!(tool.tool_call_id.as_deref(), Some("call_1"));
+        assert!(tool.created_at.is_some());
+    }
+
+    #[test]
+    fn metadata_roundtrips_through_serde() {
+        let msg = Message::user("hi").with_metadata(json!({ "tag": "x" }));
+        let text = serde_json::to_string(&msg).unwrap();
+        let back: Message = serde_json::from_str(&text).unwrap();
+        assert_eq!(back.metadata, Some(json!({ "tag": "x" })));
+        assert_eq!(back.created_at, msg.created_at);
+    }
+
+
+    #[test]
+    fn system_and_developer_roles_serialize_lowercase() {
+        assert_eq!(serde_json::to_value(Role::System).unwrap(), json!("system"));
+        assert_eq!(serde_json::to_value(Role::Developer).unwrap(), json!("developer"));
+        assert_eq!(Message::developer("be brief").role, Role::Developer);
+        assert_eq!(Role::Developer.to_string(), "developer");
+    }
 }
 
 // ─────────────────────────────────────────────────────────────────────────────
@@ -231,6 +364,7 @@ mod agent_tests {
                 content: Some(self.response.clone()),
                 tool_calls: vec![],
                 raw_tool_calls: None,
+                ..Default::default()
             })
         }
     }
@@ -271,6 +405,7 @@ mod agent_tests {
                 content: None,
                 tool_calls: vec![],
                 raw_tool_calls: None,
+                ..Default::default()
             })
         }
     }
@@ -307,6 +442,7 @@ mod agent_tests {
                         "type": "function",
                         "function": { "name": "add_numbers", "arguments": "{\"a\":10,\"b\":20}" }
                     }])),
+                    ..Default::default()
                 })
             } else {
                 // Second call: return final answer
@@ -314,6 +450,7 @@ mod agent_tests {
                     content: Some("The answer is 30".to_string()),
                     tool_calls: vec![],
                     raw_tool_calls: None,
+                    ..Default::default()
                 })
             }
         }
@@ -382,8 +519,12 @@ mod agent_tests {
         let result = agent.run("anything").await;
         assert!(result.is_err());
         match result.unwrap_err() {
-            AgentError::ProviderError(msg) => assert!(msg.contains("Simulated provider failure")),
-            _ => panic!("Expected ProviderError"),
+            AgentError::StepFailed { provider, step, source } => {
+                assert_eq!(provider, "ErrorMock");
+                assert_eq!(step, 0);
+                assert!(matches!(*source, AgentError::ProviderError(msg) if msg.contains("Simulated provider failure")));
+            }
+            other => panic!("Expected StepFailed, got {other:?}"),
         }
     }
 
@@ -398,6 +539,15 @@ mod agent_tests {
         }
     }
 
+    #[tokio::test]
+    async fn empty_response_does_not_push_assistant_message() {
+        let mut agent = Agent::new(Box::new(EmptyProvider), "test-model");
+        assert!(agent.run("anything").await.is_err());
+
+        assert_eq!(agent.history.len(), 1);
+        assert_eq!(agent.history[0].role, mini_agent::Role::User);
+    }
+
     #[tokio::test]
     async fn agent_executes_tool_and_returns_answer() {
         let call_count = std::sync::Arc::new(std::sync::Mutex::new(0));
@@ -444,239 +594,4507 @@ mod agent_tests {
             _ => panic!("Expected ToolNotFound"),
         }
     }
-}
 
-// ─────────────────────────────────────────────────────────────────────────────
-// Provider helper tests (parse_openai_completion, build_openai_messages, etc.)
-// ─────────────────────────────────────────────────────────────────────────────
+    #[tokio::test]
+    async fn transcript_log_writes_one_line_per_message() {
+        let path = std::env::temp_dir()
+            .join(format!("mini-agent-transcript-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
 
-#[cfg(test)]
-mod provider_helper_tests {
-    use mini_agent::providers::{build_openai_messages, build_openai_tools, parse_openai_completion};
-    use mini_agent::{AgentError, Message, Role, Tool};
-    use async_trait::async_trait;
-    use serde_json::{json, Value};
+        let provider = ToolCallingProvider {
+            call_count: std::sync::Arc::new(std::sync::Mutex::new(0)),
+        };
+        let mut agent = Agent::new(Box::new(provider), "test-model")
+            .with_transcript_log(&path)
+            .unwrap();
+        agent.add_tool(AddNumbersTool);
+        agent.run("Add 10 and 20").await.unwrap();
+        let history = agent.history.clone();
+        agent.close_transcript().await.unwrap();
 
-    struct DummyTool;
+        let text = std::fs::read_to_string(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        let lines: Vec<Message> = text
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
 
-    #[async_trait]
-    impl Tool for DummyTool {
-        fn name(&self) -> &'static str { "dummy_tool" }
-        fn description(&self) -> &'static str { "A dummy tool for testing" }
-        fn parameters_schema(&self) -> Value {
-            json!({ "type": "object", "properties": { "x": { "type": "integer" } }, "required": ["x"] })
-        }
-        async fn execute(&self, _args: Value) -> Result<String, AgentError> {
-            Ok("dummy_result".to_string())
+        // user, assistant (tool call), tool, assistant (final)
+        assert_eq!(lines.len(), history.len());
+        assert_eq!(lines.len(), 4);
+        for (logged, kept) in lines.iter().zip(&history) {
+            assert_eq!(logged.role, kept.role);
+            assert_eq!(logged.content, kept.content);
+            assert_eq!(logged.tool_call_id, kept.tool_call_id);
         }
+        assert_eq!(lines[2].tool_call_id.as_deref(), Some("call_abc"));
+        assert_eq!(lines[2].content, "30");
     }
 
-    // ── build_openai_messages ─────────────────────────────────────────────
+    // ── Mock provider that describes a tool call as text ──────────────────
 
-    #[test]
-    fn build_messages_user_only() {
-        let messages = vec![Message::user("Hello")];
-        let result = build_openai_messages(&messages);
-        assert_eq!(result.len(), 1);
-        assert_eq!(result[0]["role"], "user");
-        assert_eq!(result[0]["content"], "Hello");
+    struct TextToolProvider {
+        call_count: std::sync::Arc<std::sync::Mutex<usize>>,
     }
 
-    #[test]
-    fn build_messages_includes_tool_call_id() {
-        let messages = vec![Message {
-            role: Role::Tool,
-            content: "result_value".to_string(),
-            tool_call_id: Some("call_xyz".to_string()),
-            tool_calls: None,
-        }];
-        let result = build_openai_messages(&messages);
-        assert_eq!(result[0]["tool_call_id"], "call_xyz");
-    }
+    #[async_trait]
+    impl LlmProvider for TextToolProvider {
+        fn provider_name(&self) -> &str { "TextToolMock" }
 
-    #[test]
-    fn build_messages_omits_null_tool_calls() {
-        let messages = vec![Message::user("hi")];
-        let result = build_openai_messages(&messages);
-        assert!(result[0].get("tool_calls").is_none());
+        async fn complete(
+            &self,
+            messages: &[Message],
+            _tools: &[&dyn Tool],
+            _model: &str,
+        ) -> Result<Completion, AgentError> {
+            let mut count = self.call_count.lock().unwrap();
+            *count += 1;
+            if *count == 1 {
+                Ok(Completion {
+                    content: Some(
+                        "Please run this tool:\n```json\n{\"name\": \"add_numbers\", \"arguments\": {\"a\": 2, \"b\": 3}}\n```".into(),
+                    ),
+                    tool_calls: vec![],
+                    raw_tool_calls: None,
+                    ..Default::default()
+                })
+            } else {
+                let result = messages.last().unwrap().content.clone();
+                Ok(Completion {
+                    content: Some(format!("The sum is {result}")),
+                    tool_calls: vec![],
+                    raw_tool_calls: None,
+                    ..Default::default()
+                })
+            }
+        }
     }
 
-    #[test]
-    fn build_messages_preserves_order() {
-        let messages = vec![
-            Message::user("first"),
-            Message::assistant("second"),
-            Message::user("third"),
-        ];
-        let result = build_openai_messages(&messages);
-        assert_eq!(result[0]["content"], "first");
-        assert_eq!(result[1]["content"], "second");
-        assert_eq!(result[2]["content"], "third");
-    }
+    #[tokio::test]
+    async fn text_tool_parsing_extracts_and_executes_json_block() {
+        let provider = TextToolProvider {
+            call_count: std::sync::Arc::new(std::sync::Mutex::new(0)),
+        };
+        let mut agent = Agent::new(Box::new(provider), "test-model").with_text_tool_parsing(true);
+        agent.add_tool(AddNumbersTool);
 
-    // ── build_openai_tools ────────────────────────────────────────────────
+        let result = agent.run("Add 2 and 3").await.unwrap();
+        assert_eq!(result, "The sum is 5");
 
-    #[test]
-    fn build_tools_correct_shape() {
-        let tool = DummyTool;
-        let tools: Vec<&dyn Tool> = vec![&tool];
-        let result = build_openai_tools(&tools);
-        assert_eq!(result.len(), 1);
-        assert_eq!(result[0]["type"], "function");
-        assert_eq!(result[0]["function"]["name"], "dummy_tool");
-        assert_eq!(result[0]["function"]["description"], "A dummy tool for testing");
-        assert!(result[0]["function"]["parameters"].is_object());
+        let tool_msg = agent.history.iter().find(|m| m.role == mini_agent::Role::Tool).unwrap();
+        assert_eq!(tool_msg.content, "5");
+        let assistant_calls = agent.history[1].tool_calls.as_ref().unwrap();
+        assert_eq!(assistant_calls[0]["function"]["name"], "add_numbers");
+        assert_eq!(assistant_calls[0]["id"], json!(tool_msg.tool_call_id.clone().unwrap()));
     }
 
-    #[test]
-    fn build_tools_empty() {
-        let tools: Vec<&dyn Tool> = vec![];
-        let result = build_openai_tools(&tools);
-        assert!(result.is_empty());
+    #[tokio::test]
+    async fn text_tool_parsing_disabled_returns_text() {
+        let provider = TextToolProvider {
+            call_count: std::sync::Arc::new(std::sync::Mutex::new(0)),
+        };
+        let mut agent = Agent::new(Box::new(provider), "test-model");
+        agent.add_tool(AddNumbersTool);
+
+        let result = agent.run("Add 2 and 3").await.unwrap();
+        assert!(result.contains("```json"));
     }
 
-    // ── parse_openai_completion ───────────────────────────────────────────
+    #[tokio::test]
+    async fn timestamps_are_set_but_not_sent_to_provider() {
+        let provider = ToolCallingProvider {
+            call_count: std::sync::Arc::new(std::sync::Mutex::new(0)),
+        };
+        let mut agent = Agent::new(Box::new(provider), "test-model");
+        agent.add_tool(AddNumbersTool);
+        agent.run("Add 10 and 20").await.unwrap();
 
-    #[test]
-    fn parse_completion_text_only() {
-        let json = json!({
-            "choices": [{
-                "message": {
-                    "role": "assistant",
-                    "content": "Hello world"
-                }
-            }]
-        });
-        let completion = parse_openai_completion(&json).unwrap();
-        assert_eq!(completion.content, Some("Hello world".to_string()));
-        assert!(completion.tool_calls.is_empty());
-        assert!(completion.raw_tool_calls.is_none());
+        assert!(agent.history.iter().all(|m| m.created_at.is_some()));
+
+        agent.history[0].metadata = Some(json!({ "source": "cli" }));
+        let local = serde_json::to_value(&agent.history[0]).unwrap();
+        assert!(local.get("created_at").is_some());
+        assert_eq!(local["metadata"]["source"], "cli");
+
+        let sent = mini_agent::providers::build_openai_messages(&agent.history);
+        for m in &sent {
+            assert!(m.get("created_at").is_none());
+            assert!(m.get("metadata").is_none());
+        }
     }
 
-    #[test]
-    fn parse_completion_with_tool_calls() {
-        let json = json!({
-            "choices": [{
-                "message": {
-                    "role": "assistant",
-                    "content": null,
-                    "tool_calls": [{
-                        "id": "call_1",
-                        "type": "function",
-                        "function": {
-                            "name": "add_numbers",
-                            "arguments": "{\"a\":1,\"b\":2}"
-                        }
-                    }]
-                }
-            }]
-        });
-        let completion = parse_openai_completion(&json).unwrap();
-        assert_eq!(completion.tool_calls.len(), 1);
-        assert_eq!(completion.tool_calls[0].name, "add_numbers");
-        assert_eq!(completion.tool_calls[0].id, "call_1");
-        assert_eq!(completion.tool_calls[0].args["a"], 1);
-        assert_eq!(completion.tool_calls[0].args["b"], 2);
+    // ── Mock provider that returns empty content N times, then an answer ──
+
+    struct FlakyEmptyProvider {
+        empties: usize,
+        call_count: std::sync::Arc<std::sync::Mutex<usize>>,
     }
 
-    #[test]
-    fn parse_completion_missing_choices_returns_error() {
-        let json = json!({ "error": "something went wrong" });
-        let result = parse_openai_completion(&json);
-        assert!(result.is_err());
-        match result.unwrap_err() {
-            AgentError::InvalidResponse(msg) => assert!(msg.contains("choices")),
-            _ => panic!("Expected InvalidResponse"),
+    #[async_trait]
+    impl LlmProvider for FlakyEmptyProvider {
+        fn provider_name(&self) -> &str { "FlakyEmptyMock" }
+
+        async fn complete(
+            &self,
+            _messages: &[Message],
+            _tools: &[&dyn Tool],
+            _model: &str,
+        ) -> Result<Completion, AgentError> {
+            let mut count = self.call_count.lock().unwrap();
+            *count += 1;
+            let content = if *count <= self.empties { None } else { Some("Real answer".to_string()) };
+            Ok(Completion { content, tool_calls: vec![], raw_tool_calls: None, ..Default::default() })
         }
     }
 
-    #[test]
-    fn parse_completion_empty_choices_returns_error() {
-        let json = json!({ "choices": [] });
-        let result = parse_openai_completion(&json);
-        assert!(result.is_err());
+    #[tokio::test]
+    async fn retry_on_empty_yields_answer() {
+        let call_count = std::sync::Arc::new(std::sync::Mutex::new(0));
+        let provider = FlakyEmptyProvider { empties: 1, call_count: call_count.clone() };
+        let mut agent = Agent::new(Box::new(provider), "test-model").with_retry_on_empty(2);
+
+        let result = agent.run("hello").await.unwrap();
+        assert_eq!(result, "Real answer");
+        assert_eq!(*call_count.lock().unwrap(), 2);
     }
 
-    #[test]
-    fn parse_completion_bad_args_json_returns_error() {
-        let json = json!({
-            "choices": [{
-                "message": {
-                    "role": "assistant",
-                    "content": null,
-                    "tool_calls": [{
-                        "id": "call_1",
-                        "type": "function",
-                        "function": {
-                            "name": "some_tool",
-                            "arguments": "NOT VALID JSON {{{"
-                        }
-                    }]
-                }
-            }]
-        });
-        let result = parse_openai_completion(&json);
-        assert!(result.is_err());
+    #[tokio::test]
+    async fn retry_on_empty_gives_up_after_limit() {
+        let call_count = std::sync::Arc::new(std::sync::Mutex::new(0));
+        let provider = FlakyEmptyProvider { empties: 5, call_count: call_count.clone() };
+        let mut agent = Agent::new(Box::new(provider), "test-model").with_retry_on_empty(2);
+
+        match agent.run("hello").await.unwrap_err() {
+            AgentError::ProviderError(msg) => assert!(msg.contains("Empty response")),
+            other => panic!("Expected ProviderError, got {other:?}"),
+        }
+        assert_eq!(*call_count.lock().unwrap(), 3);
+    }
+
+    #[tokio::test]
+    async fn plan_returns_tool_calls_without_executing() {
+        let call_count = std::sync::Arc::new(std::sync::Mutex::new(0));
+        let provider = ToolCallingProvider { call_count: call_count.clone() };
+        let mut agent = Agent::new(Box::new(provider), "test-model");
+        agent.add_tool(AddNumbersTool);
+
+        let calls = agent.plan("Add 10 and 20").await.unwrap();
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].id, "call_abc");
+        assert_eq!(calls[0].name, "add_numbers");
+        assert_eq!(calls[0].args, json!({ "a": 10, "b": 20 }));
+
+        // user + assistant only; nothing was executed
+        assert_eq!(*call_count.lock().unwrap(), 1);
+        assert_eq!(agent.history.len(), 2);
+        assert!(agent.history.iter().all(|m| m.role != mini_agent::Role::Tool));
+        assert!(agent.history[1].tool_calls.is_some());
+    }
+
+    #[tokio::test]
+    async fn plan_returns_empty_when_model_answers_directly() {
+        let provider = MockProvider { response: "No tools needed".into() };
+        let mut agent = Agent::new(Box::new(provider), "test-model");
+        let calls = agent.plan("hello").await.unwrap();
+        assert!(calls.is_empty());
+        assert_eq!(agent.history[1].content, "No tools needed");
+    }
+
+    #[tokio::test]
+    async fn execute_call_runs_tool_and_records_result() {
+        let provider = MockProvider { response: "unused".into() };
+        let mut agent = Agent::new(Box::new(provider), "test-model");
+        agent.add_tool(MultiplyNumbersTool);
+
+        let call = mini_agent::ToolCall {
+            id: "call_7".into(),
+            name: "multiply_numbers".into(),
+            args: json!({ "a": 6, "b": 7 }),
+        };
+        let result = agent.execute_call(&call).await.unwrap();
+        assert_eq!(result, "42");
+
+        let last = agent.history.last().unwrap();
+        assert_eq!(last.role, mini_agent::Role::Tool);
+        assert_eq!(last.content, "42");
+        assert_eq!(last.tool_call_id.as_deref(), Some("call_7"));
+    }
+
+    #[tokio::test]
+    async fn plan_then_execute_call_builds_history() {
+        let call_count = std::sync::Arc::new(std::sync::Mutex::new(0));
+        let provider = ToolCallingProvider { call_count };
+        let mut agent = Agent::new(Box::new(provider), "test-model");
+        agent.add_tool(AddNumbersTool);
+
+        let calls = agent.plan("Add 10 and 20").await.unwrap();
+        for call in &calls {
+            agent.execute_call(call).await.unwrap();
+        }
+        assert_eq!(agent.history.len(), 3);
+        assert_eq!(agent.history[2].content, "30");
+    }
+
+    // ── Tool that returns an image, and a provider that records requests ──
+
+    struct ChartTool;
+
+    #[async_trait]
+    impl Tool for ChartTool {
+        fn name(&self) -> &'static str { "render_chart" }
+        fn description(&self) -> &'static str { "Renders a chart as PNG" }
+        fn parameters_schema(&self) -> serde_json::Value {
+            json!({ "type": "object", "properties": {} })
+        }
+        async fn execute(&self, _args: serde_json::Value) -> Result<String, AgentError> {
+            Ok("chart rendered".into())
+        }
+        async fn execute_output(&self, _args: serde_json::Value) -> Result<mini_agent::ToolOutput, AgentError> {
+            Ok(mini_agent::ToolOutput::Image {
+                media_type: "image/png".into(),
+                data_base64: "iVBORw0KGgo=".into(),
+            })
+        }
+    }
+
+    struct RecordingProvider {
+        requests: std::sync::Arc<std::sync::Mutex<Vec<Vec<Message>>>>,
+        first_reply: Completion,
+    }
+
+    #[async_trait]
+    impl LlmProvider for RecordingProvider {
+        fn provider_name(&self) -> &str { "RecordingMock" }
+
+        async fn complete(
+            &self,
+            messages: &[Message],
+            _tools: &[&dyn Tool],
+            _model: &str,
+        ) -> Result<Completion, AgentError> {
+            let mut requests = self.requests.lock().unwrap();
+            requests.push(messages.to_vec());
+            if requests.len() == 1 {
+                Ok(Completion {
+                    content: self.first_reply.content.clone(),
+                    tool_calls: self.first_reply.tool_calls.clone(),
+                    raw_tool_calls: self.first_reply.raw_tool_calls.clone(),
+                    ..Default::default()
+                })
+            } else {
+                Ok(Completion { content: Some("done".into()), tool_calls: vec![], raw_tool_calls: None, ..Default::default() })
+            }
+        }
+    }
+
+    fn single_call_reply(id: &str, name: &str, args: serde_json::Value) -> Completion {
+        Completion {
+            content: None,
+            tool_calls: vec![mini_agent::ToolCall { id: id.into(), name: name.into(), args: args.clone() }],
+            raw_tool_calls: Some(json!([{
+                "id": id,
+                "type": "function",
+                "function": { "name": name, "arguments": args.to_string() }
+            }])),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn tool_image_output_is_sent_as_image_block() {
+        let requests = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+        let provider = RecordingProvider {
+            requests: requests.clone(),
+            first_reply: single_call_reply("call_img", "render_chart", json!({})),
+        };
+        let mut agent = Agent::new(Box::new(provider), "test-model");
+        agent.add_tool(ChartTool);
+        agent.run("Draw the chart").await.unwrap();
+
+        let second = requests.lock().unwrap()[1].clone();
+        let tool_msg = second.iter().find(|m| m.role == mini_agent::Role::Tool).unwrap();
+        assert_eq!(tool_msg.images[0].media_type, "image/png");
+
+        let body = mini_agent::providers::build_openai_messages(&second);
+        let image_turn = body.last().unwrap();
+        assert_eq!(image_turn["role"], "user");
+        let parts = image_turn["content"].as_array().unwrap();
+        assert!(parts.iter().any(|p| p["type"] == "image_url"
+            && p["image_url"]["url"] == "data:image/png;base64,iVBORw0KGgo="));
+    }
+
+    #[tokio::test]
+    async fn stop_condition_ends_run_on_sentinel() {
+        let requests = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+        let mut first_reply = single_call_reply("call_1", "add_numbers", json!({ "a": 1, "b": 2 }));
+        first_reply.content = Some("All DONE here".into());
+        let provider = RecordingProvider { requests: requests.clone(), first_reply };
+        let mut agent = Agent::new(Box::new(provider), "test-model").with_stop_condition(Box::new(
+            |c: &Completion| c.content.as_deref().is_some_and(|t| t.contains("DONE")),
+        ));
+        agent.add_tool(AddNumbersTool);
+
+        let result = agent.run("go").await.unwrap();
+        assert_eq!(result, "All DONE here");
+        // Stopped after the first completion: no tool ran, no second request
+        assert_eq!(requests.lock().unwrap().len(), 1);
+        assert!(agent.history.iter().all(|m| m.role != mini_agent::Role::Tool));
+    }
+
+    #[tokio::test]
+    async fn stop_condition_false_does_not_interfere() {
+        let provider = MockProvider { response: "still going".into() };
+        let mut agent = Agent::new(Box::new(provider), "test-model")
+            .with_stop_condition(Box::new(|_: &Completion| false));
+        assert_eq!(agent.run("hi").await.unwrap(), "still going");
+    }
+
+    #[tokio::test]
+    async fn run_fails_fast_on_mismatched_tool_call_id() {
+        let requests = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+        let provider = RecordingProvider {
+            requests: requests.clone(),
+            first_reply: single_call_reply("call_1", "add_numbers", json!({ "a": 1, "b": 2 })),
+        };
+        let mut agent = Agent::new(Box::new(provider), "test-model");
+        agent.history.push(Message::assistant_with_tools("", json!([{ "id": "call_1" }])));
+        agent.history.push(Message::tool("call_wrong", "3"));
+
+        let err = agent.run("continue").await.unwrap_err();
+        assert!(matches!(err, AgentError::InvalidHistory(_)));
+        assert!(requests.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn pop_last_turn_removes_orphaned_tool_results() {
+        let provider = ToolCallingProvider {
+            call_count: std::sync::Arc::new(std::sync::Mutex::new(0)),
+        };
+        let mut agent = Agent::new(Box::new(provider), "test-model");
+        agent.add_tool(AddNumbersTool);
+        agent.history.push(Message::user("earlier question"));
+        agent.history.push(Message::assistant("earlier answer"));
+
+        // user, assistant (tool call), tool
+        for call in agent.plan("Add 10 and 20").await.unwrap() {
+            agent.execute_call(&call).await.unwrap();
+        }
+        assert_eq!(agent.history.len(), 5);
+
+        let removed = agent.pop_last_turn();
+        assert_eq!(removed.len(), 3);
+        assert_eq!(agent.history.len(), 2);
+        assert!(agent.history.iter().all(|m| m.role != mini_agent::Role::Tool));
+        assert_eq!(agent.history[1].content, "earlier answer");
+    }
+
+    #[test]
+    fn remove_message_drops_tool_results_with_their_call() {
+        let provider = MockProvider { response: "x".into() };
+        let mut agent = Agent::new(Box::new(provider), "test-model");
+        agent.history = vec![
+            Message::user("q"),
+            Message::assistant_with_tools("", json!([{ "id": "a" }, { "id": "b" }])),
+            Message::tool("a", "1"),
+            Message::tool("b", "2"),
+            Message::assistant("final"),
+        ];
+
+        let removed = agent.remove_message(1).unwrap();
+        assert_eq!(removed.len(), 3);
+        let contents: Vec<&str> = agent.history.iter().map(|m| m.content.as_str()).collect();
+        assert_eq!(contents, vec!["q", "final"]);
+    }
+
+    #[test]
+    fn remove_tool_message_removes_whole_group() {
+        let provider = MockProvider { response: "x".into() };
+        let mut agent = Agent::new(Box::new(provider), "test-model");
+        agent.history = vec![
+            Message::user("q"),
+            Message::assistant_with_tools("", json!([{ "id": "a" }, { "id": "b" }])),
+            Message::tool("a", "1"),
+            Message::tool("b", "2"),
+        ];
+        let removed = agent.remove_message(3).unwrap();
+        assert_eq!(removed.len(), 3);
+        assert_eq!(agent.history.len(), 1);
+        assert!(agent.remove_message(5).is_err());
+    }
+
+    // ── Mock provider that echoes the latest user message ─────────────────
+
+    struct EchoProvider;
+
+    #[async_trait]
+    impl LlmProvider for EchoProvider {
+        fn provider_name(&self) -> &str { "EchoMock" }
+
+        async fn complete(
+            &self,
+            messages: &[Message],
+            _tools: &[&dyn Tool],
+            _model: &str,
+        ) -> Result<Completion, AgentError> {
+            let last_user = messages.iter().rev().find(|m| m.role == mini_agent::Role::User).unwrap();
+            Ok(Completion {
+                content: Some(format!("echo: {}", last_user.content)),
+                tool_calls: vec![],
+                raw_tool_calls: None,
+                ..Default::default()
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn fork_runs_independent_branches_from_common_prefix() {
+        let mut agent = Agent::new(Box::new(EchoProvider), "test-model").with_max_steps(3);
+        agent.add_tool(AddNumbersTool);
+        agent.run("common prefix").await.unwrap();
+
+        let mut branch = agent.fork();
+        assert!(std::sync::Arc::ptr_eq(&agent.provider, &branch.provider));
+        assert!(std::sync::Arc::ptr_eq(&agent.tools[0], &branch.tools[0]));
+        assert_eq!(branch.max_steps, 3);
+
+        assert_eq!(agent.run("left").await.unwrap(), "echo: left");
+        assert_eq!(branch.run("right").await.unwrap(), "echo: right");
+
+        assert_eq!(agent.history.len(), 4);
+        assert_eq!(branch.history.len(), 4);
+        assert_eq!(agent.history[0].content, branch.history[0].content);
+        assert_eq!(agent.history[2].content, "left");
+        assert_eq!(branch.history[2].content, "right");
+    }
+
+    // ── Mock provider that is slow and always asks for another tool call ──
+
+    struct SlowLoopingProvider {
+        delay: std::time::Duration,
+        call_count: std::sync::Arc<std::sync::Mutex<usize>>,
+    }
+
+    #[async_trait]
+    impl LlmProvider for SlowLoopingProvider {
+        fn provider_name(&self) -> &str { "SlowMock" }
+
+        async fn complete(
+            &self,
+            _messages: &[Message],
+            _tools: &[&dyn Tool],
+            _model: &str,
+        ) -> Result<Completion, AgentError> {
+            tokio::time::sleep(self.delay).await;
+            let n = {
+                let mut count = self.call_count.lock().unwrap();
+                *count += 1;
+                *count
+            };
+            Ok(single_call_reply(&format!("call_{n}"), "add_numbers", json!({ "a": n, "b": 1 })))
+        }
+    }
+
+    struct SleepyTool;
+
+    #[async_trait]
+    impl Tool for SleepyTool {
+        fn name(&self) -> &'static str { "add_numbers" }
+        fn description(&self) -> &'static str { "Slow adder" }
+        fn parameters_schema(&self) -> serde_json::Value { json!({ "type": "object" }) }
+        async fn execute(&self, _args: serde_json::Value) -> Result<String, AgentError> {
+            tokio::time::sleep(std::time::Duration::from_secs(5)).await;
+            Ok("never".into())
+        }
+    }
+
+    #[tokio::test]
+    async fn run_timeout_stops_slow_loop() {
+        let provider = SlowLoopingProvider {
+            delay: std::time::Duration::from_millis(40),
+            call_count: std::sync::Arc::new(std::sync::Mutex::new(0)),
+        };
+        let mut agent = Agent::new(Box::new(provider), "test-model")
+            .with_max_steps(100)
+            .with_run_timeout(std::time::Duration::from_millis(150));
+        agent.add_tool(AddNumbersTool);
+
+        match agent.run("loop forever").await.unwrap_err() {
+            AgentError::Timeout { elapsed, steps_completed } => {
+                assert!(elapsed >= std::time::Duration::from_millis(150));
+                assert!((1..100).contains(&steps_completed));
+            }
+            other => panic!("Expected Timeout, got {other:?}"),
+        }
+        assert!(mini_agent::providers::validate_tool_call_ids(&agent.history).is_ok());
+    }
+
+    #[tokio::test]
+    async fn run_timeout_during_tool_drops_dangling_call() {
+        let provider = SlowLoopingProvider {
+            delay: std::time::Duration::from_millis(1),
+            call_count: std::sync::Arc::new(std::sync::Mutex::new(0)),
+        };
+        let mut agent = Agent::new(Box::new(provider), "test-model")
+            .with_run_timeout(std::time::Duration::from_millis(50));
+        agent.add_tool(SleepyTool);
+
+        let err = agent.run("go").await.unwrap_err();
+        assert!(matches!(err, AgentError::Timeout { steps_completed: 0, .. }));
+        // Only the user message remains; the unanswered tool-call turn is gone
+        assert_eq!(agent.history.len(), 1);
+        assert_eq!(agent.history[0].role, mini_agent::Role::User);
+    }
+
+    #[tokio::test]
+    async fn run_with_past_deadline_times_out_immediately() {
+        let call_count = std::sync::Arc::new(std::sync::Mutex::new(0));
+        let provider = SlowLoopingProvider {
+            delay: std::time::Duration::from_millis(1),
+            call_count: call_count.clone(),
+        };
+        let mut agent = Agent::new(Box::new(provider), "test-model");
+        agent.add_tool(AddNumbersTool);
+
+        let deadline = std::time::Instant::now() - std::time::Duration::from_secs(1);
+        let err = agent.run_with_deadline("go", deadline).await.unwrap_err();
+        assert!(matches!(err, AgentError::Timeout { steps_completed: 0, .. }));
+        assert_eq!(*call_count.lock().unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn run_with_deadline_stops_between_steps() {
+        let provider = SlowLoopingProvider {
+            delay: std::time::Duration::from_millis(40),
+            call_count: std::sync::Arc::new(std::sync::Mutex::new(0)),
+        };
+        let mut agent = Agent::new(Box::new(provider), "test-model").with_max_steps(100);
+        agent.add_tool(AddNumbersTool);
+
+        let deadline = std::time::Instant::now() + std::time::Duration::from_millis(150);
+        match agent.run_with_deadline("loop forever", deadline).await.unwrap_err() {
+            AgentError::Timeout { steps_completed, .. } => assert!((1..100).contains(&steps_completed)),
+            other => panic!("Expected Timeout, got {other:?}"),
+        }
+        assert!(mini_agent::providers::validate_tool_call_ids(&agent.history).is_ok());
+
+        // The deadline only applies to that run
+        agent.max_steps = 1;
+        assert!(matches!(agent.run("again").await.unwrap_err(), AgentError::MaxIterations));
+    }
+
+    /// Keeps calling `add_numbers`, moving the mock clock on by `tick` per
+    /// request.
+    #[cfg(feature = "testing")]
+    struct TickingProvider {
+        clock: std::sync::Arc<mini_agent::MockClock>,
+        tick: std::time::Duration,
+    }
+
+    #[cfg(feature = "testing")]
+    #[async_trait]
+    impl LlmProvider for TickingProvider {
+        fn provider_name(&self) -> &str { "TickingMock" }
+
+        async fn complete(
+            &self,
+            messages: &[Message],
+            _tools: &[&dyn Tool],
+            _model: &str,
+        ) -> Result<Completion, AgentError> {
+            self.clock.advance(self.tick);
+            let id = format!("call_{}", messages.len());
+            Ok(single_call_reply(&id, "add_numbers", json!({ "a": 1, "b": 1 })))
+        }
+    }
+
+    #[cfg(feature = "testing")]
+    #[tokio::test]
+    async fn run_timeout_follows_the_agent_clock() {
+        let clock = std::sync::Arc::new(mini_agent::MockClock::new());
+        let provider = TickingProvider { clock: clock.clone(), tick: std::time::Duration::from_secs(25) };
+        let mut agent = Agent::new(Box::new(provider), "m")
+            .with_max_steps(100)
+            .with_run_timeout(std::time::Duration::from_secs(60))
+            .with_clock(clock);
+        agent.add_tool(AddNumbersTool);
+
+        // Requests at 0s and 25s fit; the tools after the request at 50s don't
+        match agent.run("loop").await.unwrap_err() {
+            AgentError::Timeout { elapsed, steps_completed } => {
+                assert_eq!(elapsed, std::time::Duration::from_secs(75));
+                assert_eq!(steps_completed, 2);
+            }
+            other => panic!("Expected Timeout, got {other:?}"),
+        }
+        assert!(mini_agent::providers::validate_tool_call_ids(&agent.history).is_ok());
+    }
+
+    #[cfg(feature = "testing")]
+    #[tokio::test]
+    async fn run_with_deadline_follows_the_agent_clock() {
+        let clock = std::sync::Arc::new(mini_agent::MockClock::new());
+        let provider = TickingProvider { clock: clock.clone(), tick: std::time::Duration::from_secs(10) };
+        let mut agent = Agent::new(Box::new(provider), "m").with_max_steps(100).with_clock(clock.clone());
+        agent.add_tool(AddNumbersTool);
+
+        let deadline = mini_agent::Clock::now(clock.as_ref()) + std::time::Duration::from_secs(25);
+        match agent.run_with_deadline("loop", deadline).await.unwrap_err() {
+            AgentError::Timeout { elapsed, steps_completed } => {
+                assert_eq!(elapsed, std::time::Duration::from_secs(30));
+                assert_eq!(steps_completed, 2);
+            }
+            other => panic!("Expected Timeout, got {other:?}"),
+        }
+    }
+
+    // ── Mock provider that records the model it was asked for ──
+
+    struct ModelRecordingProvider {
+        models: std::sync::Arc<std::sync::Mutex<Vec<String>>>,
+    }
+
+    #[async_trait]
+    impl LlmProvider for ModelRecordingProvider {
+        fn provider_name(&self) -> &str { "ModelMock" }
+
+        async fn complete(
+            &self,
+            _messages: &[Message],
+            _tools: &[&dyn Tool],
+            model: &str,
+        ) -> Result<Completion, AgentError> {
+            self.models.lock().unwrap().push(model.to_string());
+            Ok(Completion {
+                content: Some("ok".into()),
+                tool_calls: vec![],
+                raw_tool_calls: None,
+                ..Default::default()
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn run_with_model_overrides_model_for_that_run() {
+        let models = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let provider = ModelRecordingProvider { models: models.clone() };
+        let mut agent = Agent::new(Box::new(provider), "expensive-model");
+
+        agent.run_with_model("quick question", "cheap-model").await.unwrap();
+        agent.run("hard question").await.unwrap();
+
+        assert_eq!(*models.lock().unwrap(), vec!["cheap-model", "expensive-model"]);
+        assert_eq!(agent.model, "expensive-model");
+    }
+
+    #[tokio::test]
+    async fn tool_invocations_record_each_execution() {
+        let provider = ToolCallingProvider {
+            call_count: std::sync::Arc::new(std::sync::Mutex::new(0)),
+        };
+        let mut agent = Agent::new(Box::new(provider), "test-model");
+        agent.add_tool(AddNumbersTool);
+
+        agent.run("Add 10 and 20").await.unwrap();
+
+        let invocations = agent.tool_invocations();
+        assert_eq!(invocations.len(), 1);
+        assert_eq!(invocations[0].name, "add_numbers");
+        assert_eq!(invocations[0].args, json!({ "a": 10, "b": 20 }));
+        assert_eq!(invocations[0].result.as_deref(), Some("30"));
+        assert!(invocations[0].error.is_none());
+    }
+
+    #[tokio::test]
+    async fn tool_invocations_record_failures() {
+        let provider = ToolCallingProvider {
+            call_count: std::sync::Arc::new(std::sync::Mutex::new(0)),
+        };
+        let mut agent = Agent::new(Box::new(provider), "test-model");
+
+        assert!(agent.run("Add 10 and 20").await.is_err());
+
+        let invocations = agent.tool_invocations();
+        assert_eq!(invocations.len(), 1);
+        assert!(invocations[0].result.is_none());
+        assert!(invocations[0].error.as_deref().unwrap().contains("add_numbers"));
+    }
+
+
+    #[tokio::test]
+    async fn examples_are_sent_and_survive_trim() {
+        let requests = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let provider = RecordingProvider {
+            requests: requests.clone(),
+            first_reply: Completion { content: Some("first".into()), tool_calls: vec![], raw_tool_calls: None, ..Default::default() },
+        };
+        let mut agent = Agent::new(Box::new(provider), "test-model").with_examples(vec![
+            Message::user("What is 2+2?"),
+            Message::assistant("4"),
+        ]);
+
+        agent.run("first question").await.unwrap();
+        agent.run("second question").await.unwrap();
+        let trimmed = agent.trim_history(1);
+        assert_eq!(trimmed.len(), 3);
+        assert!(agent.history.iter().all(|m| m.content != "What is 2+2?"));
+
+        agent.run("third question").await.unwrap();
+
+        let requests = requests.lock().unwrap();
+        for request in requests.iter() {
+            assert_eq!(request[1].content, "What is 2+2?");
+            assert_eq!(request[2].content, "4");
+        }
+        let last = requests.last().unwrap();
+        assert_eq!(last.len(), 5); // system, 2 examples, "done", new question
+        assert_eq!(last[4].content, "third question");
+    }
+
+    #[test]
+    fn trim_history_never_starts_on_tool_result() {
+        let mut agent = Agent::new(Box::new(MockProvider { response: String::new() }), "test-model");
+        agent.history = vec![
+            Message::user("q"),
+            Message::assistant_with_tools(
+                "",
+                json!([{ "id": "c1", "type": "function", "function": { "name": "add_numbers", "arguments": "{}" } }]),
+            ),
+            Message::tool("c1", "3"),
+            Message::assistant("3"),
+        ];
+
+        let removed = agent.trim_history(2);
+
+        assert_eq!(removed.len(), 3);
+        assert_eq!(agent.history.len(), 1);
+        assert_eq!(agent.history[0].content, "3");
+    }
+
+
+    // ── Mock provider that sends bad tool args until it sees the schema note ──
+
+    struct StubbornProvider {
+        call_count: std::sync::Arc<std::sync::Mutex<usize>>,
+        learns_from_note: bool,
+    }
+
+    #[async_trait]
+    impl LlmProvider for StubbornProvider {
+        fn provider_name(&self) -> &str { "StubbornMock" }
+
+        async fn complete(
+            &self,
+            messages: &[Message],
+            _tools: &[&dyn Tool],
+            _model: &str,
+        ) -> Result<Completion, AgentError> {
+            let n = {
+                let mut count = self.call_count.lock().unwrap();
+                *count += 1;
+                *count
+            };
+            let last = messages.last().unwrap();
+            if last.content == "30" {
+                return Ok(Completion { content: Some("The answer is 30".into()), tool_calls: vec![], raw_tool_calls: None, ..Default::default() });
+            }
+            let saw_note = messages.iter().any(|m| m.content.contains("must match this JSON schema"));
+            let args = if saw_note && self.learns_from_note {
+                json!({ "a": 10, "b": 20 })
+            } else {
+                json!({ "a": "ten", "b": "twenty" })
+            };
+            Ok(single_call_reply(&format!("call_{n}"), "add_numbers", args))
+        }
+    }
+
+    #[tokio::test]
+    async fn tool_retry_adds_schema_note_then_succeeds() {
+        let call_count = std::sync::Arc::new(std::sync::Mutex::new(0));
+        let provider = StubbornProvider { call_count: call_count.clone(), learns_from_note: true };
+        let mut agent = Agent::new(Box::new(provider), "test-model").with_tool_retry(2, 4);
+        agent.add_tool(AddNumbersTool);
+
+        let result = agent.run("Add 10 and 20").await.unwrap();
+        assert_eq!(result, "The answer is 30");
+        // Two bad calls, one corrected call, one final answer
+        assert_eq!(*call_count.lock().unwrap(), 4);
+
+        let notes: Vec<_> = agent.history.iter().filter(|m| m.content.contains("must match this JSON schema")).collect();
+        assert_eq!(notes.len(), 1);
+        assert!(notes[0].content.contains("\"required\":[\"a\",\"b\"]"));
+        assert!(agent.history.iter().any(|m| m.content.starts_with("Error: Tool execution failed")));
+
+        // The note doesn't split the turn: popping it removes everything
+        let turn_len = agent.history.len();
+        assert_eq!(agent.pop_last_turn().len(), turn_len);
+        assert!(agent.history.is_empty());
+    }
+
+    #[tokio::test]
+    async fn tool_retry_gives_up_after_cap() {
+        let provider = StubbornProvider {
+            call_count: std::sync::Arc::new(std::sync::Mutex::new(0)),
+            learns_from_note: false,
+        };
+        let mut agent = Agent::new(Box::new(provider), "test-model").with_tool_retry(2, 3);
+        agent.add_tool(AddNumbersTool);
+
+        match agent.run("Add 10 and 20").await.unwrap_err() {
+            AgentError::ToolError(msg) => assert!(msg.contains("'add_numbers' failed 3 times in a row")),
+            other => panic!("Expected ToolError, got {other:?}"),
+        }
+    }
+
+
+    // ── Tool whose schema generation is instrumented ──
+
+    struct CountingSchemaTool {
+        schema_calls: std::sync::Arc<std::sync::Mutex<usize>>,
+    }
+
+    #[async_trait]
+    impl Tool for CountingSchemaTool {
+        fn name(&self) -> &'static str { "counted" }
+        fn description(&self) -> &'static str { "Counts schema builds" }
+        fn parameters_schema(&self) -> serde_json::Value {
+            *self.schema_calls.lock().unwrap() += 1;
+            json!({ "type": "object" })
+        }
+        async fn execute(&self, _args: serde_json::Value) -> Result<String, AgentError> {
+            Ok("ok".into())
+        }
+    }
+
+    // ── Mock provider that serializes tools like a real provider ──
+
+    struct SerializingProvider;
+
+    #[async_trait]
+    impl LlmProvider for SerializingProvider {
+        fn provider_name(&self) -> &str { "SerializingMock" }
+
+        async fn complete(
+            &self,
+            _messages: &[Message],
+            tools: &[&dyn Tool],
+            _model: &str,
+        ) -> Result<Completion, AgentError> {
+            let _ = mini_agent::providers::build_openai_tools(tools);
+            Ok(Completion { content: Some("done".into()), tool_calls: vec![], raw_tool_calls: None, ..Default::default() })
+        }
+    }
+
+    #[tokio::test]
+    async fn tool_schemas_are_built_once_across_runs() {
+        let schema_calls = std::sync::Arc::new(std::sync::Mutex::new(0));
+        let mut agent = Agent::new(Box::new(SerializingProvider), "test-model");
+        agent.add_tool(CountingSchemaTool { schema_calls: schema_calls.clone() });
+
+        agent.run("one").await.unwrap();
+        agent.run("two").await.unwrap();
+        assert_eq!(*schema_calls.lock().unwrap(), 1);
+
+        // Changing the tool set invalidates the cache
+        agent.add_tool(AddNumbersTool);
+        agent.run("three").await.unwrap();
+        assert_eq!(*schema_calls.lock().unwrap(), 2);
+
+        // So does replacing a tool with another of the same name
+        agent.tools[0] = std::sync::Arc::new(CountingSchemaTool { schema_calls: schema_calls.clone() });
+        agent.run("four").await.unwrap();
+        assert_eq!(*schema_calls.lock().unwrap(), 3);
+    }
+
+
+    // ── Mock provider that asks for a tool, then fails ──
+
+    struct FailsSecondStepProvider {
+        call_count: std::sync::Arc<std::sync::Mutex<usize>>,
+    }
+
+    #[async_trait]
+    impl LlmProvider for FailsSecondStepProvider {
+        fn provider_name(&self) -> &str { "FailingMock" }
+
+        async fn complete(
+            &self,
+            _messages: &[Message],
+            _tools: &[&dyn Tool],
+            _model: &str,
+        ) -> Result<Completion, AgentError> {
+            let mut count = self.call_count.lock().unwrap();
+            *count += 1;
+            if *count == 1 {
+                let mut reply = single_call_reply("call_1", "add_numbers", json!({ "a": 10, "b": 20 }));
+                reply.content = Some("Adding the numbers first.".into());
+                Ok(reply)
+            } else {
+                Err(AgentError::ProviderError("connection reset".into()))
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn failed_run_keeps_partial_progress() {
+        let provider = FailsSecondStepProvider { call_count: std::sync::Arc::new(std::sync::Mutex::new(0)) };
+        let mut agent = Agent::new(Box::new(provider), "test-model");
+        agent.add_tool(AddNumbersTool);
+
+        assert!(agent.run("Add 10 and 20").await.is_err());
+
+        let tool_result = agent.history.iter().find(|m| m.role == mini_agent::Role::Tool).unwrap();
+        assert_eq!(tool_result.content, "30");
+        assert_eq!(tool_result.tool_call_id.as_deref(), Some("call_1"));
+        assert_eq!(agent.last_partial_content(), Some("Adding the numbers first."));
+    }
+
+
+    // ── Resuming after MaxIterations ──────────────────────────────────────
+
+    /// Calls `add_numbers` for the first `tool_rounds` requests, then answers.
+    struct LongTaskProvider {
+        tool_rounds: usize,
+        calls: std::sync::Mutex<usize>,
+    }
+
+    #[async_trait]
+    impl LlmProvider for LongTaskProvider {
+        fn provider_name(&self) -> &str { "LongTaskMock" }
+
+        async fn complete(
+            &self,
+            _messages: &[Message],
+            _tools: &[&dyn Tool],
+            _model: &str,
+        ) -> Result<Completion, AgentError> {
+            let mut calls = self.calls.lock().unwrap();
+            *calls += 1;
+            if *calls > self.tool_rounds {
+                return Ok(Completion { content: Some("all done".into()), ..Default::default() });
+            }
+            let id = format!("call_{calls}");
+            Ok(Completion {
+                tool_calls: vec![mini_agent::ToolCall {
+                    id: id.clone(),
+                    name: "add_numbers".into(),
+                    args: json!({ "a": 1, "b": 2 }),
+                }],
+                raw_tool_calls: Some(json!([{
+                    "id": id,
+                    "type": "function",
+                    "function": { "name": "add_numbers", "arguments": "{\"a\":1,\"b\":2}" }
+                }])),
+                ..Default::default()
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn resume_continues_after_max_iterations() {
+        let provider = LongTaskProvider { tool_rounds: 3, calls: std::sync::Mutex::new(0) };
+        let mut agent = Agent::new(Box::new(provider), "m").with_max_steps(2);
+        agent.add_tool(AddNumbersTool);
+
+        let err = agent.run("do a long task").await.unwrap_err();
+        assert!(matches!(err, AgentError::MaxIterations));
+        assert_eq!(agent.history.len(), 5);
+        assert_eq!(agent.history.last().unwrap().role, mini_agent::Role::Tool);
+
+        assert_eq!(agent.resume().await.unwrap(), "all done");
+        // Only the original user message — resuming adds none
+        let user_turns = agent.history.iter().filter(|m| m.role == mini_agent::Role::User).count();
+        assert_eq!(user_turns, 1);
+        assert_eq!(agent.history.len(), 8);
+    }
+
+    #[tokio::test]
+    async fn resume_without_history_is_an_error() {
+        let mut agent = Agent::new(Box::new(MockProvider { response: "hi".into() }), "m");
+        assert!(matches!(agent.resume().await, Err(AgentError::InvalidHistory(_))));
+    }
+
+
+    #[test]
+    fn debug_dump_lists_model_tools_and_history() {
+        let mut agent = Agent::new(Box::new(MockProvider { response: "hi".into() }), "dump-model")
+            .with_system_prompt("Be terse.");
+        agent.add_tool(AddNumbersTool);
+        agent.add_tool(MultiplyNumbersTool);
+        agent.history.push(Message::user("what is 2 + 2?"));
+        agent.history.push(Message::tool("call_9", "4"));
+
+        let dump = agent.debug_dump();
+        assert!(dump.contains("model:     dump-model"));
+        assert!(dump.contains("Be terse."));
+        assert!(dump.contains("=== Tools (2) ==="));
+        assert!(dump.contains("- add_numbers:"));
+        assert!(dump.contains("- multiply_numbers:"));
+        assert!(dump.contains("[0] user: what is 2 + 2?"));
+        assert!(dump.contains("tool_call_id: call_9"));
+    }
+
+
+    // ── Native system role ────────────────────────────────────────────────
+
+    struct DeveloperRoleProvider {
+        requests: std::sync::Arc<std::sync::Mutex<Vec<Vec<Message>>>>,
+    }
+
+    #[async_trait]
+    impl LlmProvider for DeveloperRoleProvider {
+        fn provider_name(&self) -> &str { "DeveloperMock" }
+
+        fn system_role(&self, model: &str) -> Option<mini_agent::Role> {
+            (model == "reasoner").then_some(mini_agent::Role::Developer)
+        }
+
+        async fn complete(
+            &self,
+            messages: &[Message],
+            _tools: &[&dyn Tool],
+            _model: &str,
+        ) -> Result<Completion, AgentError> {
+            self.requests.lock().unwrap().push(messages.to_vec());
+            Ok(Completion { content: Some("ok".into()), ..Default::default() })
+        }
+    }
+
+    #[tokio::test]
+    async fn system_prompt_uses_the_provider_role_for_the_model() {
+        let requests = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+        let provider = DeveloperRoleProvider { requests: requests.clone() };
+        let mut agent = Agent::new(Box::new(provider), "reasoner").with_system_prompt("Be terse.");
+
+        agent.run("hi").await.unwrap();
+        agent.run_with_model("hi again", "chat").await.unwrap();
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests[0][0].role, mini_agent::Role::Developer);
+        assert_eq!(requests[0][0].content, "Be terse.");
+        // Without a native role the prompt falls back to a prefixed user turn
+        assert_eq!(requests[1][0].role, mini_agent::Role::User);
+        assert_eq!(requests[1][0].content, "[SYSTEM]: Be terse.");
+    }
+
+
+    // ── Sorted tools ──────────────────────────────────────────────────────
+
+    /// Records the serialized tools block of every request.
+    struct ToolNameRecorder {
+        requests: std::sync::Arc<std::sync::Mutex<Vec<Vec<serde_json::Value>>>>,
+    }
+
+    #[async_trait]
+    impl LlmProvider for ToolNameRecorder {
+        fn provider_name(&self) -> &str { "ToolNameRecorder" }
+
+        async fn complete(
+            &self,
+            _messages: &[Message],
+            tools: &[&dyn Tool],
+            _model: &str,
+        ) -> Result<Completion, AgentError> {
+            self.requests.lock().unwrap().push(mini_agent::providers::build_openai_tools(tools));
+            Ok(Completion { content: Some("ok".into()), ..Default::default() })
+        }
+    }
+
+    #[tokio::test]
+    async fn sorted_tools_are_sent_alphabetically() {
+        let requests = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+        let provider = ToolNameRecorder { requests: requests.clone() };
+        let mut agent = Agent::new(Box::new(provider), "m").with_sorted_tools(true);
+        agent.add_tool(MultiplyNumbersTool);
+        agent.add_tool(mini_agent::JokeTool);
+        agent.add_tool(AddNumbersTool);
+
+        agent.run("hi").await.unwrap();
+
+        let body = requests.lock().unwrap()[0].clone();
+        let names: Vec<&str> = body.iter().map(|t| t["function"]["name"].as_str().unwrap()).collect();
+        assert_eq!(names, ["add_numbers", "get_joke", "multiply_numbers"]);
+        // Registration order itself is untouched
+        assert_eq!(agent.tools[0].name(), "multiply_numbers");
+    }
+
+
+    // ── Terminal tools ────────────────────────────────────────────────────
+
+    struct FinalAnswerTool;
+
+    #[async_trait]
+    impl Tool for FinalAnswerTool {
+        fn name(&self) -> &'static str { "final_answer" }
+        fn description(&self) -> &'static str { "Submit the final answer" }
+        fn parameters_schema(&self) -> serde_json::Value {
+            json!({ "type": "object", "properties": { "answer": { "type": "string" } } })
+        }
+        async fn execute(&self, args: serde_json::Value) -> Result<String, AgentError> {
+            Ok(args["answer"].as_str().unwrap_or_default().to_string())
+        }
+        fn terminal(&self) -> bool { true }
+    }
+
+    /// Answers through `final_answer` first; any later request is a bug.
+    struct FinalAnswerProvider {
+        calls: std::sync::Arc<std::sync::Mutex<usize>>,
+    }
+
+    #[async_trait]
+    impl LlmProvider for FinalAnswerProvider {
+        fn provider_name(&self) -> &str { "FinalAnswerMock" }
+
+        async fn complete(
+            &self,
+            _messages: &[Message],
+            _tools: &[&dyn Tool],
+            _model: &str,
+        ) -> Result<Completion, AgentError> {
+            *self.calls.lock().unwrap() += 1;
+            Ok(Completion {
+                tool_calls: vec![mini_agent::ToolCall {
+                    id: "call_final".into(),
+                    name: "final_answer".into(),
+                    args: json!({ "answer": "42" }),
+                }],
+                raw_tool_calls: Some(json!([{
+                    "id": "call_final",
+                    "type": "function",
+                    "function": { "name": "final_answer", "arguments": "{\"answer\":\"42\"}" }
+                }])),
+                ..Default::default()
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn terminal_tool_ends_the_run_with_its_output() {
+        let calls = std::sync::Arc::new(std::sync::Mutex::new(0));
+        let mut agent = Agent::new(Box::new(FinalAnswerProvider { calls: calls.clone() }), "m");
+        agent.add_tool(FinalAnswerTool);
+
+        assert_eq!(agent.run("what is the answer?").await.unwrap(), "42");
+        assert_eq!(*calls.lock().unwrap(), 1);
+        let last = agent.history.last().unwrap();
+        assert_eq!(last.role, mini_agent::Role::Tool);
+        assert_eq!(last.content, "42");
+        assert!(!AddNumbersTool.terminal());
+    }
+
+
+    // ── Batch runs ────────────────────────────────────────────────────────
+
+    /// Echoes the prompt after a delay that shrinks with the input number,
+    /// so later inputs finish first, and records peak concurrency.
+    struct SlowEchoProvider {
+        in_flight: std::sync::Arc<std::sync::atomic::AtomicUsize>,
+        peak: std::sync::Arc<std::sync::atomic::AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl LlmProvider for SlowEchoProvider {
+        fn provider_name(&self) -> &str { "SlowEchoMock" }
+
+        async fn complete(
+            &self,
+            messages: &[Message],
+            _tools: &[&dyn Tool],
+            _model: &str,
+        ) -> Result<Completion, AgentError> {
+            use std::sync::atomic::Ordering;
+            let now = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+            self.peak.fetch_max(now, Ordering::SeqCst);
+
+            let prompt = messages.last().unwrap().content.clone();
+            let n: u64 = prompt.trim_start_matches("q").parse().unwrap();
+            tokio::time::sleep(std::time::Duration::from_millis(40 - 5 * n)).await;
+
+            self.in_flight.fetch_sub(1, Ordering::SeqCst);
+            Ok(Completion { content: Some(format!("a{n}")), ..Default::default() })
+        }
+    }
+
+    #[tokio::test]
+    async fn run_batch_keeps_input_order_and_bounds_concurrency() {
+        let peak = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let provider = SlowEchoProvider { in_flight: Default::default(), peak: peak.clone() };
+        let mut agent = Agent::new(Box::new(provider), "m");
+        agent.history.push(Message::user("earlier conversation"));
+
+        let inputs: Vec<String> = (0..6).map(|i| format!("q{i}")).collect();
+        let results = agent.run_batch(inputs, 2).await;
+
+        let answers: Vec<String> = results.into_iter().map(Result::unwrap).collect();
+        assert_eq!(answers, ["a0", "a1", "a2", "a3", "a4", "a5"]);
+        assert!(peak.load(std::sync::atomic::Ordering::SeqCst) <= 2);
+        // The agent's own history is untouched
+        assert_eq!(agent.history.len(), 1);
+    }
+
+
+    #[tokio::test]
+    async fn capability_check_refuses_tools_for_a_non_tool_model() {
+        // Fails before any request is sent, so no server is needed
+        let provider = mini_agent::OllamaProvider::with_base_url("http://127.0.0.1:9", "llama3");
+        let mut agent = Agent::new(Box::new(provider), "llama3").with_capability_check(true);
+        agent.add_tool(AddNumbersTool);
+
+        match agent.run("add 1 and 2").await {
+            Err(AgentError::ProviderError(msg)) => assert!(msg.contains("does not support tools"), "{msg}"),
+            other => panic!("expected a capability error, got {other:?}"),
+        }
+    }
+
+
+    #[tokio::test]
+    async fn output_transform_is_applied_to_the_answer() {
+        let provider = MockProvider { response: "  forty-two  ".into() };
+        let mut agent = Agent::new(Box::new(provider), "m")
+            .with_output_transform(Box::new(|answer| Ok(answer.trim().to_uppercase())));
+
+        assert_eq!(agent.run("answer?").await.unwrap(), "FORTY-TWO");
+        // History keeps what the model actually said
+        assert_eq!(agent.history.last().unwrap().content, "  forty-two  ");
+    }
+
+    #[tokio::test]
+    async fn output_transform_errors_fail_the_run() {
+        let provider = MockProvider { response: "   ".into() };
+        let mut agent = Agent::new(Box::new(provider), "m").with_output_transform(Box::new(|answer| {
+            let trimmed = answer.trim();
+            if trimmed.is_empty() {
+                return Err(AgentError::InvalidResponse("empty answer".into()));
+            }
+            Ok(trimmed.to_string())
+        }));
+
+        match agent.run("answer?").await {
+            Err(AgentError::InvalidResponse(msg)) => assert_eq!(msg, "empty answer"),
+            other => panic!("expected the transform's error, got {other:?}"),
+        }
+    }
+
+
+    #[tokio::test]
+    async fn run_detailed_traces_every_step() {
+        let provider = ToolCallingProvider { call_count: std::sync::Arc::new(std::sync::Mutex::new(0)) };
+        let mut agent = Agent::new(Box::new(provider), "m");
+        agent.add_tool(AddNumbersTool);
+
+        let outcome = agent.run_detailed("add 10 and 20").await.unwrap();
+        assert_eq!(outcome.answer, "The answer is 30");
+        assert_eq!(outcome.steps.len(), 2);
+
+        let first = &outcome.steps[0];
+        assert_eq!(first.content, None);
+        assert_eq!(first.tool_calls.len(), 1);
+        assert_eq!(first.tool_calls[0].name, "add_numbers");
+        assert_eq!(first.tool_results.len(), 1);
+        assert_eq!(first.tool_results[0].result.as_deref(), Some("30"));
+
+        let second = &outcome.steps[1];
+        assert_eq!(second.content.as_deref(), Some("The answer is 30"));
+        assert!(second.tool_calls.is_empty() && second.tool_results.is_empty());
+    }
+
+
+    // ── Loop detection ────────────────────────────────────────────────────
+
+    /// Repeats the same logical call with fresh ids (and shuffled key order)
+    /// until it is told it already made it, then answers.
+    struct RepeatingProvider {
+        calls: std::sync::Mutex<usize>,
+    }
+
+    #[async_trait]
+    impl LlmProvider for RepeatingProvider {
+        fn provider_name(&self) -> &str { "RepeatingMock" }
+
+        async fn complete(
+            &self,
+            messages: &[Message],
+            _tools: &[&dyn Tool],
+            _model: &str,
+        ) -> Result<Completion, AgentError> {
+            if messages.last().is_some_and(|m| m.content.contains("You already called")) {
+                return Ok(Completion { content: Some("3".into()), ..Default::default() });
+            }
+            let mut calls = self.calls.lock().unwrap();
+            *calls += 1;
+            let id = format!("call_{calls}");
+            let arguments = if calls.is_multiple_of(2) { r#"{"b":2,"a":1}"# } else { r#"{"a":1,"b":2}"# };
+            Ok(Completion {
+                tool_calls: vec![mini_agent::ToolCall {
+                    id: id.clone(),
+                    name: "add_numbers".into(),
+                    args: serde_json::from_str(arguments).unwrap(),
+                }],
+                raw_tool_calls: Some(json!([{
+                    "id": id,
+                    "type": "function",
+                    "function": { "name": "add_numbers", "arguments": arguments }
+                }])),
+                ..Default::default()
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn repeated_identical_calls_are_cut_off() {
+        let provider = RepeatingProvider { calls: std::sync::Mutex::new(0) };
+        let mut agent = Agent::new(Box::new(provider), "m").with_max_steps(10).with_loop_detection(2);
+        agent.add_tool(AddNumbersTool);
+
+        assert_eq!(agent.run("add 1 and 2").await.unwrap(), "3");
+        // Two real executions, then the third repeat got the note instead
+        assert_eq!(agent.tool_invocations().len(), 2);
+        let note = &agent.history[agent.history.len() - 2];
+        assert_eq!(note.role, mini_agent::Role::Tool);
+        assert_eq!(note.tool_call_id.as_deref(), Some("call_3"));
+    }
+
+    #[tokio::test]
+    async fn loop_detection_is_off_by_default() {
+        let provider = RepeatingProvider { calls: std::sync::Mutex::new(0) };
+        let mut agent = Agent::new(Box::new(provider), "m").with_max_steps(5);
+        agent.add_tool(AddNumbersTool);
+
+        assert!(matches!(agent.run("add 1 and 2").await.unwrap_err(), AgentError::MaxIterations));
+        assert_eq!(agent.tool_invocations().len(), 5);
+    }
+
+    // ── Mock provider that reports token usage ────────────────────────────
+
+    struct UsageProvider;
+
+    #[async_trait]
+    impl LlmProvider for UsageProvider {
+        fn provider_name(&self) -> &str { "UsageMock" }
+
+        async fn complete(
+            &self,
+            _messages: &[Message],
+            _tools: &[&dyn Tool],
+            _model: &str,
+        ) -> Result<Completion, AgentError> {
+            Ok(Completion {
+                content: Some("done".into()),
+                usage: Some(mini_agent::Usage { prompt_tokens: 1000, completion_tokens: 500, total_tokens: 1500 }),
+                ..Default::default()
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn estimated_cost_prices_accumulated_usage() {
+        let mut agent = Agent::new(Box::new(UsageProvider), "gpt-4o-2024-08-06");
+        agent.run("one").await.unwrap();
+        agent.run("two").await.unwrap();
+
+        assert_eq!(agent.usage().prompt_tokens, 2000);
+        assert_eq!(agent.usage().completion_tokens, 1000);
+        // gpt-4o: $0.0025 in, $0.01 out per 1K → 2 × 0.0025 + 1 × 0.01
+        let cost = agent.estimated_cost().unwrap();
+        assert!((cost - 0.015).abs() < 1e-9, "cost = {cost}");
+
+        let prices = mini_agent::PriceTable::empty().with_price("gpt-4o", 0.001, 0.002);
+        let mut agent = Agent::new(Box::new(UsageProvider), "openai/gpt-4o").with_price_table(prices);
+        agent.run("one").await.unwrap();
+        let cost = agent.estimated_cost().unwrap();
+        assert!((cost - 0.002).abs() < 1e-9, "cost = {cost}");
+
+        let mut agent = Agent::new(Box::new(UsageProvider), "my-local-model");
+        agent.run("one").await.unwrap();
+        assert_eq!(agent.estimated_cost(), None);
+    }
+
+    // ── Mock provider whose backend changes between calls ────────────────
+
+    struct FingerprintProvider {
+        calls: std::sync::Mutex<usize>,
+    }
+
+    #[async_trait]
+    impl LlmProvider for FingerprintProvider {
+        fn provider_name(&self) -> &str { "FingerprintMock" }
+
+        async fn complete(
+            &self,
+            _messages: &[Message],
+            _tools: &[&dyn Tool],
+            _model: &str,
+        ) -> Result<Completion, AgentError> {
+            let mut calls = self.calls.lock().unwrap();
+            *calls += 1;
+            let first = *calls == 1;
+            let tool_calls = if first {
+                vec![mini_agent::ToolCall { id: "call_1".into(), name: "add_numbers".into(), args: json!({ "a": 1, "b": 2 }) }]
+            } else {
+                vec![]
+            };
+            Ok(Completion {
+                content: (!first).then(|| "3".to_string()),
+                tool_calls,
+                raw_tool_calls: first.then(|| json!([{
+                    "id": "call_1",
+                    "type": "function",
+                    "function": { "name": "add_numbers", "arguments": "{\"a\":1,\"b\":2}" }
+                }])),
+                system_fingerprint: Some(format!("fp_{calls}")),
+                ..Default::default()
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn fingerprints_are_recorded_per_completion() {
+        let provider = FingerprintProvider { calls: std::sync::Mutex::new(0) };
+        let observer = std::sync::Arc::new(RecordingObserver::default());
+        let mut agent = Agent::new(Box::new(provider), "m").with_observer(observer.clone());
+        agent.add_tool(AddNumbersTool);
+
+        assert_eq!(agent.run("1 + 2?").await.unwrap(), "3");
+        assert_eq!(agent.fingerprints(), vec!["fp_1".to_string(), "fp_2".to_string()]);
+        assert!(observer.events.lock().unwrap().contains(&"fingerprint:1:fp_1->fp_2".to_string()));
+    }
+
+    #[test]
+    fn export_tool_schemas_matches_request_tools() {
+        let mut agent = Agent::new(Box::new(MockProvider { response: "ok".into() }), "m");
+        agent.add_tool(MultiplyNumbersTool);
+        agent.add_tool(AddNumbersTool);
+
+        let expected = mini_agent::providers::build_openai_tools(&[&MultiplyNumbersTool, &AddNumbersTool]);
+        assert_eq!(agent.export_tool_schemas(), serde_json::Value::Array(expected.clone()));
+
+        let lines: Vec<serde_json::Value> = agent
+            .export_tool_schemas_jsonl()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines, expected);
+
+        // Sorted tools are exported in the order they are sent
+        let agent = agent.with_sorted_tools(true);
+        assert_eq!(agent.export_tool_schemas()[0]["function"]["name"], "add_numbers");
+    }
+
+    // ── Tool that renders its CSV output as a markdown table ──────────────
+
+    struct CsvTool;
+
+    #[async_trait]
+    impl Tool for CsvTool {
+        fn name(&self) -> &'static str { "csv" }
+        fn description(&self) -> &'static str { "Returns a small CSV table" }
+        fn parameters_schema(&self) -> serde_json::Value { json!({ "type": "object" }) }
+        async fn execute(&self, _args: serde_json::Value) -> Result<String, AgentError> {
+            Ok("city,temp\nOslo,4\nRome,18".into())
+        }
+        fn format_for_model(&self, output: &mini_agent::ToolOutput) -> String {
+            let mini_agent::ToolOutput::Text(csv) = output else { return String::new() };
+            let mut rows = csv.lines().map(|line| format!("| {} |", line.replace(',', " | ")));
+            let header = rows.next().unwrap_or_default();
+            let separator = format!("|{}", "---|".repeat(header.matches(" | ").count() + 1));
+            std::iter::once(header).chain(std::iter::once(separator)).chain(rows).collect::<Vec<_>>().join("\n")
+        }
+    }
+
+    #[tokio::test]
+    async fn tool_formats_its_result_for_the_model() {
+        let mut agent = Agent::new(Box::new(MockProvider { response: "ok".into() }), "m");
+        agent.add_tool(CsvTool);
+        let call = mini_agent::ToolCall { id: "call_1".into(), name: "csv".into(), args: json!({}) };
+
+        let returned = agent.execute_call(&call).await.unwrap();
+        let expected = "| city | temp |\n|---|---|\n| Oslo | 4 |\n| Rome | 18 |";
+        assert_eq!(returned, expected);
+        assert_eq!(agent.history.last().unwrap().content, expected);
+    }
+
+    // ── Observer that records every event it sees ─────────────────────────
+
+    #[derive(Default)]
+    struct RecordingObserver {
+        events: std::sync::Mutex<Vec<String>>,
+    }
+
+    impl mini_agent::AgentObserver for RecordingObserver {
+        fn on_llm_request(&self, step: usize, _messages: &[Message]) {
+            self.events.lock().unwrap().push(format!("llm:{step}"));
+        }
+        fn on_tool_start(&self, step: usize, call: &mini_agent::ToolCall) {
+            self.events.lock().unwrap().push(format!("tool:{step}:{}", call.name));
+        }
+        fn on_fingerprint_change(&self, step: usize, previous: &str, current: &str) {
+            self.events.lock().unwrap().push(format!("fingerprint:{step}:{previous}->{current}"));
+        }
+    }
+
+    #[tokio::test]
+    async fn observer_sees_step_indices_reset_per_run() {
+        let call_count = std::sync::Arc::new(std::sync::Mutex::new(0));
+        let observer = std::sync::Arc::new(RecordingObserver::default());
+        let mut agent = Agent::new(Box::new(ToolCallingProvider { call_count: call_count.clone() }), "m")
+            .with_observer(observer.clone());
+        agent.add_tool(AddNumbersTool);
+
+        agent.run("10 + 20?").await.unwrap();
+        assert_eq!(*observer.events.lock().unwrap(), ["llm:0", "tool:0:add_numbers", "llm:1"]);
+
+        observer.events.lock().unwrap().clear();
+        *call_count.lock().unwrap() = 0;
+        agent.run("again").await.unwrap();
+        assert_eq!(*observer.events.lock().unwrap(), ["llm:0", "tool:0:add_numbers", "llm:1"]);
+    }
+
+    // ── Mock provider that needs a second try to produce JSON ─────────────
+
+    struct ProseThenJsonProvider {
+        requests: std::sync::Arc<std::sync::Mutex<Vec<Vec<Message>>>>,
+        delay: std::time::Duration,
+    }
+
+    #[async_trait]
+    impl LlmProvider for ProseThenJsonProvider {
+        fn provider_name(&self) -> &str { "ProseThenJsonMock" }
+
+        async fn complete(
+            &self,
+            messages: &[Message],
+            _tools: &[&dyn Tool],
+            _model: &str,
+        ) -> Result<Completion, AgentError> {
+            tokio::time::sleep(self.delay).await;
+            let mut requests = self.requests.lock().unwrap();
+            requests.push(messages.to_vec());
+            let content = if requests.len() == 1 {
+                "Oslo is chilly today, around four degrees."
+            } else {
+                "Here you go:\n```json\n{ \"city\": \"Oslo\", \"temp\": 4 }\n```"
+            };
+            Ok(Completion { content: Some(content.into()), ..Default::default() })
+        }
+    }
+
+    #[tokio::test]
+    async fn json_output_extracts_json_from_prose() {
+        let provider = MockProvider {
+            response: "Sure! Here is the data: {\"city\": \"Oslo\", \"temp\": 4} Let me know if you need more.".into(),
+        };
+        let mut agent = Agent::new(Box::new(provider), "m").with_json_output(true);
+
+        let answer = agent.run("weather in Oslo as JSON").await.unwrap();
+        let parsed: serde_json::Value = serde_json::from_str(&answer).unwrap();
+        assert_eq!(parsed, json!({ "city": "Oslo", "temp": 4 }));
+    }
+
+    #[tokio::test]
+    async fn json_output_retries_once_without_json() {
+        let requests = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+        let provider = ProseThenJsonProvider { requests: requests.clone(), delay: std::time::Duration::ZERO };
+        let mut agent = Agent::new(Box::new(provider), "m").with_json_output(true);
+
+        let answer = agent.run("weather in Oslo").await.unwrap();
+        assert_eq!(serde_json::from_str::<serde_json::Value>(&answer).unwrap()["temp"], 4);
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+        assert!(requests[0][0].content.contains("Respond with only valid JSON"));
+        assert!(requests[1].last().unwrap().content.contains("not valid JSON"));
+    }
+
+    #[tokio::test]
+    async fn json_retry_is_part_of_the_same_turn() {
+        let provider = ProseThenJsonProvider {
+            requests: std::sync::Arc::new(std::sync::Mutex::new(vec![])),
+            delay: std::time::Duration::ZERO,
+        };
+        let mut agent = Agent::new(Box::new(provider), "m").with_json_output(true);
+
+        let outcome = agent.run_detailed("weather in Oslo").await.unwrap();
+        // Both attempts are traced
+        assert_eq!(outcome.steps.len(), 2);
+        // user, prose reply, retry note, JSON reply
+        assert_eq!(agent.pop_last_turn().len(), 4);
+        assert!(agent.history.is_empty());
+    }
+
+    #[tokio::test]
+    async fn json_retry_shares_the_run_timeout() {
+        let provider = ProseThenJsonProvider {
+            requests: std::sync::Arc::new(std::sync::Mutex::new(vec![])),
+            delay: std::time::Duration::from_millis(60),
+        };
+        let mut agent = Agent::new(Box::new(provider), "m")
+            .with_json_output(true)
+            .with_run_timeout(std::time::Duration::from_millis(100));
+
+        // Each attempt fits in the timeout, both together don't
+        let err = agent.run("weather in Oslo").await.unwrap_err();
+        assert!(matches!(err, AgentError::Timeout { .. }), "{err:?}");
+    }
+
+    #[test]
+    fn heal_history_answers_dangling_tool_calls() {
+        let mut agent = Agent::new(Box::new(MockProvider { response: "ok".into() }), "m");
+        agent.history = vec![
+            Message::user("add and multiply"),
+            Message::assistant_with_tools("", json!([
+                { "id": "call_1", "type": "function", "function": { "name": "add_numbers", "arguments": "{}" } },
+                { "id": "call_2", "type": "function", "function": { "name": "multiply_numbers", "arguments": "{}" } },
+            ])),
+            Message::tool("call_1", "3"),
+        ];
+
+        assert_eq!(agent.heal_history(), 1);
+        assert_eq!(agent.history.len(), 4);
+        assert_eq!(agent.history[3].role, mini_agent::Role::Tool);
+        assert_eq!(agent.history[3].tool_call_id.as_deref(), Some("call_2"));
+        assert!(agent.history[3].content.starts_with("Error:"));
+
+        // A healthy history is left alone
+        assert_eq!(agent.heal_history(), 0);
+        assert_eq!(agent.history.len(), 4);
+    }
+
+    #[tokio::test]
+    async fn assistant_messages_carry_the_agent_name() {
+        let call_count = std::sync::Arc::new(std::sync::Mutex::new(0));
+        let mut agent = Agent::new(Box::new(ToolCallingProvider { call_count }), "m").with_name("calc_bot");
+        agent.add_tool(AddNumbersTool);
+        agent.run("10 + 20?").await.unwrap();
+
+        let assistant: Vec<&Message> =
+            agent.history.iter().filter(|m| m.role == mini_agent::Role::Assistant).collect();
+        assert_eq!(assistant.len(), 2);
+        assert!(assistant.iter().all(|m| m.name.as_deref() == Some("calc_bot")));
+        // Only the agent's own turns are tagged
+        assert!(agent.history[0].name.is_none());
+
+        let sent = mini_agent::providers::build_openai_messages(&agent.history);
+        assert_eq!(sent.last().unwrap()["name"], "calc_bot");
+        assert!(sent[0].get("name").is_none());
+    }
+
+    #[tokio::test]
+    async fn run_with_context_sends_docs_without_keeping_them() {
+        let requests = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+        let mut agent = Agent::new(Box::new(ProseThenJsonProvider { requests: requests.clone(), delay: std::time::Duration::ZERO }), "m");
+
+        let docs = vec!["Oslo averages 4°C in April.".to_string(), "Rome averages 15°C in April.".to_string()];
+        agent.run_with_context("How cold is Oslo in April?", docs).await.unwrap();
+
+        let sent = requests.lock().unwrap()[0].clone();
+        let docs_at = sent.iter().position(|m| m.content.contains("[1] Oslo averages 4°C")).unwrap();
+        assert!(sent[docs_at].content.contains("[2] Rome"));
+        assert_eq!(sent[docs_at + 1].content, "How cold is Oslo in April?");
+        assert!(agent.history.iter().all(|m| !m.content.contains("averages")));
+
+        // The next plain run doesn't see them either
+        agent.run("And Rome?").await.unwrap();
+        assert!(requests.lock().unwrap()[1].iter().all(|m| !m.content.contains("averages")));
+    }
+
+    #[tokio::test]
+    async fn run_with_context_clears_docs_when_cancelled() {
+        let requests = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+        let provider = ProseThenJsonProvider { requests: requests.clone(), delay: std::time::Duration::from_millis(200) };
+        let mut agent = Agent::new(Box::new(provider), "m");
+
+        let docs = vec!["Oslo averages 4°C in April.".to_string()];
+        let cancelled = tokio::time::timeout(
+            std::time::Duration::from_millis(20),
+            agent.run_with_context("How cold is Oslo in April?", docs),
+        )
+        .await;
+        assert!(cancelled.is_err());
+
+        agent.history.clear();
+        agent.run("And Rome?").await.unwrap();
+        assert!(requests.lock().unwrap().last().unwrap().iter().all(|m| !m.content.contains("averages")));
+    }
+
+    #[tokio::test]
+    async fn send_filter_omits_messages_from_request_only() {
+        let requests = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+        let mut agent = Agent::new(Box::new(ProseThenJsonProvider { requests: requests.clone(), delay: std::time::Duration::ZERO }), "m")
+            .with_send_filter(Box::new(|m: &Message| !m.content.starts_with("[internal]")));
+        agent.history.push(Message::user("[internal] customer id 4711"));
+
+        agent.run("What's the weather?").await.unwrap();
+
+        let sent = requests.lock().unwrap()[0].clone();
+        assert!(sent.iter().all(|m| !m.content.contains("4711")));
+        assert!(sent.iter().any(|m| m.content == "What's the weather?"));
+        assert_eq!(agent.history[0].content, "[internal] customer id 4711");
+    }
+
+    #[tokio::test]
+    async fn send_filter_may_not_split_a_tool_call_group() {
+        let mut agent = Agent::new(Box::new(MockProvider { response: "ok".into() }), "m")
+            .with_send_filter(Box::new(|m: &Message| m.role != mini_agent::Role::Tool));
+        agent.history = vec![
+            Message::user("1 + 2?"),
+            Message::assistant_with_tools("", json!([
+                { "id": "call_1", "type": "function", "function": { "name": "add_numbers", "arguments": "{}" } }
+            ])),
+            Message::tool("call_1", "3"),
+        ];
+
+        let err = agent.run("and 2 + 2?").await.unwrap_err();
+        assert!(matches!(err, AgentError::InvalidHistory(msg) if msg.contains("tool result 2")));
+    }
+
+    // ── Mock provider that records the tool names it was offered ──
+
+    struct ToolNamesProvider {
+        offered: std::sync::Arc<std::sync::Mutex<Vec<Vec<String>>>>,
+    }
+
+    #[async_trait]
+    impl LlmProvider for ToolNamesProvider {
+        fn provider_name(&self) -> &str { "ToolNamesMock" }
+
+        async fn complete(
+            &self,
+            _messages: &[Message],
+            tools: &[&dyn Tool],
+            _model: &str,
+        ) -> Result<Completion, AgentError> {
+            self.offered.lock().unwrap().push(tools.iter().map(|t| t.name().to_string()).collect());
+            Ok(Completion { content: Some("ok".into()), ..Default::default() })
+        }
+    }
+
+    #[tokio::test]
+    async fn tool_selector_limits_tools_sent_for_run() {
+        let offered = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let provider = ToolNamesProvider { offered: offered.clone() };
+        let mut agent = Agent::new(Box::new(provider), "test-model").with_tool_selector(Box::new(
+            |input, tools| {
+                let wanted = if input.contains("multiply") { "multiply_numbers" } else { "add_numbers" };
+                tools.iter().position(|t| t.name() == wanted).into_iter().collect()
+            },
+        ));
+        agent.add_tool(AddNumbersTool);
+        agent.add_tool(MultiplyNumbersTool);
+
+        agent.run("please add 2 and 3").await.unwrap();
+        agent.run("now multiply them").await.unwrap();
+
+        assert_eq!(*offered.lock().unwrap(), vec![vec!["add_numbers"], vec!["multiply_numbers"]]);
+    }
+
+    // ── Mock embedder: one dimension per keyword ──
+
+    struct KeywordEmbedder;
+
+    #[async_trait]
+    impl mini_agent::Embedder for KeywordEmbedder {
+        async fn embed(&self, texts: &[&str]) -> Result<Vec<Vec<f32>>, AgentError> {
+            Ok(texts
+                .iter()
+                .map(|text| {
+                    let text = text.to_lowercase();
+                    ["add", "multipl", "joke"].iter().map(|k| if text.contains(k) { 1.0 } else { 0.0 }).collect()
+                })
+                .collect())
+        }
+    }
+
+    #[tokio::test]
+    async fn semantic_tool_selector_offers_most_similar_tool() {
+        let joke = mini_agent::JokeTool;
+        let tools: [&dyn Tool; 3] = [&AddNumbersTool, &MultiplyNumbersTool, &joke];
+        let selector = mini_agent::SemanticToolSelector::new(Box::new(KeywordEmbedder), &tools, 1).await.unwrap();
+
+        let offered = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let provider = ToolNamesProvider { offered: offered.clone() };
+        let mut agent = Agent::new(Box::new(provider), "test-model").with_semantic_tool_selector(selector);
+        agent.add_tool(AddNumbersTool);
+        agent.add_tool(MultiplyNumbersTool);
+        agent.add_tool(mini_agent::JokeTool);
+
+        agent.run("Multiply 6 by 7").await.unwrap();
+        agent.run("Tell me a joke").await.unwrap();
+
+        assert_eq!(*offered.lock().unwrap(), vec![vec!["multiply_numbers"], vec!["get_joke"]]);
+    }
+
+    #[tokio::test]
+    async fn arg_coercion_converts_numeric_strings() {
+        let requests = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+        let provider = RecordingProvider {
+            requests: requests.clone(),
+            first_reply: single_call_reply("call_1", "add_numbers", json!({ "a": "10", "b": 20 })),
+        };
+        let mut agent = Agent::new(Box::new(provider), "test-model").with_arg_coercion(true);
+        agent.add_tool(AddNumbersTool);
+        agent.run("Add 10 and 20").await.unwrap();
+
+        let second = requests.lock().unwrap()[1].clone();
+        let tool_msg = second.iter().find(|m| m.role == mini_agent::Role::Tool).unwrap();
+        assert_eq!(tool_msg.content, "30");
+        // The call is recorded as the model sent it
+        assert_eq!(agent.tool_invocations()[0].args, json!({ "a": "10", "b": 20 }));
+    }
+
+    #[tokio::test]
+    async fn without_arg_coercion_mistyped_args_fail() {
+        let requests = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+        let provider = RecordingProvider {
+            requests: requests.clone(),
+            first_reply: single_call_reply("call_1", "add_numbers", json!({ "a": "10", "b": 20 })),
+        };
+        let mut agent = Agent::new(Box::new(provider), "test-model");
+        agent.add_tool(AddNumbersTool);
+
+        let err = agent.run("Add 10 and 20").await.unwrap_err();
+        assert!(matches!(err, AgentError::ToolError(msg) if msg.contains("'a'")));
+    }
+
+    #[tokio::test]
+    async fn tool_postprocessor_rewrites_tool_message() {
+        let provider = ToolCallingProvider {
+            call_count: std::sync::Arc::new(std::sync::Mutex::new(0)),
+        };
+        let mut agent = Agent::new(Box::new(provider), "test-model").with_tool_postprocessor(Box::new(
+            |name: &str, output: String| {
+                let name = name.to_string();
+                Box::pin(async move {
+                    let truncated: String = output.chars().take(1).collect();
+                    format!("[{name}] {truncated}…")
+                })
+            },
+        ));
+        agent.add_tool(AddNumbersTool);
+
+        agent.run("Add 10 and 20").await.unwrap();
+
+        let tool_msg = agent.history.iter().find(|m| m.role == mini_agent::Role::Tool).unwrap();
+        assert_eq!(tool_msg.content, "[add_numbers] 3…");
+        assert_eq!(agent.tool_invocations()[0].result.as_deref(), Some("[add_numbers] 3…"));
+    }
+
+    #[tokio::test]
+    async fn answer_delimiter_returns_only_final_answer() {
+        let reply = "Thought: the user wants a sum.\nFinal Answer: draft\nThought: check again.\nFinal Answer:  30 \n";
+        let provider = MockProvider { response: reply.into() };
+        let mut agent = Agent::new(Box::new(provider), "test-model").with_answer_delimiter("Final Answer:");
+
+        assert_eq!(agent.run("Add 10 and 20").await.unwrap(), "30");
+        assert_eq!(agent.history.last().unwrap().content, reply);
+    }
+
+    #[tokio::test]
+    async fn answer_delimiter_missing_returns_whole_reply() {
+        let provider = MockProvider { response: "just 30".into() };
+        let mut agent = Agent::new(Box::new(provider), "test-model").with_answer_delimiter("Final Answer:");
+
+        assert_eq!(agent.run("Add 10 and 20").await.unwrap(), "just 30");
+    }
+
+    #[tokio::test]
+    async fn ask_user_tool_feeds_reply_into_loop() {
+        let requests = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+        let provider = RecordingProvider {
+            requests: requests.clone(),
+            first_reply: single_call_reply("call_1", "ask_user", json!({ "question": "Which city?" })),
+        };
+        let questions = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+        let asked = questions.clone();
+        let mut agent = Agent::new(Box::new(provider), "test-model");
+        agent.add_tool(mini_agent::AskUserTool::new(move |question: &str| {
+            asked.lock().unwrap().push(question.to_string());
+            Box::pin(async { "Paris".to_string() })
+        }));
+
+        assert_eq!(agent.run("What's the weather?").await.unwrap(), "done");
+
+        assert_eq!(*questions.lock().unwrap(), vec!["Which city?"]);
+        let second = requests.lock().unwrap()[1].clone();
+        let tool_msg = second.iter().find(|m| m.role == mini_agent::Role::Tool).unwrap();
+        assert_eq!(tool_msg.tool_call_id.as_deref(), Some("call_1"));
+        assert_eq!(tool_msg.content, "Paris");
+    }
+
+    #[test]
+    fn validate_history_reports_unregistered_tools() {
+        let provider = MockProvider { response: "ok".into() };
+        let mut agent = Agent::new(Box::new(provider), "test-model");
+        agent.add_tool(AddNumbersTool);
+        agent.history = vec![
+            Message::user("add, then search"),
+            Message::assistant_with_tools("", json!([
+                { "id": "call_1", "type": "function", "function": { "name": "add_numbers", "arguments": "{\"a\":1,\"b\":2}" } },
+                { "id": "call_2", "type": "function", "function": { "name": "web_search", "arguments": "{}" } }
+            ])),
+            Message::tool("call_1", "3"),
+            Message::tool("call_2", "results"),
+            Message::assistant_with_tools("", json!([
+                { "id": "call_3", "type": "function", "function": { "name": "web_search", "arguments": "{}" } }
+            ])),
+        ];
+
+        let err = agent.validate_history().unwrap_err();
+        assert!(
+            matches!(&err, AgentError::InvalidHistory(msg) if msg.contains("'web_search' (message 1)") && !msg.contains("add_numbers")),
+            "{err}"
+        );
+
+        agent.history.truncate(1);
+        assert!(agent.validate_history().is_ok());
+    }
+
+    // ── Mock provider that counts its calls ──
+
+    struct CountingProvider {
+        calls: std::sync::Arc<std::sync::Mutex<usize>>,
+        fails: bool,
+    }
+
+    #[async_trait]
+    impl LlmProvider for CountingProvider {
+        fn provider_name(&self) -> &str { "CountingMock" }
+
+        async fn complete(
+            &self,
+            _messages: &[Message],
+            _tools: &[&dyn Tool],
+            _model: &str,
+        ) -> Result<Completion, AgentError> {
+            *self.calls.lock().unwrap() += 1;
+            if self.fails {
+                return Err(AgentError::InvalidResponse("OpenAI 401: invalid api key".into()));
+            }
+            Ok(Completion { content: Some("ok".into()), ..Default::default() })
+        }
+    }
+
+    type CallCounter = std::sync::Arc<std::sync::Mutex<usize>>;
+
+    fn weighted_balancer(weights: [u32; 3]) -> (mini_agent::LoadBalancedProvider, Vec<CallCounter>) {
+        let counters: Vec<CallCounter> = weights.iter().map(|_| Default::default()).collect();
+        let mut balancer = mini_agent::LoadBalancedProvider::new();
+        for (weight, calls) in weights.iter().zip(&counters) {
+            balancer = balancer.with_provider(Box::new(CountingProvider { calls: calls.clone(), fails: false }), *weight);
+        }
+        (balancer, counters)
+    }
+
+    #[tokio::test]
+    async fn load_balancer_picks_in_proportion_to_weights() {
+        let (balancer, counters) = weighted_balancer([6, 3, 1]);
+        let balancer = balancer.with_seed(7);
+
+        for _ in 0..2000 {
+            balancer.complete(&[Message::user("hi")], &[], "").await.unwrap();
+        }
+
+        let counts: Vec<usize> = counters.iter().map(|c| *c.lock().unwrap()).collect();
+        assert_eq!(counts.iter().sum::<usize>(), 2000);
+        for (count, expected) in counts.iter().zip([1200, 600, 200]) {
+            assert!(count.abs_diff(expected) < 100, "{counts:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn load_balancer_round_robin_follows_weights_exactly() {
+        let (balancer, counters) = weighted_balancer([2, 0, 1]);
+        let balancer = balancer.with_round_robin();
+
+        for _ in 0..9 {
+            balancer.complete(&[Message::user("hi")], &[], "").await.unwrap();
+        }
+
+        let counts: Vec<usize> = counters.iter().map(|c| *c.lock().unwrap()).collect();
+        assert_eq!(counts, vec![6, 0, 3]);
+    }
+
+    #[tokio::test]
+    async fn load_balancer_without_weights_is_an_error() {
+        let (balancer, _) = weighted_balancer([0, 0, 0]);
+        let err = balancer.complete(&[Message::user("hi")], &[], "").await.unwrap_err();
+        assert!(matches!(err, AgentError::ProviderError(_)));
+    }
+
+    #[cfg(feature = "testing")]
+    #[tokio::test]
+    async fn load_balancer_skips_a_failing_provider_until_cooldown() {
+        let clock = std::sync::Arc::new(mini_agent::MockClock::new());
+        let dead_calls = CallCounter::default();
+        let live_calls = CallCounter::default();
+        let balancer = mini_agent::LoadBalancedProvider::new()
+            .with_provider(Box::new(CountingProvider { calls: dead_calls.clone(), fails: true }), 1)
+            .with_provider(Box::new(CountingProvider { calls: live_calls.clone(), fails: false }), 1)
+            .with_round_robin()
+            .with_circuit_breaker(2, std::time::Duration::from_secs(30))
+            .with_clock(clock.clone());
+
+        for _ in 0..10 {
+            let _ = balancer.complete(&[Message::user("hi")], &[], "").await;
+        }
+        // Two failures trip the dead provider; the rest go to the live one
+        assert_eq!(*dead_calls.lock().unwrap(), 2);
+        assert_eq!(*live_calls.lock().unwrap(), 8);
+
+        // Still cooling down just before the 30s are up
+        clock.advance(std::time::Duration::from_secs(29));
+        let _ = balancer.complete(&[Message::user("hi")], &[], "").await;
+        assert_eq!(*dead_calls.lock().unwrap(), 2);
+
+        // After the cooldown it is tried again, and trips on the next failure
+        clock.advance(std::time::Duration::from_secs(1));
+        for _ in 0..4 {
+            let _ = balancer.complete(&[Message::user("hi")], &[], "").await;
+        }
+        assert_eq!(*dead_calls.lock().unwrap(), 3);
+        assert_eq!(*live_calls.lock().unwrap(), 12);
+    }
+
+    #[tokio::test]
+    async fn compact_system_prompt_replaces_full_prompt_past_threshold() {
+        let requests = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+        let provider = RecordingProvider {
+            requests: requests.clone(),
+            first_reply: Completion { content: Some("hi".into()), ..Default::default() },
+        };
+        let mut agent = Agent::new(Box::new(provider), "test-model")
+            .with_system_prompt("FULL PROMPT with every policy spelled out")
+            .with_compact_system_prompt("COMPACT PROMPT")
+            .with_compact_prompt_threshold(200);
+
+        agent.run("hello").await.unwrap();
+        agent.run(&"a long document ".repeat(100)).await.unwrap();
+
+        let requests = requests.lock().unwrap();
+        assert!(requests[0][0].content.contains("FULL PROMPT"));
+        assert!(requests[1][0].content.contains("COMPACT PROMPT"));
+        assert!(!requests[1][0].content.contains("FULL PROMPT"));
+        // The agent's configured prompt is untouched
+        assert!(agent.system_prompt.starts_with("FULL PROMPT"));
+    }
+
+    #[tokio::test]
+    async fn run_capturing_returns_messages_added_by_the_run() {
+        let provider = ToolCallingProvider {
+            call_count: std::sync::Arc::new(std::sync::Mutex::new(0)),
+        };
+        let mut agent = Agent::new(Box::new(provider), "test-model");
+        agent.add_tool(AddNumbersTool);
+        agent.history.push(Message::user("earlier question"));
+        agent.history.push(Message::assistant("earlier answer"));
+
+        let (answer, added) = agent.run_capturing("Add 10 and 20").await.unwrap();
+
+        assert_eq!(answer, "The answer is 30");
+        let roles: Vec<_> = added.iter().map(|m| m.role).collect();
+        assert_eq!(
+            roles,
+            vec![mini_agent::Role::User, mini_agent::Role::Assistant, mini_agent::Role::Tool, mini_agent::Role::Assistant]
+        );
+        assert_eq!(added[0].content, "Add 10 and 20");
+        assert_eq!(added[2].content, "30");
+        assert_eq!(added[3].content, "The answer is 30");
+        assert_eq!(agent.history.len(), 6);
+    }
+
+    #[tokio::test]
+    async fn oversized_tool_args_are_rejected_before_execution() {
+        let requests = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+        let huge = "x".repeat(10_000);
+        let provider = RecordingProvider {
+            requests: requests.clone(),
+            first_reply: single_call_reply("call_1", "add_numbers", json!({ "a": 1, "b": 2, "pad": huge })),
+        };
+        let mut agent = Agent::new(Box::new(provider), "test-model").with_max_tool_arg_bytes(1024);
+        agent.add_tool(AddNumbersTool);
+
+        assert_eq!(agent.run("Add 1 and 2").await.unwrap(), "done");
+
+        let invocations = agent.tool_invocations();
+        assert_eq!(invocations.len(), 1);
+        assert!(invocations[0].result.is_none(), "the tool never ran");
+        let second = requests.lock().unwrap()[1].clone();
+        let tool_msg = second.iter().find(|m| m.role == mini_agent::Role::Tool).unwrap();
+        assert_eq!(tool_msg.tool_call_id.as_deref(), Some("call_1"));
+        assert!(tool_msg.content.starts_with("Error: ") && tool_msg.content.contains("1024-byte limit"));
+    }
+}
+
+// ─────────────────────────────────────────────────────────────────────────────
+// Provider helper tests (parse_openai_completion, build_openai_messages, etc.)
+// ─────────────────────────────────────────────────────────────────────────────
+
+#[cfg(test)]
+mod provider_helper_tests {
+    use mini_agent::providers::{build_openai_messages, build_openai_tools, parse_openai_completion, validate_tool_call_ids};
+    use mini_agent::{AgentError, Message, Role, Tool};
+    use async_trait::async_trait;
+    use serde_json::{json, Value};
+
+    struct DummyTool;
+
+    #[async_trait]
+    impl Tool for DummyTool {
+        fn name(&self) -> &'static str { "dummy_tool" }
+        fn description(&self) -> &'static str { "A dummy tool for testing" }
+        fn parameters_schema(&self) -> Value {
+            json!({ "type": "object", "properties": { "x": { "type": "integer" } }, "required": ["x"] })
+        }
+        async fn execute(&self, _args: Value) -> Result<String, AgentError> {
+            Ok("dummy_result".to_string())
+        }
+    }
+
+    // ── build_openai_messages ─────────────────────────────────────────────
+
+    #[test]
+    fn build_messages_user_only() {
+        let messages = vec![Message::user("Hello")];
+        let result = build_openai_messages(&messages);
+        assert_eq!(result.len(), 1);
+        assert_eq!(result[0]["role"], "user");
+        assert_eq!(result[0]["content"], "Hello");
+    }
+
+    #[test]
+    fn build_messages_includes_tool_call_id() {
+        let messages = vec![Message {
+            role: Role::Tool,
+            content: "result_value".to_string(),
+            tool_call_id: Some("call_xyz".to_string()),
+            tool_calls: None,
+            ..Default::default()
+        }];
+        let result = build_openai_messages(&messages);
+        assert_eq!(result[0]["tool_call_id"], "call_xyz");
+    }
+
+    #[test]
+    fn build_messages_omits_null_tool_calls() {
+        let messages = vec![Message::user("hi")];
+        let result = build_openai_messages(&messages);
+        assert!(result[0].get("tool_calls").is_none());
+    }
+
+    #[test]
+    fn build_messages_preserves_order() {
+        let messages = vec![
+            Message::user("first"),
+            Message::assistant("second"),
+            Message::user("third"),
+        ];
+        let result = build_openai_messages(&messages);
+        assert_eq!(result[0]["content"], "first");
+        assert_eq!(result[1]["content"], "second");
+        assert_eq!(result[2]["content"], "third");
+    }
+
+    // ── build_openai_tools ────────────────────────────────────────────────
+
+    #[test]
+    fn build_tools_correct_shape() {
+        let tool = DummyTool;
+        let tools: Vec<&dyn Tool> = vec![&tool];
+        let result = build_openai_tools(&tools);
+        assert_eq!(result.len(), 1);
+        assert_eq!(result[0]["type"], "function");
+        assert_eq!(result[0]["function"]["name"], "dummy_tool");
+        assert_eq!(result[0]["function"]["description"], "A dummy tool for testing");
+        assert!(result[0]["function"]["parameters"].is_object());
+    }
+
+    #[test]
+    fn build_tools_empty() {
+        let tools: Vec<&dyn Tool> = vec![];
+        let result = build_openai_tools(&tools);
+        assert!(result.is_empty());
+    }
+
+    // ── parse_openai_completion ───────────────────────────────────────────
+
+    #[test]
+    fn parse_completion_text_only() {
+        let json = json!({
+            "choices": [{
+                "message": {
+                    "role": "assistant",
+                    "content": "Hello world"
+                }
+            }]
+        });
+        let completion = parse_openai_completion(&json).unwrap();
+        assert_eq!(completion.content, Some("Hello world".to_string()));
+        assert!(completion.tool_calls.is_empty());
+        assert!(completion.raw_tool_calls.is_none());
+    }
+
+    #[test]
+    fn parse_completion_with_tool_calls() {
+        let json = json!({
+            "choices": [{
+                "message": {
+                    "role": "assistant",
+                    "content": null,
+                    "tool_calls": [{
+                        "id": "call_1",
+                        "type": "function",
+                        "function": {
+                            "name": "add_numbers",
+                            "arguments": "{\"a\":1,\"b\":2}"
+                        }
+                    }]
+                }
+            }]
+        });
+        let completion = parse_openai_completion(&json).unwrap();
+        assert_eq!(completion.tool_calls.len(), 1);
+        assert_eq!(completion.tool_calls[0].name, "add_numbers");
+        assert_eq!(completion.tool_calls[0].id, "call_1");
+        assert_eq!(completion.tool_calls[0].args["a"], 1);
+        assert_eq!(completion.tool_calls[0].args["b"], 2);
+    }
+
+    #[test]
+    fn parse_completion_missing_choices_returns_error() {
+        let json = json!({ "error": "something went wrong" });
+        let result = parse_openai_completion(&json);
+        assert!(result.is_err());
+        match result.unwrap_err() {
+            AgentError::InvalidResponse(msg) => assert!(msg.contains("choices")),
+            _ => panic!("Expected InvalidResponse"),
+        }
+    }
+
+    #[test]
+    fn parse_completion_empty_choices_returns_error() {
+        let json = json!({ "choices": [] });
+        let result = parse_openai_completion(&json);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn parse_completion_bad_args_json_returns_error() {
+        let json = json!({
+            "choices": [{
+                "message": {
+                    "role": "assistant",
+                    "content": null,
+                    "tool_calls": [{
+                        "id": "call_1",
+                        "type": "function",
+                        "function": {
+                            "name": "some_tool",
+                            "arguments": "NOT VALID JSON {{{"
+                        }
+                    }]
+                }
+            }]
+        });
+        let result = parse_openai_completion(&json);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn parse_completion_null_content() {
+        let json = json!({
+            "choices": [{
+                "message": {
+                    "role": "assistant",
+                    "content": null
+                }
+            }]
+        });
+        let completion = parse_openai_completion(&json).unwrap();
+        assert!(completion.content.is_none());
+    }
+
+    #[test]
+    fn parse_completion_falls_back_to_legacy_text() {
+        let json = json!({ "choices": [{ "index": 0, "text": "legacy answer", "finish_reason": "stop" }] });
+        let completion = parse_openai_completion(&json).unwrap();
+        assert_eq!(completion.content.as_deref(), Some("legacy answer"));
+        assert!(completion.tool_calls.is_empty());
+    }
+
+    #[test]
+    fn parse_completion_accepts_delta_in_place_of_message() {
+        let json = json!({ "choices": [{ "delta": { "role": "assistant", "content": "from delta" } }] });
+        let completion = parse_openai_completion(&json).unwrap();
+        assert_eq!(completion.content.as_deref(), Some("from delta"));
+    }
+
+    #[test]
+    fn parse_completion_all_empty_message() {
+        let json = json!({ "choices": [{ "message": { "role": "assistant", "tool_calls": null } }] });
+        let completion = parse_openai_completion(&json).unwrap();
+        assert!(completion.content.is_none());
+        assert!(completion.tool_calls.is_empty());
+        assert!(completion.raw_tool_calls.is_none());
+    }
+
+    #[test]
+    fn parse_completion_choice_without_message_or_text_is_error() {
+        let json = json!({ "choices": [{ "finish_reason": "stop" }] });
+        assert!(matches!(parse_openai_completion(&json), Err(AgentError::InvalidResponse(msg)) if msg.contains("message")));
+    }
+
+    // ── validate_tool_call_ids ────────────────────────────────────────────
+
+    fn assistant_calling(id: &str) -> Message {
+        Message::assistant_with_tools("", json!([{
+            "id": id, "type": "function",
+            "function": { "name": "dummy_tool", "arguments": "{\"x\":1}" }
+        }]))
+    }
+
+    #[test]
+    fn validate_ids_accepts_matching_pair() {
+        let messages = vec![Message::user("q"), assistant_calling("call_1"), Message::tool("call_1", "r")];
+        assert!(validate_tool_call_ids(&messages).is_ok());
+    }
+
+    #[test]
+    fn validate_ids_rejects_mismatched_id() {
+        let messages = vec![Message::user("q"), assistant_calling("call_1"), Message::tool("call_2", "r")];
+        match validate_tool_call_ids(&messages).unwrap_err() {
+            AgentError::InvalidHistory(msg) => {
+                assert!(msg.contains("call_2"));
+                assert!(msg.contains("call_1"));
+            }
+            other => panic!("Expected InvalidHistory, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn validate_ids_rejects_tool_without_assistant_turn() {
+        let messages = vec![Message::user("q"), Message::tool("call_1", "r")];
+        assert!(matches!(validate_tool_call_ids(&messages), Err(AgentError::InvalidHistory(_))));
+    }
+
+
+    #[test]
+    fn parse_reasoning_content() {
+        let json = json!({
+            "choices": [{ "message": {
+                "role": "assistant",
+                "content": "4",
+                "reasoning_content": "2 + 2 is 4"
+            } }]
+        });
+        let completion = parse_openai_completion(&json).unwrap();
+        assert_eq!(completion.reasoning.as_deref(), Some("2 + 2 is 4"));
+
+        let plain = parse_openai_completion(&json!({ "choices": [{ "message": { "content": "4" } }] })).unwrap();
+        assert_eq!(plain.reasoning, None);
+    }
+
+    #[test]
+    fn parse_system_fingerprint() {
+        let json = json!({
+            "system_fingerprint": "fp_44709d6fcb",
+            "choices": [{ "message": { "content": "hi" } }]
+        });
+        let completion = parse_openai_completion(&json).unwrap();
+        assert_eq!(completion.system_fingerprint.as_deref(), Some("fp_44709d6fcb"));
+    }
+}
+
+// ─────────────────────────────────────────────────────────────────────────────
+// Error type tests
+// ─────────────────────────────────────────────────────────────────────────────
+
+#[cfg(test)]
+mod error_tests {
+    use mini_agent::AgentError;
+
+    #[test]
+    fn tool_not_found_display() {
+        let err = AgentError::ToolNotFound("my_tool".to_string());
+        assert!(err.to_string().contains("my_tool"));
+    }
+
+    #[test]
+    fn tool_error_display() {
+        let err = AgentError::ToolError("bad input".to_string());
+        assert!(err.to_string().contains("bad input"));
+    }
+
+    #[test]
+    fn max_iterations_display() {
+        let err = AgentError::MaxIterations;
+        assert!(!err.to_string().is_empty());
+    }
+
+    #[test]
+    fn invalid_response_display() {
+        let err = AgentError::InvalidResponse("missing field".to_string());
+        assert!(err.to_string().contains("missing field"));
+    }
+
+    #[test]
+    fn provider_error_display() {
+        let err = AgentError::ProviderError("timeout".to_string());
+        assert!(err.to_string().contains("timeout"));
+    }
+
+    #[test]
+    fn json_error_from_serde() {
+        let bad_json = "{ not valid }";
+        let serde_err = serde_json::from_str::<serde_json::Value>(bad_json).unwrap_err();
+        let agent_err: AgentError = serde_err.into();
+        assert!(matches!(agent_err, AgentError::Json(_)));
+    }
+
+    #[test]
+    fn timeout_display() {
+        let err = AgentError::Timeout { elapsed: std::time::Duration::from_millis(1500), steps_completed: 2 };
+        let text = err.to_string();
+        assert!(text.contains("1.5s"));
+        assert!(text.contains("2 steps"));
+    }
+
+
+    #[test]
+    fn retryable_classification() {
+        let step = |source| AgentError::StepFailed { provider: "OpenAI".into(), step: 0, source: Box::new(source) };
+        assert!(step(AgentError::InvalidResponse("OpenAI 429: Rate limit reached".into())).is_retryable());
+        assert!(!step(AgentError::InvalidResponse("OpenAI 401: invalid api key".into())).is_retryable());
+        assert!(!step(AgentError::ProviderError("response body exceeds the 1000-byte limit".into())).is_retryable());
+        assert!(!AgentError::ProviderError("OpenAI rejected the credentials (401)".into()).is_retryable());
+        assert!(AgentError::InvalidResponse("OpenAI 429: Rate limit reached".into()).is_retryable());
+        assert!(AgentError::InvalidResponse("Anthropic 529: overloaded".into()).is_retryable());
+        assert!(AgentError::InvalidResponse("Groq 503: Service Unavailable".into()).is_retryable());
+
+        assert!(!AgentError::InvalidResponse("OpenAI 400: bad request, retry after 500 ms".into()).is_retryable());
+        assert!(!AgentError::InvalidResponse("missing 'choices'".into()).is_retryable());
+        assert!(!AgentError::ToolNotFound("x".into()).is_retryable());
+        assert!(!AgentError::ToolError("x".into()).is_retryable());
+        assert!(!AgentError::MaxIterations.is_retryable());
+        assert!(!AgentError::InvalidHistory("x".into()).is_retryable());
+        assert!(!AgentError::Timeout { elapsed: std::time::Duration::from_secs(1), steps_completed: 0 }.is_retryable());
+        assert!(!AgentError::Io(std::io::Error::other("disk full")).is_retryable());
+        let json_err = serde_json::from_str::<serde_json::Value>("{").unwrap_err();
+        assert!(!AgentError::Json(json_err).is_retryable());
+    }
+
+    #[tokio::test]
+    async fn connection_errors_are_retryable() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+
+        let err = reqwest::get(format!("http://{addr}/")).await.unwrap_err();
+        assert!(AgentError::LlmError(err).is_retryable());
+    }
+}
+// ─────────────────────────────────────────────────────────────────────────────
+// Token estimation tests
+// ─────────────────────────────────────────────────────────────────────────────
+
+#[cfg(test)]
+mod token_tests {
+    use mini_agent::{token_estimate, Message};
+
+    // Per-request framing: 3 tokens per message + 3 reply-priming tokens,
+    // plus one token for the "user" role.
+    const FRAMING: usize = 3 + 3 + 1;
+
+    fn assert_close(actual: usize, expected: usize, tolerance: usize) {
+        assert!(
+            actual.abs_diff(expected) <= tolerance,
+            "estimate {actual} not within {tolerance} of {expected}"
+        );
+    }
+
+    #[test]
+    fn openai_model_uses_tiktoken() {
+        // "Hello, world!" is 4 tokens in cl100k_base.
+        let msgs = vec![Message::user("Hello, world!")];
+        assert_close(token_estimate(&msgs, "gpt-4"), FRAMING + 4, 0);
+    }
+
+    #[test]
+    fn openai_sentence_count() {
+        // "The quick brown fox jumps over the lazy dog" is 9 tokens in cl100k_base.
+        let msgs = vec![Message::user("The quick brown fox jumps over the lazy dog")];
+        assert_close(token_estimate(&msgs, "gpt-3.5-turbo"), FRAMING + 9, 1);
+    }
+
+    #[test]
+    fn provider_prefixed_openai_slug_is_recognised() {
+        let msgs = vec![Message::user("The quick brown fox jumps over the lazy dog")];
+        assert_close(token_estimate(&msgs, "openai/gpt-4o"), FRAMING + 9, 1);
+    }
+
+    #[test]
+    fn unknown_model_uses_char_heuristic() {
+        // 40 chars of content → 10 tokens by the chars/4 heuristic.
+        let msgs = vec![Message::user("a".repeat(40))];
+        assert_eq!(token_estimate(&msgs, "llama3"), FRAMING + 10);
+    }
+
+    #[test]
+    fn estimate_grows_with_messages() {
+        let one = vec![Message::user("hello there")];
+        let two = vec![Message::user("hello there"), Message::assistant("general kenobi")];
+        assert!(token_estimate(&two, "gpt-4o") > token_estimate(&one, "gpt-4o"));
+        assert_eq!(token_estimate(&[], "mistral"), 3);
+    }
+}
+
+// ─────────────────────────────────────────────────────────────────────────────
+// Provider request body tests
+// ─────────────────────────────────────────────────────────────────────────────
+
+#[cfg(test)]
+mod provider_request_tests {
+    use mini_agent::{
+        AnthropicProvider, ChatTemplate, GroqProvider, LlmProvider, Message, ModelCapabilities, OllamaGenerateProvider,
+        OllamaProvider, OpenAiProvider, OpenRouterProvider, ProviderConfig, Role, RoutingPrefs, ToolChoice,
+    };
+
+    #[test]
+    fn openrouter_routing_prefs_in_body() {
+        let provider = OpenRouterProvider::new("key", "meta-llama/llama-3.1-8b-instruct")
+            .with_routing(RoutingPrefs {
+                order: vec!["Groq".into(), "Together".into()],
+                allow_fallbacks: Some(false),
+                require_parameters: Some(true),
+            });
+        let body = provider.request_body(&[Message::user("hi")], &[], "").unwrap();
+        assert_eq!(body["provider"]["order"][0], "Groq");
+        assert_eq!(body["provider"]["order"][1], "Together");
+        assert_eq!(body["provider"]["allow_fallbacks"], false);
+        assert_eq!(body["provider"]["require_parameters"], true);
+    }
+
+    #[test]
+    fn openrouter_omits_provider_without_routing() {
+        let provider = OpenRouterProvider::new("key", "some/model");
+        let body = provider.request_body(&[Message::user("hi")], &[], "").unwrap();
+        assert!(body.get("provider").is_none());
+        assert_eq!(body["model"], "some/model");
+    }
+
+    #[test]
+    fn openrouter_routing_skips_unset_fields() {
+        let provider = OpenRouterProvider::new("key", "some/model").with_routing(RoutingPrefs {
+            require_parameters: Some(true),
+            ..Default::default()
+        });
+        let body = provider.request_body(&[Message::user("hi")], &[], "").unwrap();
+        assert!(body["provider"].get("order").is_none());
+        assert!(body["provider"].get("allow_fallbacks").is_none());
+    }
+
+    fn header<'a>(request: &'a reqwest::Request, name: &str) -> &'a str {
+        request.headers().get(name).unwrap().to_str().unwrap()
+    }
+
+    #[test]
+    fn openrouter_default_attribution_headers() {
+        let provider = OpenRouterProvider::new("key", "some/model");
+        let request = provider.build_request(&[Message::user("hi")], &[], "").unwrap();
+        assert_eq!(header(&request, "HTTP-Referer"), "https://github.com/RajMandaliya/mini-agent");
+        assert_eq!(header(&request, "X-Title"), "mini-agent");
+        assert!(request.headers().get("user-agent").is_none());
+    }
+
+    #[test]
+    fn openrouter_custom_attribution_headers() {
+        let provider = OpenRouterProvider::new("key", "some/model")
+            .with_referer("https://example.com")
+            .with_title("My App")
+            .with_user_agent("my-app/1.0");
+        let request = provider.build_request(&[Message::user("hi")], &[], "").unwrap();
+        assert_eq!(header(&request, "HTTP-Referer"), "https://example.com");
+        assert_eq!(header(&request, "X-Title"), "My App");
+        assert_eq!(header(&request, "User-Agent"), "my-app/1.0");
+    }
+
+    #[test]
+    fn user_agent_sent_by_every_provider() {
+        let msgs = [Message::user("hi")];
+        let requests = vec![
+            OpenAiProvider::new("key", "gpt-4o").with_user_agent("ua/1").build_request(&msgs, &[], ""),
+            AnthropicProvider::new("key", "claude").with_user_agent("ua/1").build_request(&msgs, &[], ""),
+            OllamaProvider::new("llama3").with_user_agent("ua/1").build_request(&msgs, &[], ""),
+            OpenRouterProvider::new("key", "m").with_user_agent("ua/1").build_request(&msgs, &[], ""),
+            OllamaGenerateProvider::new("llama3", ChatTemplate::Llama3).with_user_agent("ua/1").build_request(&msgs, &[], ""),
+        ];
+        for request in requests {
+            assert_eq!(header(&request.unwrap(), "User-Agent"), "ua/1");
+        }
+    }
+
+    #[test]
+    fn anthropic_tool_result_carries_image_block() {
+        let mut tool_msg = Message::tool("toolu_1", "[image: image/png]");
+        tool_msg.images.push(mini_agent::ImageContent {
+            media_type: "image/png".into(),
+            data_base64: "AAAA".into(),
+        });
+        let history = vec![
+            Message::user("chart please"),
+            Message::assistant_with_tools("", serde_json::json!([{
+                "id": "toolu_1", "type": "function",
+                "function": { "name": "render_chart", "arguments": "{}" }
+            }])),
+            tool_msg,
+        ];
+        let body = AnthropicProvider::new("key", "claude").request_body(&history, &[], "").unwrap();
+        let result = &body["messages"][2]["content"][0];
+        assert_eq!(result["type"], "tool_result");
+        assert_eq!(result["content"][1]["type"], "image");
+        assert_eq!(result["content"][1]["source"]["media_type"], "image/png");
+        assert_eq!(result["content"][1]["source"]["data"], "AAAA");
+    }
+
+    #[test]
+    fn groq_omits_tools_key_when_empty() {
+        let provider = GroqProvider::new("key", "llama-3.1-8b-instant");
+        let body = provider.request_body(&[Message::user("hi")], &[], "").unwrap();
+        assert!(body.get("tools").is_none());
+        assert!(body.get("tool_choice").is_none());
+    }
+
+    #[test]
+    fn groq_includes_tools_when_present() {
+        let tool = mini_agent::AddNumbersTool;
+        let tools: Vec<&dyn mini_agent::Tool> = vec![&tool];
+        let body = GroqProvider::new("key", "m").request_body(&[Message::user("hi")], &tools, "").unwrap();
+        assert_eq!(body["tools"][0]["function"]["name"], "add_numbers");
+        assert_eq!(body["tool_choice"], "auto");
+    }
+
+    #[test]
+    fn groq_clamps_temperature() {
+        let zero = GroqProvider::new("key", "m").with_temperature(0.0);
+        let body = zero.request_body(&[Message::user("hi")], &[], "").unwrap();
+        assert!(body["temperature"].as_f64().unwrap() > 0.0);
+
+        let hot = GroqProvider::new("key", "m").with_temperature(5.0);
+        let body = hot.request_body(&[Message::user("hi")], &[], "").unwrap();
+        assert_eq!(body["temperature"], 2.0);
+    }
+
+    #[test]
+    fn tools_key_absent_when_empty_for_openai_compatible_providers() {
+        let msgs = [Message::user("hi")];
+        let bodies = vec![
+            ("OpenAI", OpenAiProvider::new("key", "gpt-4o").request_body(&msgs, &[], "").unwrap()),
+            ("OpenRouter", OpenRouterProvider::new("key", "m").request_body(&msgs, &[], "").unwrap()),
+            ("Ollama", OllamaProvider::new("llama3").request_body(&msgs, &[], "").unwrap()),
+        ];
+        for (name, body) in bodies {
+            assert!(body.get("tools").is_none(), "{name} sent a tools key");
+            assert!(body.get("tool_choice").is_none(), "{name} sent a tool_choice key");
+        }
+    }
+
+    #[test]
+    fn tools_key_present_when_registered() {
+        let tool = mini_agent::AddNumbersTool;
+        let tools: Vec<&dyn mini_agent::Tool> = vec![&tool];
+        let msgs = [Message::user("hi")];
+        let openai = OpenAiProvider::new("key", "gpt-4o").request_body(&msgs, &tools, "").unwrap();
+        assert_eq!(openai["tools"].as_array().unwrap().len(), 1);
+        assert_eq!(openai["tool_choice"], "auto");
+        let ollama = OllamaProvider::new("llama3").request_body(&msgs, &tools, "").unwrap();
+        assert_eq!(ollama["tools"].as_array().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn sampling_params_present_when_configured() {
+        let msgs = [Message::user("hi")];
+        let bodies = vec![
+            OpenAiProvider::new("key", "gpt-4o")
+                .with_top_p(0.9)
+                .with_frequency_penalty(0.5)
+                .with_presence_penalty(-0.25)
+                .request_body(&msgs, &[], "")
+                .unwrap(),
+            OpenRouterProvider::new("key", "m")
+                .with_top_p(0.9)
+                .with_frequency_penalty(0.5)
+                .with_presence_penalty(-0.25)
+                .request_body(&msgs, &[], "")
+                .unwrap(),
+            OllamaProvider::new("llama3")
+                .with_top_p(0.9)
+                .with_frequency_penalty(0.5)
+                .with_presence_penalty(-0.25)
+                .request_body(&msgs, &[], "")
+                .unwrap(),
+        ];
+        for body in bodies {
+            assert_eq!(body["top_p"], 0.9);
+            assert_eq!(body["frequency_penalty"], 0.5);
+            assert_eq!(body["presence_penalty"], -0.25);
+        }
+    }
+
+    #[test]
+    fn sampling_params_absent_by_default() {
+        let body = OpenAiProvider::new("key", "gpt-4o").request_body(&[Message::user("hi")], &[], "").unwrap();
+        for key in ["top_p", "frequency_penalty", "presence_penalty"] {
+            assert!(body.get(key).is_none(), "{key} should be absent");
+        }
+    }
+
+    #[test]
+    fn sampling_params_set_individually() {
+        let body = OpenAiProvider::new("key", "gpt-4o")
+            .with_presence_penalty(1.0)
+            .request_body(&[Message::user("hi")], &[], "")
+            .unwrap();
+        assert_eq!(body["presence_penalty"], 1.0);
+        assert!(body.get("top_p").is_none());
+        assert!(body.get("frequency_penalty").is_none());
+    }
+
+    #[test]
+    fn parallel_tool_calls_flag_in_body() {
+        let tool = mini_agent::AddNumbersTool;
+        let tools: Vec<&dyn mini_agent::Tool> = vec![&tool];
+        let msgs = [Message::user("hi")];
+        let bodies = vec![
+            OpenAiProvider::new("key", "gpt-4o")
+                .with_parallel_tool_calls(false)
+                .request_body(&msgs, &tools, "")
+                .unwrap(),
+            OpenRouterProvider::new("key", "m")
+                .with_parallel_tool_calls(false)
+                .request_body(&msgs, &tools, "")
+                .unwrap(),
+        ];
+        for body in bodies {
+            assert_eq!(body["parallel_tool_calls"], false);
+        }
+    }
+
+    #[test]
+    fn parallel_tool_calls_omitted_without_tools_or_setting() {
+        let tool = mini_agent::AddNumbersTool;
+        let tools: Vec<&dyn mini_agent::Tool> = vec![&tool];
+        let msgs = [Message::user("hi")];
+
+        let unset = OpenAiProvider::new("key", "gpt-4o").request_body(&msgs, &tools, "").unwrap();
+        assert!(unset.get("parallel_tool_calls").is_none());
+
+        let no_tools = OpenAiProvider::new("key", "gpt-4o")
+            .with_parallel_tool_calls(false)
+            .request_body(&msgs, &[], "")
+            .unwrap();
+        assert!(no_tools.get("parallel_tool_calls").is_none());
+    }
+
+
+    #[test]
+    fn logit_bias_serializes_with_string_token_ids() {
+        let bias = std::collections::HashMap::from([(50256u32, -100.0f32), (1234, 2.5)]);
+        let msgs = [Message::user("hi")];
+        let bodies = vec![
+            OpenAiProvider::new("key", "gpt-4o").with_logit_bias(bias.clone()).request_body(&msgs, &[], "").unwrap(),
+            OpenRouterProvider::new("key", "m").with_logit_bias(bias).request_body(&msgs, &[], "").unwrap(),
+        ];
+        for body in bodies {
+            assert_eq!(body["logit_bias"], serde_json::json!({ "50256": -100.0, "1234": 2.5 }));
+        }
+        let plain = OpenAiProvider::new("key", "gpt-4o").request_body(&msgs, &[], "").unwrap();
+        assert!(plain.get("logit_bias").is_none());
+    }
+
+
+    #[test]
+    fn anthropic_prompt_caching_marks_last_tool() {
+        let add = mini_agent::AddNumbersTool;
+        let mul = mini_agent::MultiplyNumbersTool;
+        let tools: Vec<&dyn mini_agent::Tool> = vec![&add, &mul];
+        let msgs = [Message::user("hi")];
+
+        let body = AnthropicProvider::new("key", "claude")
+            .with_prompt_caching(true)
+            .request_body(&msgs, &tools, "")
+            .unwrap();
+        assert!(body["tools"][0].get("cache_control").is_none());
+        assert_eq!(body["tools"][1]["cache_control"], serde_json::json!({ "type": "ephemeral" }));
+
+        let plain = AnthropicProvider::new("key", "claude").request_body(&msgs, &tools, "").unwrap();
+        assert!(plain["tools"][1].get("cache_control").is_none());
+    }
+
+
+    #[test]
+    fn provider_config_deserializes_and_builds_openai() {
+        let config: ProviderConfig = serde_json::from_str(
+            r#"{
+                "api_key": "sk-config",
+                "model": "gpt-4o-mini",
+                "base_url": "https://gateway.example.com/v1/",
+                "timeout": 2.5,
+                "max_retries": 3,
+                "temperature": 0.2,
+                "max_tokens": 256
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(config.timeout, Some(std::time::Duration::from_millis(2500)));
+        assert_eq!(config.max_retries, 3);
+
+        let provider = OpenAiProvider::from_config(config).unwrap();
+        let msgs = [Message::user("hi")];
+        let body = provider.request_body(&msgs, &[], "").unwrap();
+        assert_eq!(body["model"], "gpt-4o-mini");
+        assert_eq!(body["temperature"], 0.2);
+        assert_eq!(body["max_tokens"], 256);
+
+        let request = provider.build_request(&msgs, &[], "").unwrap();
+        assert_eq!(request.url().as_str(), "https://gateway.example.com/v1/chat/completions");
+        assert_eq!(header(&request, "authorization"), "Bearer sk-config");
+    }
+
+    #[test]
+    fn provider_config_optional_fields_keep_defaults() {
+        let config: ProviderConfig = serde_json::from_str(r#"{ "api_key": "k", "model": "m" }"#).unwrap();
+        assert_eq!(config.timeout, None);
+        assert_eq!(config.max_retries, 0);
+
+        let body = OpenRouterProvider::from_config(config.clone())
+            .unwrap()
+            .request_body(&[Message::user("hi")], &[], "")
+            .unwrap();
+        assert_eq!(body["temperature"], 0.7);
+        assert_eq!(body["max_tokens"], 1024);
+
+        let ollama = OllamaProvider::from_config(config).unwrap().request_body(&[Message::user("hi")], &[], "").unwrap();
+        assert!(ollama.get("temperature").is_none());
+    }
+
+
+    #[test]
+    fn anthropic_tool_message_without_id_is_an_error() {
+        let orphan = Message { role: mini_agent::Role::Tool, content: "42".into(), ..Default::default() };
+        let msgs = [Message::user("hi"), orphan];
+
+        let err = AnthropicProvider::new("key", "claude").request_body(&msgs, &[], "").unwrap_err();
+        assert!(matches!(err, mini_agent::AgentError::InvalidHistory(msg) if msg.contains("index 1 has no tool_call_id")));
+    }
+
+
+    #[test]
+    fn openai_store_and_metadata_in_body() {
+        let metadata = std::collections::HashMap::from([
+            ("team".to_string(), "search".to_string()),
+            ("agent".to_string(), "triage".to_string()),
+        ]);
+        let body = OpenAiProvider::new("key", "gpt-4o")
+            .with_store(true)
+            .with_request_metadata(metadata)
+            .request_body(&[Message::user("hi")], &[], "")
+            .unwrap();
+        assert_eq!(body["store"], true);
+        assert_eq!(body["metadata"], serde_json::json!({ "team": "search", "agent": "triage" }));
+
+        let plain = OpenAiProvider::new("key", "gpt-4o").request_body(&[Message::user("hi")], &[], "").unwrap();
+        assert!(plain.get("store").is_none());
+        assert!(plain.get("metadata").is_none());
+    }
+
+    #[test]
+    fn openai_prediction_in_body() {
+        let current = "fn main() {\n    println!(\"hi\");\n}\n";
+        let body = OpenAiProvider::new("key", "gpt-4o")
+            .with_prediction(current)
+            .request_body(&[Message::user("Rename the greeting")], &[], "")
+            .unwrap();
+        assert_eq!(body["prediction"], serde_json::json!({ "type": "content", "content": current }));
+
+        let plain = OpenAiProvider::new("key", "gpt-4o").request_body(&[Message::user("hi")], &[], "").unwrap();
+        assert!(plain.get("prediction").is_none());
+    }
+
+
+    #[test]
+    fn extra_body_fields_are_merged_without_overwriting() {
+        let extra = serde_json::json!({ "top_k": 40, "seed": 7, "model": "hijacked" });
+        let msgs = [Message::user("hi")];
+        let bodies = vec![
+            OpenAiProvider::new("key", "m").with_extra_body(extra.clone()).request_body(&msgs, &[], "").unwrap(),
+            OpenRouterProvider::new("key", "m").with_extra_body(extra.clone()).request_body(&msgs, &[], "").unwrap(),
+            GroqProvider::new("key", "m").with_extra_body(extra.clone()).request_body(&msgs, &[], "").unwrap(),
+            OllamaProvider::new("m").with_extra_body(extra.clone()).request_body(&msgs, &[], "").unwrap(),
+            AnthropicProvider::new("key", "m").with_extra_body(extra).request_body(&msgs, &[], "").unwrap(),
+        ];
+        for body in bodies {
+            assert_eq!(body["top_k"], 40);
+            assert_eq!(body["seed"], 7);
+            assert_eq!(body["model"], "m");
+        }
+    }
+
+
+    #[test]
+    fn foreign_tool_calls_are_normalized_for_openai() {
+        // One call stored in Anthropic's block shape, one OpenAI-shaped call
+        // with object arguments and a stray streaming key, and one unusable
+        let history = vec![
+            Message::user("weather?"),
+            Message::assistant_with_tools("", serde_json::json!([
+                { "type": "tool_use", "id": "toolu_1", "name": "weather", "input": { "city": "Oslo" } },
+                { "index": 0, "id": "toolu_2", "type": "function",
+                  "function": { "name": "weather", "arguments": { "city": "Rome" } } },
+                { "type": "tool_use", "name": "missing_id", "input": {} },
+            ])),
+            Message::tool("toolu_1", "cold"),
+            Message::tool("toolu_2", "warm"),
+        ];
+        let body = OpenAiProvider::new("key", "gpt-4o").request_body(&history, &[], "").unwrap();
+
+        let calls = body["messages"][1]["tool_calls"].as_array().unwrap();
+        assert_eq!(calls.len(), 2);
+        for (call, city) in calls.iter().zip(["Oslo", "Rome"]) {
+            assert_eq!(call.as_object().unwrap().len(), 3);
+            assert_eq!(call["type"], "function");
+            assert_eq!(call["function"]["name"], "weather");
+            let args: serde_json::Value =
+                serde_json::from_str(call["function"]["arguments"].as_str().unwrap()).unwrap();
+            assert_eq!(args["city"], city);
+        }
+        assert_eq!(body["messages"][2]["tool_call_id"], "toolu_1");
+    }
+
+    #[test]
+    fn anthropic_resend_keeps_calls_with_unparseable_arguments() {
+        let history = vec![
+            Message::user("go"),
+            Message::assistant_with_tools("", serde_json::json!([
+                { "id": "call_1", "type": "function", "function": { "name": "run", "arguments": "{not json" } },
+            ])),
+            Message::tool("call_1", "done"),
+        ];
+        let body = AnthropicProvider::new("key", "m").request_body(&history, &[], "").unwrap();
+
+        let block = &body["messages"][1]["content"][0];
+        assert_eq!(block["type"], "tool_use");
+        assert_eq!(block["id"], "call_1");
+        assert_eq!(block["input"], serde_json::json!({}));
+    }
+
+
+    #[test]
+    fn openai_picks_developer_role_for_reasoning_models() {
+        let provider = OpenAiProvider::new("key", "gpt-4o");
+        assert_eq!(provider.system_role(""), Some(Role::System));
+        assert_eq!(provider.system_role("o3-mini"), Some(Role::Developer));
+        assert_eq!(provider.system_role("gpt-5"), Some(Role::Developer));
+
+        let forced = OpenAiProvider::new("key", "gpt-4o").with_developer_role(true);
+        assert_eq!(forced.system_role(""), Some(Role::Developer));
+        assert_eq!(AnthropicProvider::new("key", "m").system_role("m"), Some(Role::System));
+        assert_eq!(GroqProvider::new("key", "m").system_role("m"), Some(Role::System));
+        assert_eq!(OpenRouterProvider::new("key", "m").system_role("m"), Some(Role::System));
+        assert_eq!(OllamaProvider::new("llama3").system_role(""), Some(Role::System));
+    }
+
+    #[test]
+    fn openai_sends_developer_messages_with_their_role() {
+        let msgs = [Message::developer("Be terse."), Message::user("hi")];
+        let body = OpenAiProvider::new("key", "o3-mini").request_body(&msgs, &[], "").unwrap();
+        assert_eq!(body["messages"][0]["role"], "developer");
+        assert_eq!(body["messages"][0]["content"], "Be terse.");
+    }
+
+    #[test]
+    fn anthropic_lifts_system_and_developer_into_system_field() {
+        let msgs = [Message::system("Be terse."), Message::developer("Use metric."), Message::user("hi")];
+        let body = AnthropicProvider::new("key", "m").request_body(&msgs, &[], "").unwrap();
+        assert_eq!(body["system"], "Be terse.\n\nUse metric.");
+        assert_eq!(body["messages"].as_array().unwrap().len(), 1);
+        assert_eq!(body["messages"][0]["role"], "user");
+
+        let cached = AnthropicProvider::new("key", "m")
+            .with_prompt_caching(true)
+            .request_body(&msgs, &[], "")
+            .unwrap();
+        assert_eq!(cached["system"][0]["text"], "Be terse.\n\nUse metric.");
+        assert_eq!(cached["system"][0]["cache_control"]["type"], "ephemeral");
+    }
+
+
+    #[test]
+    fn known_models_report_capabilities() {
+        let gpt = OpenAiProvider::new("key", "gpt-4o-mini").capabilities("");
+        assert!(gpt.supports_tools && gpt.supports_vision && gpt.supports_streaming);
+        assert_eq!(gpt.context_window, Some(128_000));
+
+        let gpt35 = OpenAiProvider::new("key", "gpt-4o").capabilities("gpt-3.5-turbo");
+        assert!(gpt35.supports_tools && !gpt35.supports_vision);
+
+        let claude = AnthropicProvider::new("key", "claude-sonnet-4-20250514").capabilities("");
+        assert_eq!(claude.context_window, Some(200_000));
+        assert!(claude.supports_vision);
+
+        // OpenRouter slugs reuse the upstream vendor's table
+        let routed = OpenRouterProvider::new("key", "anthropic/claude-3.5-sonnet").capabilities("");
+        assert_eq!(routed, claude);
+
+        assert!(!OllamaProvider::new("llama3").capabilities("").supports_tools);
+        assert!(GroqProvider::new("key", "llama-3.3-70b-versatile").capabilities("").supports_tools);
+    }
+
+    #[test]
+    fn unknown_models_get_conservative_capabilities() {
+        let unknown = OpenAiProvider::new("key", "gpt-4o").capabilities("some-future-model");
+        assert_eq!(unknown, ModelCapabilities::default());
+        assert!(!unknown.supports_tools);
+        assert_eq!(unknown.context_window, None);
+    }
+
+
+    #[test]
+    fn anthropic_merges_parallel_tool_results_into_one_user_turn() {
+        let history = vec![
+            Message::user("weather in Oslo and Rome?"),
+            Message::assistant_with_tools("", serde_json::json!([
+                { "id": "toolu_1", "type": "function", "function": { "name": "weather", "arguments": "{\"city\":\"Oslo\"}" } },
+                { "id": "toolu_2", "type": "function", "function": { "name": "weather", "arguments": "{\"city\":\"Rome\"}" } },
+            ])),
+            Message::tool("toolu_1", "cold"),
+            Message::tool("toolu_2", "warm"),
+            Message::user("thanks"),
+        ];
+        let body = AnthropicProvider::new("key", "m").request_body(&history, &[], "").unwrap();
+
+        let messages = body["messages"].as_array().unwrap();
+        assert_eq!(messages.len(), 4);
+        let results = messages[2]["content"].as_array().unwrap();
+        assert_eq!(messages[2]["role"], "user");
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0]["tool_use_id"], "toolu_1");
+        assert_eq!(results[1]["tool_use_id"], "toolu_2");
+        assert_eq!(results[1]["content"], "warm");
+        // The follow-up question stays a separate turn
+        assert_eq!(messages[3]["content"], "thanks");
+    }
+
+    #[test]
+    fn anthropic_assistant_prefill_is_the_trailing_message() {
+        let msgs = vec![Message::user("list three colours as JSON")];
+        let body = AnthropicProvider::new("key", "m")
+            .with_assistant_prefill("{\"colours\": [ \n")
+            .request_body(&msgs, &[], "")
+            .unwrap();
+
+        let messages = body["messages"].as_array().unwrap();
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[1]["role"], "assistant");
+        // Trailing whitespace is trimmed, Anthropic rejects it
+        assert_eq!(messages[1]["content"], "{\"colours\": [");
+
+        let plain = AnthropicProvider::new("key", "m").request_body(&msgs, &[], "").unwrap();
+        assert_eq!(plain["messages"].as_array().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn openai_allowed_tools_choice_in_body() {
+        let (add, multiply) = (mini_agent::AddNumbersTool, mini_agent::MultiplyNumbersTool);
+        let tools: Vec<&dyn mini_agent::Tool> = vec![&add, &multiply];
+        let msgs = [Message::user("hi")];
+
+        let body = OpenAiProvider::new("key", "gpt-4o")
+            .with_tool_choice(ToolChoice::AllowedTools(vec!["add_numbers".into()]))
+            .request_body(&msgs, &tools, "")
+            .unwrap();
+        assert_eq!(
+            body["tool_choice"],
+            serde_json::json!({
+                "type": "allowed_tools",
+                "allowed_tools": {
+                    "mode": "auto",
+                    "tools": [{ "type": "function", "function": { "name": "add_numbers" } }]
+                }
+            })
+        );
+
+        let err = OpenAiProvider::new("key", "gpt-4o")
+            .with_tool_choice(ToolChoice::AllowedTools(vec!["add_numbers".into(), "web_search".into()]))
+            .request_body(&msgs, &tools, "")
+            .unwrap_err();
+        assert!(matches!(err, mini_agent::AgentError::ToolNotFound(name) if name == "web_search"));
+
+        let default = OpenAiProvider::new("key", "gpt-4o").request_body(&msgs, &tools, "").unwrap();
+        assert_eq!(default["tool_choice"], "auto");
+    }
+
+    #[test]
+    fn max_tokens_default_depends_on_model() {
+        let msgs = [Message::user("hi")];
+        let max_tokens = |provider: OpenAiProvider| provider.request_body(&msgs, &[], "").unwrap()["max_tokens"].clone();
+
+        assert_eq!(max_tokens(OpenAiProvider::new("key", "gpt-4o")), 4096);
+        // 8k context window
+        assert_eq!(max_tokens(OpenAiProvider::new("key", "gpt-4")), 2048);
+        // Reasoning models need room to think
+        let o3 = OpenAiProvider::new("key", "o3-mini").request_body(&msgs, &[], "").unwrap();
+        assert_eq!(o3["max_completion_tokens"], 16384);
+        assert_eq!(max_tokens(OpenAiProvider::new("key", "my-finetune")), 1024);
+        assert_eq!(max_tokens(OpenAiProvider::new("key", "gpt-4o").with_max_tokens(300)), 300);
+
+        let claude = AnthropicProvider::new("key", "claude-3-5-haiku-latest").request_body(&msgs, &[], "").unwrap();
+        assert_eq!(claude["max_tokens"], 4096);
+    }
+
+    #[test]
+    fn openai_reasoning_models_use_max_completion_tokens() {
+        let msgs = [Message::user("hi")];
+        let body = OpenAiProvider::new("key", "o3-mini").with_max_tokens(500).request_body(&msgs, &[], "").unwrap();
+        assert_eq!(body["max_completion_tokens"], 500);
+        assert!(body.get("max_tokens").is_none());
+        assert!(body.get("temperature").is_none());
+
+        let body = OpenAiProvider::new("key", "gpt-4o").request_body(&msgs, &[], "").unwrap();
+        assert!(body.get("max_completion_tokens").is_none());
+        assert!(body.get("temperature").is_some());
+
+        let forced = OpenAiProvider::new("key", "my-reasoner").with_reasoning_params(true);
+        let body = forced.request_body(&msgs, &[], "").unwrap();
+        assert!(body.get("max_completion_tokens").is_some());
+        assert!(body.get("temperature").is_none());
+    }
+
+    #[test]
+    fn completion_tool_calls_round_trip_to_both_providers() {
+        // As an Anthropic-style provider might report it, with extra keys
+        let completion = mini_agent::Completion {
+            content: Some("Checking.".into()),
+            tool_calls: vec![mini_agent::ToolCall {
+                id: "toolu_01".into(),
+                name: "add_numbers".into(),
+                args: serde_json::json!({ "a": 1, "b": 2 }),
+            }],
+            raw_tool_calls: Some(serde_json::json!([{
+                "type": "tool_use", "id": "toolu_01", "name": "add_numbers",
+                "input": { "a": 1, "b": 2 }, "cache_control": { "type": "ephemeral" }
+            }])),
+            ..Default::default()
+        };
+        let assistant = Message::from_completion(&completion);
+        assert_eq!(
+            assistant.tool_calls,
+            Some(serde_json::json!([{
+                "id": "toolu_01",
+                "type": "function",
+                "function": { "name": "add_numbers", "arguments": "{\"a\":1,\"b\":2}" }
+            }]))
+        );
+        let history = vec![Message::user("1 + 2?"), assistant, Message::tool("toolu_01", "3")];
+
+        let openai = mini_agent::providers::build_openai_messages(&history);
+        assert_eq!(openai[1]["tool_calls"], history[1].tool_calls.clone().unwrap());
+
+        let anthropic = AnthropicProvider::new("key", "m").request_body(&history, &[], "").unwrap();
+        let blocks = anthropic["messages"][1]["content"].as_array().unwrap();
+        assert_eq!(blocks[0], serde_json::json!({ "type": "text", "text": "Checking." }));
+        assert_eq!(
+            blocks[1],
+            serde_json::json!({ "type": "tool_use", "id": "toolu_01", "name": "add_numbers", "input": { "a": 1, "b": 2 } })
+        );
+    }
+
+    #[test]
+    fn conversion_utilities_build_both_request_formats() {
+        use mini_agent::providers::{to_anthropic_request, to_openai_request};
+        use mini_agent::{AddNumbersTool, Tool};
+        use serde_json::json;
+
+        let history = vec![
+            Message::system("Be brief."),
+            Message::user("Add 1 and 2"),
+            Message::assistant_with_tools("", json!([{
+                "id": "call_1",
+                "type": "function",
+                "function": { "name": "add_numbers", "arguments": "{\"a\":1,\"b\":2}" }
+            }])),
+            Message::tool("call_1", "3"),
+            Message::assistant("It's 3."),
+        ];
+        let tools: [&dyn Tool; 1] = [&AddNumbersTool];
+
+        let openai = to_openai_request(&history, &tools, "gpt-4o").unwrap();
+        assert_eq!(openai["model"], "gpt-4o");
+        assert_eq!(openai["messages"].as_array().unwrap().len(), 5);
+        assert_eq!(openai["messages"][0]["role"], "system");
+        assert_eq!(openai["messages"][2]["tool_calls"][0]["function"]["name"], "add_numbers");
+        assert_eq!(openai["messages"][3]["tool_call_id"], "call_1");
+        assert_eq!(openai["tools"][0]["function"]["name"], "add_numbers");
+
+        let anthropic = to_anthropic_request(&history, &tools, "claude-3-5-haiku-latest").unwrap();
+        assert_eq!(anthropic["model"], "claude-3-5-haiku-latest");
+        assert_eq!(anthropic["system"], "Be brief.");
+        let messages = anthropic["messages"].as_array().unwrap();
+        assert_eq!(messages.len(), 4);
+        assert_eq!(messages[1]["content"][0]["type"], "tool_use");
+        assert_eq!(messages[1]["content"][0]["input"], json!({ "a": 1, "b": 2 }));
+        assert_eq!(messages[2]["role"], "user");
+        assert_eq!(messages[2]["content"][0]["tool_use_id"], "call_1");
+        assert_eq!(anthropic["tools"][0]["name"], "add_numbers");
+        assert!(anthropic["max_tokens"].is_u64());
+    }
+
+    #[test]
+    fn ollama_generate_renders_chatml_prompt() {
+        let provider = OllamaGenerateProvider::new("qwen2.5:7b", ChatTemplate::ChatMl);
+        let msgs = vec![Message::system("Be brief."), Message::user("Hi")];
+
+        assert_eq!(
+            provider.render_prompt(&msgs, &[]),
+            "<|im_start|>system\nBe brief.<|im_end|>\n<|im_start|>user\nHi<|im_end|>\n<|im_start|>assistant\n"
+        );
+        let body = provider.request_body(&msgs, &[], "");
+        assert_eq!(body["model"], "qwen2.5:7b");
+        assert_eq!(body["raw"], true);
+        assert_eq!(body["options"]["stop"][0], "<|im_end|>");
+    }
+
+    #[test]
+    fn ollama_generate_puts_the_system_prompt_in_the_system_turn() {
+        let provider = OllamaGenerateProvider::new("qwen2.5:7b", ChatTemplate::ChatMl);
+        assert_eq!(provider.system_role(""), Some(Role::System));
+
+        let msgs = vec![Message::system("Be brief."), Message::user("Hi")];
+        let tool = mini_agent::PreciseMathTool;
+        let prompt = provider.render_prompt(&msgs, &[&tool]);
+        assert!(prompt.starts_with("<|im_start|>system\nBe brief.<|im_end|>\n<|im_start|>system\nYou can call"));
+        assert!(prompt.ends_with("<|im_start|>user\nHi<|im_end|>\n<|im_start|>assistant\n"));
+    }
+
+    #[test]
+    fn ollama_generate_from_config() {
+        let config = ProviderConfig {
+            model: "qwen2.5:7b".into(),
+            base_url: Some("http://gpu-box:11434/".into()),
+            temperature: Some(0.2),
+            max_tokens: Some(64),
+            ..Default::default()
+        };
+        let provider = OllamaGenerateProvider::from_config(config, ChatTemplate::ChatMl)
+            .unwrap()
+            .with_top_p(0.9)
+            .with_extra_body(serde_json::json!({ "keep_alive": "10m", "raw": false }));
+
+        let request = provider.build_request(&[Message::user("Hi")], &[], "").unwrap();
+        assert_eq!(request.url().as_str(), "http://gpu-box:11434/api/generate");
+        let body = provider.request_body(&[Message::user("Hi")], &[], "");
+        assert_eq!(body["options"]["temperature"], 0.2);
+        assert_eq!(body["options"]["num_predict"], 64);
+        assert_eq!(body["options"]["top_p"], 0.9);
+        assert_eq!(body["keep_alive"], "10m");
+        assert_eq!(body["raw"], true);
+
+        let caps = provider.capabilities("");
+        assert!(!caps.supports_tools);
+        assert_eq!(caps.context_window, Some(32_768));
+    }
+
+    #[test]
+    fn ollama_generate_renders_llama3_prompt() {
+        let provider = OllamaGenerateProvider::new("llama3:8b-text", ChatTemplate::Llama3);
+        let msgs = vec![Message::system("Be brief."), Message::user("Hi")];
+
+        assert_eq!(
+            provider.render_prompt(&msgs, &[]),
+            "<|begin_of_text|><|start_header_id|>system<|end_header_id|>\n\nBe brief.<|eot_id|>\
+             <|start_header_id|>user<|end_header_id|>\n\nHi<|eot_id|>\
+             <|start_header_id|>assistant<|end_header_id|>\n\n"
+        );
+    }
+}
+
+// ─────────────────────────────────────────────────────────────────────────────
+// Extra built-in tool tests
+// ─────────────────────────────────────────────────────────────────────────────
+
+#[cfg(test)]
+mod extra_tool_tests {
+    use mini_agent::{AgentError, Completion, JsonQueryTool, LlmProvider, Message, Tool, TranslateTool};
+    use async_trait::async_trait;
+    use serde_json::json;
+    use std::sync::{Arc, Mutex};
+
+    // ── Mock provider that records the prompt and returns a canned reply ──
+
+    struct CannedProvider {
+        reply: String,
+        prompts: Arc<Mutex<Vec<String>>>,
+    }
+
+    #[async_trait]
+    impl LlmProvider for CannedProvider {
+        fn provider_name(&self) -> &str { "CannedMock" }
+
+        async fn complete(
+            &self,
+            messages: &[Message],
+            _tools: &[&dyn Tool],
+            _model: &str,
+        ) -> Result<Completion, AgentError> {
+            self.prompts.lock().unwrap().push(messages[0].content.clone());
+            Ok(Completion {
+                content: Some(self.reply.clone()),
+                tool_calls: vec![],
+                raw_tool_calls: None,
+                ..Default::default()
+            })
+        }
+    }
+
+    // ── TranslateTool ─────────────────────────────────────────────────────
+
+    #[tokio::test]
+    async fn translate_returns_provider_translation() {
+        let prompts = Arc::new(Mutex::new(vec![]));
+        let provider = CannedProvider { reply: "  Bonjour le monde  ".into(), prompts: prompts.clone() };
+        let tool = TranslateTool::new(Arc::new(provider), "");
+
+        let result = tool
+            .execute(json!({ "text": "Hello world", "target_lang": "French" }))
+            .await
+            .unwrap();
+        assert_eq!(result, "Bonjour le monde");
+
+        let prompt = &prompts.lock().unwrap()[0];
+        assert!(prompt.contains("French"));
+        assert!(prompt.contains("Hello world"));
+    }
+
+    #[tokio::test]
+    async fn translate_missing_target_returns_error() {
+        let provider = CannedProvider { reply: "x".into(), prompts: Arc::new(Mutex::new(vec![])) };
+        let tool = TranslateTool::new(Arc::new(provider), "");
+        let result = tool.execute(json!({ "text": "Hello" })).await;
+        assert!(matches!(result, Err(AgentError::ToolError(_))));
+    }
+
+    // ── CurrencyTool ──────────────────────────────────────────────────────
+
+    struct FixedRates {
+        lookups: Arc<Mutex<usize>>,
+    }
+
+    #[async_trait]
+    impl mini_agent::RateSource for FixedRates {
+        async fn rate(&self, from: &str, to: &str) -> Result<f64, AgentError> {
+            *self.lookups.lock().unwrap() += 1;
+            match (from, to) {
+                ("USD", "EUR") => Ok(0.5),
+                _ => Err(AgentError::ToolError(format!("no rate for {from} → {to}"))),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn currency_converts_with_mock_rate() {
+        let lookups = Arc::new(Mutex::new(0));
+        let tool = mini_agent::CurrencyTool::with_source(Arc::new(FixedRates { lookups: lookups.clone() }));
+
+        let result = tool.execute(json!({ "amount": 120, "from": "usd", "to": "EUR" })).await.unwrap();
+        assert_eq!(result, "60.00 EUR");
+
+        // Second conversion is served from the cache
+        let result = tool.execute(json!({ "amount": 3, "from": "USD", "to": "EUR" })).await.unwrap();
+        assert_eq!(result, "1.50 EUR");
+        assert_eq!(*lookups.lock().unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn currency_source_failure_is_tool_error() {
+        let tool = mini_agent::CurrencyTool::with_source(Arc::new(FixedRates { lookups: Arc::new(Mutex::new(0)) }));
+        let result = tool.execute(json!({ "amount": 1, "from": "GBP", "to": "JPY" })).await;
+        assert!(matches!(result, Err(AgentError::ToolError(_))));
+
+        let result = tool.execute(json!({ "amount": 1, "from": "dollars", "to": "EUR" })).await;
+        assert!(matches!(result, Err(AgentError::ToolError(_))));
+    }
+
+    #[cfg(feature = "testing")]
+    #[tokio::test]
+    async fn currency_cache_expires_with_mock_clock() {
+        let lookups = Arc::new(Mutex::new(0));
+        let clock = Arc::new(mini_agent::MockClock::new());
+        let tool = mini_agent::CurrencyTool::with_source(Arc::new(FixedRates { lookups: lookups.clone() }))
+            .with_ttl(std::time::Duration::from_secs(60))
+            .with_clock(clock.clone());
+        let args = json!({ "amount": 2, "from": "USD", "to": "EUR" });
+
+        tool.execute(args.clone()).await.unwrap();
+        clock.advance(std::time::Duration::from_secs(59));
+        tool.execute(args.clone()).await.unwrap();
+        assert_eq!(*lookups.lock().unwrap(), 1);
+
+        // Past the TTL the rate is fetched again
+        clock.advance(std::time::Duration::from_secs(1));
+        tool.execute(args).await.unwrap();
+        assert_eq!(*lookups.lock().unwrap(), 2);
+    }
+
+
+    // ── RegexExtractTool ──────────────────────────────────────────────────
+
+    #[tokio::test]
+    async fn regex_extract_returns_capture_groups() {
+        let result = mini_agent::RegexExtractTool
+            .execute(json!({
+                "pattern": r"(\w+)@(\w+\.com)",
+                "text": "contact alice@example.com or bob@test.com"
+            }))
+            .await
+            .unwrap();
+        assert_eq!(result, "alice\texample.com\nbob\ttest.com");
+    }
+
+    #[tokio::test]
+    async fn regex_extract_returns_whole_matches_without_groups() {
+        let result = mini_agent::RegexExtractTool
+            .execute(json!({ "pattern": r"\d+", "text": "a1 b22 c333" }))
+            .await
+            .unwrap();
+        assert_eq!(result, "1\n22\n333");
+    }
+
+    #[tokio::test]
+    async fn regex_extract_invalid_pattern_is_tool_error() {
+        let err = mini_agent::RegexExtractTool
+            .execute(json!({ "pattern": "(unclosed", "text": "x" }))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, AgentError::ToolError(msg) if msg.contains("invalid pattern")));
+    }
+
+    #[tokio::test]
+    async fn regex_extract_rejects_oversized_input() {
+        let text = "a".repeat(200_000);
+        let err = mini_agent::RegexExtractTool
+            .execute(json!({ "pattern": "a", "text": text }))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, AgentError::ToolError(_)));
+    }
+
+
+    // ── schema_for / TypedTool ────────────────────────────────────────────
+
+    #[derive(schemars::JsonSchema, serde::Deserialize)]
+    #[serde(deny_unknown_fields)]
+    struct AddArgs {
+        a: i64,
+        b: i64,
+    }
+
+    #[test]
+    fn schema_for_matches_add_numbers_shape() {
+        let derived = mini_agent::schema_for::<AddArgs>();
+        let handwritten = mini_agent::AddNumbersTool.parameters_schema();
+
+        assert_eq!(derived["type"], handwritten["type"]);
+        assert_eq!(derived["required"], handwritten["required"]);
+        assert_eq!(derived["additionalProperties"], handwritten["additionalProperties"]);
+        for key in ["a", "b"] {
+            assert_eq!(derived["properties"][key]["type"], handwritten["properties"][key]["type"]);
+        }
+        assert!(derived.get("$schema").is_none());
+    }
+
+    #[tokio::test]
+    async fn typed_tool_deserializes_args() {
+        let tool = mini_agent::TypedTool::new("add", "Adds two integers", |args: AddArgs| async move {
+            Ok((args.a + args.b).to_string())
+        });
+
+        assert_eq!(tool.execute(json!({ "a": 4, "b": 5 })).await.unwrap(), "9");
+        let err = tool.execute(json!({ "a": "four", "b": 5 })).await.unwrap_err();
+        assert!(matches!(err, AgentError::ToolError(msg) if msg.contains("invalid arguments for 'add'")));
+    }
+
+
+    // ── ImageGenTool ──────────────────────────────────────────────────────
+
+    #[tokio::test]
+    async fn image_gen_extracts_url() {
+        let payload = r#"{"created":1700000000,"data":[{"url":"https://images.example.com/cat.png","revised_prompt":"a cat"}]}"#;
+        let (base_url, captured) = super::mock_http::serve(vec![(200, payload.into())]).await;
+        let tool = mini_agent::ImageGenTool::new("sk-test").with_base_url(base_url);
+
+        let result = tool.execute(json!({ "prompt": "a cat", "size": "512x512" })).await.unwrap();
+        assert_eq!(result, "https://images.example.com/cat.png");
+
+        let request = captured.lock().unwrap()[0].clone();
+        assert_eq!(request.request_line, "POST /images/generations HTTP/1.1");
+        assert_eq!(request.header("authorization"), Some("Bearer sk-test"));
+        let body: serde_json::Value = serde_json::from_str(&request.body).unwrap();
+        assert_eq!(body["prompt"], "a cat");
+        assert_eq!(body["size"], "512x512");
+    }
+
+    #[tokio::test]
+    async fn image_gen_maps_api_errors() {
+        let payload = r#"{"error":{"message":"Your request was rejected by the safety system."}}"#;
+        let (base_url, _) = super::mock_http::serve(vec![(400, payload.into())]).await;
+        let tool = mini_agent::ImageGenTool::new("sk-test").with_base_url(base_url);
+
+        let err = tool.execute(json!({ "prompt": "something" })).await.unwrap_err();
+        assert!(matches!(err, AgentError::ToolError(msg) if msg.contains("400: Your request was rejected")));
+    }
+
+    #[tokio::test]
+    async fn image_gen_reports_non_json_errors() {
+        let (base_url, _) = super::mock_http::serve(vec![(502, "upstream connect error".into())]).await;
+        let tool = mini_agent::ImageGenTool::new("sk-test").with_base_url(base_url);
+
+        let err = tool.execute(json!({ "prompt": "something" })).await.unwrap_err();
+        assert!(matches!(err, AgentError::ToolError(msg) if msg == "images API returned 502: upstream connect error"));
+    }
+
+
+    // ── PreciseMathTool ───────────────────────────────────────────────────
+
+    #[tokio::test]
+    async fn precise_math_adds_decimals_exactly() {
+        let tool = mini_agent::PreciseMathTool;
+        let sum = tool.execute(json!({ "a": "0.1", "b": "0.2", "op": "+" })).await.unwrap();
+        assert_eq!(sum, "0.3");
+
+        let big = tool
+            .execute(json!({ "a": "9223372036854775807", "b": "1", "op": "+" }))
+            .await
+            .unwrap();
+        assert_eq!(big, "9223372036854775808");
+    }
+
+    #[tokio::test]
+    async fn precise_math_division_by_zero_is_tool_error() {
+        let err = mini_agent::PreciseMathTool
+            .execute(json!({ "a": "10", "b": "0.00", "op": "/" }))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, AgentError::ToolError(msg) if msg.contains("division by zero")));
+    }
+
+    #[tokio::test]
+    async fn precise_math_rejects_unparseable_input() {
+        let err = mini_agent::PreciseMathTool
+            .execute(json!({ "a": "ten", "b": "1", "op": "*" }))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, AgentError::ToolError(msg) if msg.contains("'a' is not a decimal number")));
+    }
+
+
+    // ── SqlQueryTool ──────────────────────────────────────────────────────
+
+    #[cfg(feature = "sqlite")]
+    fn sql_tool() -> mini_agent::SqlQueryTool {
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE users (id INTEGER, name TEXT, score REAL);
+             INSERT INTO users VALUES (1, 'ada', 9.5), (2, 'bob', NULL), (3, 'cy', 7.0);",
+        )
+        .unwrap();
+        mini_agent::SqlQueryTool::from_connection(conn).unwrap().with_max_rows(2)
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn sql_query_returns_rows_as_json() {
+        let out = sql_tool()
+            .execute(json!({ "sql": "SELECT id, name, score FROM users ORDER BY id" }))
+            .await
+            .unwrap();
+        let out: serde_json::Value = serde_json::from_str(&out).unwrap();
+        assert_eq!(out["rows"][0], json!({ "id": 1, "name": "ada", "score": 9.5 }));
+        assert_eq!(out["rows"][1]["score"], serde_json::Value::Null);
+        assert_eq!(out["rows"].as_array().unwrap().len(), 2);
+        assert_eq!(out["truncated"], true);
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn sql_query_rejects_writes() {
+        let tool = sql_tool();
+        for sql in [
+            "INSERT INTO users VALUES (4, 'eve', 1.0)",
+            "WITH x AS (SELECT 1) DELETE FROM users",
+            "DROP TABLE users",
+        ] {
+            let err = tool.execute(json!({ "sql": sql })).await.unwrap_err();
+            assert!(matches!(err, AgentError::ToolError(_)), "{sql} was not rejected");
+        }
+        let out = tool.execute(json!({ "sql": "SELECT count(*) AS n FROM users" })).await.unwrap();
+        assert!(out.contains("\"n\":3"));
+    }
+
+
+    // ── RandomTool ────────────────────────────────────────────────────────
+
+    #[tokio::test]
+    async fn random_tool_is_deterministic_with_a_seed() {
+        let roll = |tool: mini_agent::RandomTool| async move {
+            let mut rolls = vec![];
+            for _ in 0..5 {
+                rolls.push(tool.execute(json!({ "min": 1, "max": 6 })).await.unwrap());
+            }
+            rolls
+        };
+        let first = roll(mini_agent::RandomTool::with_seed(7)).await;
+        let second = roll(mini_agent::RandomTool::with_seed(7)).await;
+        assert_eq!(first, second);
+        for value in &first {
+            assert!((1..=6).contains(&value.parse::<i64>().unwrap()));
+        }
+
+        let single = mini_agent::RandomTool::new().execute(json!({ "min": 3, "max": 3 })).await.unwrap();
+        assert_eq!(single, "3");
+    }
+
+    #[tokio::test]
+    async fn random_tool_rejects_min_above_max() {
+        let err = mini_agent::RandomTool::with_seed(1)
+            .execute(json!({ "min": 10, "max": 1 }))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, AgentError::ToolError(msg) if msg.contains("must not be greater")));
+    }
+
+    // ── JsonQueryTool ─────────────────────────────────────────────────────
+
+    #[tokio::test]
+    async fn json_query_extracts_nested_fields() {
+        let doc = r#"{ "user": { "name": "Ada", "langs": [{ "id": "rust" }, { "id": "ocaml" }] } }"#;
+        let query = |path: &str| JsonQueryTool.execute(json!({ "json": doc, "path": path }));
+
+        assert_eq!(query("$.user.name").await.unwrap(), "\"Ada\"");
+        assert_eq!(query("user['langs'][-1].id").await.unwrap(), "\"ocaml\"");
+        assert_eq!(query("$.user.langs[*].id").await.unwrap(), r#"["rust","ocaml"]"#);
+    }
+
+    #[tokio::test]
+    async fn json_query_rejects_bad_input() {
+        let bad_path = JsonQueryTool.execute(json!({ "json": "{\"a\": [1]}", "path": "$.a[first]" })).await;
+        assert!(matches!(bad_path, Err(AgentError::ToolError(msg)) if msg.contains("invalid path")));
+
+        let missing = JsonQueryTool.execute(json!({ "json": "{\"a\": 1}", "path": "$.b" })).await;
+        assert!(matches!(missing, Err(AgentError::ToolError(_))));
+
+        let bad_json = JsonQueryTool.execute(json!({ "json": "{not json", "path": "$" })).await;
+        assert!(matches!(bad_json, Err(AgentError::ToolError(msg)) if msg.contains("invalid JSON")));
+    }
+
+    // ── ConfigReadTool ────────────────────────────────────────────────────
+
+    #[tokio::test]
+    async fn config_read_returns_allowed_value() {
+        std::env::set_var("MINI_AGENT_TEST_REGION", "eu-west-1");
+        let tool = mini_agent::ConfigReadTool::new(["MINI_AGENT_TEST_REGION"]);
+        let result = tool.execute(json!({ "key": "MINI_AGENT_TEST_REGION" })).await.unwrap();
+        assert_eq!(result, "eu-west-1");
+    }
+
+    #[tokio::test]
+    async fn config_read_refuses_keys_off_the_allowlist() {
+        std::env::set_var("MINI_AGENT_TEST_SECRET", "sk-hidden");
+        let tool = mini_agent::ConfigReadTool::new(["MINI_AGENT_TEST_REGION"]);
+        let err = tool.execute(json!({ "key": "MINI_AGENT_TEST_SECRET" })).await.unwrap_err();
+        assert!(matches!(&err, AgentError::ToolError(msg) if !msg.contains("sk-hidden")));
+    }
+
+    #[tokio::test]
+    async fn config_read_reports_unset_allowed_key() {
+        let tool = mini_agent::ConfigReadTool::new(["MINI_AGENT_TEST_NEVER_SET"]);
+        let result = tool.execute(json!({ "key": "MINI_AGENT_TEST_NEVER_SET" })).await.unwrap();
+        assert_eq!(result, "not set");
+    }
+
+    // ── HashTool ──────────────────────────────────────────────────────────
+
+    #[tokio::test]
+    async fn hash_returns_known_digests() {
+        let cases = [
+            ("sha256", "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9"),
+            ("sha1", "2aae6c35c94fcfb415dbe95f408b9ce91ee846ed"),
+            ("md5", "5eb63bbbe01eeed093cb22bb8f5acdc3"),
+        ];
+        for (algorithm, expected) in cases {
+            let digest = mini_agent::HashTool
+                .execute(json!({ "algorithm": algorithm, "data": "hello world" }))
+                .await
+                .unwrap();
+            assert_eq!(digest, expected, "{algorithm}");
+        }
+    }
+
+    #[tokio::test]
+    async fn hash_rejects_unknown_algorithm() {
+        let result = mini_agent::HashTool.execute(json!({ "algorithm": "crc32", "data": "hello" })).await;
+        assert!(matches!(result, Err(AgentError::ToolError(msg)) if msg.contains("crc32")));
+    }
+}
+
+// ─────────────────────────────────────────────────────────────────────────────
+// Provider HTTP tests (against the local mock server)
+// ─────────────────────────────────────────────────────────────────────────────
+
+#[cfg(test)]
+mod provider_http_tests {
+    use super::mock_http;
+    use mini_agent::{
+        AgentError, ChatTemplate, HttpPostTool, LlmProvider, Message, OllamaGenerateProvider, OllamaProvider,
+        OpenAiProvider, Tool,
+    };
+
+    #[tokio::test]
+    async fn proxy_routes_requests_through_mock_proxy() {
+        let (proxy_url, captured) = mock_http::serve(vec![(200, mock_http::chat_completion("via proxy"))]).await;
+        let proxy_with_auth = proxy_url.replace("http://", "http://user:secret@");
+
+        let provider = OllamaProvider::with_base_url("http://ollama.internal:11434", "llama3")
+            .with_proxy(&proxy_with_auth)
+            .unwrap();
+        let completion = provider.complete(&[Message::user("hi")], &[], "").await.unwrap();
+        assert_eq!(completion.content.as_deref(), Some("via proxy"));
+
+        let request = captured.lock().unwrap()[0].clone();
+        // Proxied requests use the absolute-form target
+        assert_eq!(request.request_line, "POST http://ollama.internal:11434/v1/chat/completions HTTP/1.1");
+        // base64("user:secret")
+        assert_eq!(request.header("proxy-authorization"), Some("Basic dXNlcjpzZWNyZXQ="));
+        let body: serde_json::Value = serde_json::from_str(&request.body).unwrap();
+        assert_eq!(body["messages"][0]["content"], "hi");
     }
 
     #[test]
-    fn parse_completion_null_content() {
-        let json = json!({
-            "choices": [{
-                "message": {
-                    "role": "assistant",
-                    "content": null
-                }
-            }]
+    fn invalid_proxy_url_is_rejected() {
+        assert!(OllamaProvider::new("llama3").with_proxy("not a url").is_err());
+    }
+
+
+    #[tokio::test]
+    async fn ollama_health_check_hits_root() {
+        let (base_url, captured) = mock_http::serve(vec![(200, "Ollama is running".into())]).await;
+        let provider = OllamaProvider::with_base_url(base_url, "llama3");
+
+        provider.health_check().await.unwrap();
+        assert_eq!(captured.lock().unwrap()[0].request_line, "GET / HTTP/1.1");
+    }
+
+    #[tokio::test]
+    async fn openai_health_check_lists_models() {
+        let (base_url, captured) = mock_http::serve(vec![(200, r#"{"object":"list","data":[]}"#.into())]).await;
+        let provider = OpenAiProvider::new("sk-test", "gpt-4o").with_base_url(format!("{base_url}/v1"));
+
+        provider.health_check().await.unwrap();
+        let request = captured.lock().unwrap()[0].clone();
+        assert_eq!(request.request_line, "GET /v1/models HTTP/1.1");
+        assert_eq!(request.header("authorization"), Some("Bearer sk-test"));
+    }
+
+    #[tokio::test]
+    async fn openai_health_check_reports_bad_key() {
+        let (base_url, _) = mock_http::serve(vec![(401, r#"{"error":{"message":"Incorrect API key"}}"#.into())]).await;
+        let provider = OpenAiProvider::new("bad", "gpt-4o").with_base_url(base_url);
+
+        let err = provider.health_check().await.unwrap_err();
+        assert!(matches!(&err, AgentError::ProviderError(msg) if msg.contains("rejected the credentials (401)")));
+    }
+
+    #[tokio::test]
+    async fn health_check_reports_server_errors() {
+        let (base_url, _) = mock_http::serve(vec![(503, "overloaded".into())]).await;
+        let provider = OllamaProvider::with_base_url(base_url, "llama3");
+
+        let err = provider.health_check().await.unwrap_err();
+        assert!(matches!(&err, AgentError::ProviderError(msg) if msg.contains("(503): overloaded")));
+    }
+
+    #[tokio::test]
+    async fn health_check_reports_unreachable_host() {
+        // Grab a free port, then close it so nothing is listening
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+
+        let provider = OllamaProvider::with_base_url(format!("http://{addr}"), "llama3");
+        let err = provider.health_check().await.unwrap_err();
+        assert!(matches!(&err, AgentError::ProviderError(msg) if msg.contains("Ollama is unreachable")));
+    }
+
+    struct PingProvider {
+        calls: std::sync::Arc<std::sync::Mutex<Vec<String>>>,
+    }
+
+    #[async_trait::async_trait]
+    impl LlmProvider for PingProvider {
+        fn provider_name(&self) -> &str { "PingMock" }
+
+        async fn complete(
+            &self,
+            messages: &[Message],
+            _tools: &[&dyn mini_agent::Tool],
+            _model: &str,
+        ) -> Result<mini_agent::Completion, AgentError> {
+            self.calls.lock().unwrap().push(messages[0].content.clone());
+            Ok(mini_agent::Completion { content: Some("pong".into()), tool_calls: vec![], raw_tool_calls: None, ..Default::default() })
+        }
+    }
+
+    #[tokio::test]
+    async fn default_health_check_sends_a_tiny_completion() {
+        let calls = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+        let provider = PingProvider { calls: calls.clone() };
+
+        provider.health_check().await.unwrap();
+        assert_eq!(*calls.lock().unwrap(), vec!["ping"]);
+    }
+
+
+    #[tokio::test]
+    async fn max_retries_retries_server_errors() {
+        let (base_url, captured) = mock_http::serve(vec![
+            (503, "busy".into()),
+            (200, mock_http::chat_completion("after retry")),
+        ])
+        .await;
+        let config = mini_agent::ProviderConfig {
+            model: "llama3".into(),
+            base_url: Some(base_url),
+            max_retries: 2,
+            ..Default::default()
+        };
+        let provider = OllamaProvider::from_config(config).unwrap();
+
+        let completion = provider.complete(&[Message::user("hi")], &[], "").await.unwrap();
+        assert_eq!(completion.content.as_deref(), Some("after retry"));
+        assert_eq!(captured.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn without_retries_server_error_is_returned() {
+        let (base_url, captured) = mock_http::serve(vec![(503, "busy".into())]).await;
+        let provider = OllamaProvider::with_base_url(base_url, "llama3");
+
+        assert!(provider.complete(&[Message::user("hi")], &[], "").await.is_err());
+        assert_eq!(captured.lock().unwrap().len(), 1);
+    }
+
+
+    #[tokio::test]
+    async fn oversized_response_is_rejected() {
+        let huge = mock_http::chat_completion(&"x".repeat(10_000));
+        let (base_url, _) = mock_http::serve(vec![(200, huge)]).await;
+
+        let provider = OllamaProvider::with_base_url(&base_url, "llama3").with_max_response_bytes(1_000);
+        let err = provider.complete(&[Message::user("hi")], &[], "").await.unwrap_err();
+        match err {
+            AgentError::ProviderError(msg) => assert!(msg.contains("1000-byte limit"), "{msg}"),
+            other => panic!("expected ProviderError, got {other:?}"),
+        }
+
+        // The same body is fine under a generous limit
+        let provider = OllamaProvider::with_base_url(&base_url, "llama3").with_max_response_bytes(100_000);
+        let completion = provider.complete(&[Message::user("hi")], &[], "").await.unwrap();
+        assert_eq!(completion.content.unwrap().len(), 10_000);
+    }
+
+
+    fn anthropic_reply(blocks: serde_json::Value) -> String {
+        serde_json::json!({ "type": "message", "role": "assistant", "content": blocks }).to_string()
+    }
+
+    #[tokio::test]
+    async fn anthropic_joins_text_around_tool_use() {
+        let body = anthropic_reply(serde_json::json!([
+            { "type": "text", "text": "Let me check." },
+            { "type": "tool_use", "id": "toolu_1", "name": "add_numbers", "input": { "a": 1, "b": 2 } },
+            { "type": "text", "text": "Checking now." },
+            { "type": "text", "text": "\n\n" },
+            { "type": "text", "text": "Done:\n" },
+            { "type": "text", "text": "- item" },
+        ]));
+        let (base_url, _) = mock_http::serve(vec![(200, body)]).await;
+        let config = |base_url: &str| mini_agent::ProviderConfig {
+            model: "claude".into(),
+            base_url: Some(base_url.to_string()),
+            ..Default::default()
+        };
+
+        let provider = mini_agent::AnthropicProvider::from_config(config(&base_url)).unwrap();
+        let completion = provider.complete(&[Message::user("hi")], &[], "").await.unwrap();
+        // Blank blocks are dropped; no separator after a block ending in a newline
+        assert_eq!(completion.content.as_deref(), Some("Let me check.\nChecking now.\nDone:\n- item"));
+        assert_eq!(completion.tool_calls.len(), 1);
+
+        let provider = mini_agent::AnthropicProvider::from_config(config(&base_url)).unwrap().with_text_joiner(" ");
+        let completion = provider.complete(&[Message::user("hi")], &[], "").await.unwrap();
+        assert_eq!(completion.content.as_deref(), Some("Let me check. Checking now. Done:\n- item"));
+    }
+
+    #[tokio::test]
+    async fn anthropic_usage_reaches_estimated_cost() {
+        let body = serde_json::json!({
+            "type": "message",
+            "role": "assistant",
+            "content": [{ "type": "text", "text": "Hi!" }],
+            "usage": { "input_tokens": 2000, "output_tokens": 1000 },
+        })
+        .to_string();
+        let (base_url, _) = mock_http::serve(vec![(200, body)]).await;
+        let config = mini_agent::ProviderConfig {
+            model: "claude-sonnet-4-20250514".into(),
+            base_url: Some(base_url),
+            ..Default::default()
+        };
+        let provider = mini_agent::AnthropicProvider::from_config(config).unwrap();
+        let mut agent = mini_agent::Agent::new(Box::new(provider), "claude-sonnet-4-20250514");
+        assert_eq!(agent.estimated_cost(), None);
+
+        agent.run("hi").await.unwrap();
+
+        assert_eq!(agent.usage().prompt_tokens, 2000);
+        // claude-sonnet-4: $0.003 in, $0.015 out per 1K → 2 × 0.003 + 1 × 0.015
+        let cost = agent.estimated_cost().unwrap();
+        assert!((cost - 0.021).abs() < 1e-9, "cost = {cost}");
+    }
+
+    #[tokio::test]
+    async fn anthropic_prefill_is_prepended_to_content() {
+        let body = anthropic_reply(serde_json::json!([{ "type": "text", "text": "\"ok\": true}" }]));
+        let (base_url, _) = mock_http::serve(vec![(200, body)]).await;
+        let config = mini_agent::ProviderConfig {
+            model: "claude".into(),
+            base_url: Some(base_url),
+            ..Default::default()
+        };
+
+        let provider = mini_agent::AnthropicProvider::from_config(config).unwrap().with_assistant_prefill("{");
+        let completion = provider.complete(&[Message::user("hi")], &[], "").await.unwrap();
+        assert_eq!(completion.content.as_deref(), Some("{\"ok\": true}"));
+    }
+
+    #[tokio::test]
+    async fn http_post_sends_json_body_and_headers() {
+        let (base_url, captured) = mock_http::serve(vec![(200, r#"{"received":true}"#.into())]).await;
+        let tool = HttpPostTool::new(["127.0.0.1"]).with_private_network(true);
+
+        let result = tool
+            .execute(serde_json::json!({
+                "url": format!("{base_url}/hooks/deploy"),
+                "body": { "service": "api", "version": 7 },
+                "headers": { "X-Token": "abc" }
+            }))
+            .await
+            .unwrap();
+        assert_eq!(result, r#"{"received":true}"#);
+
+        let request = captured.lock().unwrap()[0].clone();
+        assert_eq!(request.request_line, "POST /hooks/deploy HTTP/1.1");
+        assert_eq!(request.header("x-token"), Some("abc"));
+        assert_eq!(request.header("content-type"), Some("application/json"));
+        let sent: serde_json::Value = serde_json::from_str(&request.body).unwrap();
+        assert_eq!(sent, serde_json::json!({ "service": "api", "version": 7 }));
+    }
+
+    #[tokio::test]
+    async fn http_post_notes_a_truncated_response() {
+        let (base_url, _) = mock_http::serve(vec![(200, "x".repeat(150_000))]).await;
+        let tool = HttpPostTool::new(["127.0.0.1"]).with_private_network(true);
+
+        let result = tool.execute(serde_json::json!({ "url": base_url, "body": "hi" })).await.unwrap();
+        assert!(result.starts_with(&"x".repeat(100_000)));
+        assert!(result.ends_with("\n[response truncated at 100000 bytes]"));
+    }
+
+    #[tokio::test]
+    async fn http_post_refuses_disallowed_hosts() {
+        let (base_url, captured) = mock_http::serve(vec![(200, "ok".into())]).await;
+        let body = serde_json::json!("hi");
+
+        let tool = HttpPostTool::new(["*.example.com"]);
+        let err = tool.execute(serde_json::json!({ "url": "https://example.org/x", "body": body })).await.unwrap_err();
+        assert!(matches!(err, AgentError::ToolError(msg) if msg.contains("not on the allowlist")));
+
+        // Allowlisted, but loopback is refused unless private networks are enabled
+        let tool = HttpPostTool::new(["127.0.0.1"]);
+        let err = tool.execute(serde_json::json!({ "url": base_url, "body": body })).await.unwrap_err();
+        assert!(matches!(err, AgentError::ToolError(msg) if msg.contains("private address")));
+        assert!(captured.lock().unwrap().is_empty());
+
+        // So do carrier-grade NAT, "this network", benchmarking, multicast and NAT64
+        for url in [
+            "http://100.64.0.1/hook",
+            "http://100.127.255.254/hook",
+            "http://0.1.2.3/hook",
+            "http://198.18.0.1/hook",
+            "http://198.19.255.254/hook",
+            "http://224.0.0.251/hook",
+            "http://[ff02::1]/hook",
+            "http://[64:ff9b::a00:1]/hook",
+        ] {
+            let host = url.trim_start_matches("http://").trim_end_matches("/hook");
+            let tool = HttpPostTool::new([host]);
+            let err = tool.execute(serde_json::json!({ "url": url, "body": body })).await.unwrap_err();
+            assert!(matches!(&err, AgentError::ToolError(msg) if msg.contains("private address")), "{url}: {err:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn raw_response_keeps_the_full_provider_body() {
+        let payload = serde_json::json!({
+            "id": "chatcmpl-1",
+            "choices": [{ "message": { "role": "assistant", "content": "hello" }, "finish_reason": "stop" }],
+            "x_vendor": { "cache_hit": true }
         });
-        let completion = parse_openai_completion(&json).unwrap();
-        assert!(completion.content.is_none());
+        let (base_url, _) = mock_http::serve(vec![
+            (200, payload.to_string()),
+            (200, payload.to_string()),
+        ])
+        .await;
+
+        let provider = OllamaProvider::with_base_url(base_url.clone(), "llama3");
+        let completion = provider.complete(&[Message::user("hi")], &[], "").await.unwrap();
+        assert_eq!(completion.raw.as_ref(), Some(&payload));
+
+        let mut agent = mini_agent::Agent::new(Box::new(OllamaProvider::with_base_url(base_url, "llama3")), "llama3");
+        assert!(agent.last_raw_response().is_none());
+        agent.run("hi").await.unwrap();
+        assert_eq!(agent.last_raw_response(), Some(&payload));
+    }
+
+    #[tokio::test]
+    async fn idempotency_key_is_stable_across_retries() {
+        let (base_url, captured) = mock_http::serve(vec![
+            (503, "busy".into()),
+            (200, mock_http::chat_completion("first")),
+            (200, mock_http::chat_completion("second")),
+        ])
+        .await;
+        let config = mini_agent::ProviderConfig {
+            model: "gpt-4o".into(),
+            base_url: Some(base_url),
+            max_retries: 1,
+            ..Default::default()
+        };
+        let provider = OpenAiProvider::from_config(config).unwrap().with_idempotency(true);
+
+        provider.complete(&[Message::user("hi")], &[], "").await.unwrap();
+        provider.complete(&[Message::user("again")], &[], "").await.unwrap();
+
+        let requests = captured.lock().unwrap().clone();
+        assert_eq!(requests.len(), 3);
+        let keys: Vec<_> = requests.iter().map(|r| r.header("idempotency-key").unwrap().to_string()).collect();
+        assert_eq!(keys[0].len(), 36);
+        assert_eq!(keys[0], keys[1], "a retry reuses the key");
+        assert_ne!(keys[1], keys[2], "a new request gets a new key");
+    }
+
+    #[tokio::test]
+    async fn idempotency_key_is_off_by_default() {
+        let (base_url, captured) = mock_http::serve(vec![(200, mock_http::chat_completion("ok"))]).await;
+        let provider = OpenAiProvider::new("key", "gpt-4o").with_base_url(base_url);
+
+        provider.complete(&[Message::user("hi")], &[], "").await.unwrap();
+        assert_eq!(captured.lock().unwrap()[0].header("idempotency-key"), None);
+    }
+
+    #[tokio::test]
+    async fn env_headers_are_attached_to_every_request() {
+        std::env::set_var("MINI_AGENT_TEST_TRACE_ID", "trace-123");
+        let (base_url, captured) = mock_http::serve(vec![(200, mock_http::chat_completion("ok"))]).await;
+        let config: mini_agent::ProviderConfig = serde_json::from_value(serde_json::json!({
+            "model": "llama3",
+            "base_url": base_url,
+            "env_headers": {
+                "X-Trace-Id": "MINI_AGENT_TEST_TRACE_ID",
+                "X-Unset": "MINI_AGENT_TEST_UNSET_VAR"
+            }
+        }))
+        .unwrap();
+        let provider = OllamaProvider::from_config(config).unwrap();
+
+        provider.complete(&[Message::user("hi")], &[], "").await.unwrap();
+
+        let request = captured.lock().unwrap()[0].clone();
+        assert_eq!(request.header("x-trace-id"), Some("trace-123"));
+        assert_eq!(request.header("x-unset"), None);
+    }
+
+    #[tokio::test]
+    async fn ollama_generate_posts_rendered_prompt() {
+        let reply = r#"{"model":"qwen2.5:7b","response":" Hello! ","done":true,"prompt_eval_count":12,"eval_count":3}"#;
+        let (base_url, captured) = mock_http::serve(vec![(200, reply.into())]).await;
+        let provider = OllamaGenerateProvider::new("qwen2.5:7b", ChatTemplate::ChatMl).with_base_url(base_url);
+
+        let completion = provider.complete(&[Message::user("Hi")], &[], "").await.unwrap();
+        assert_eq!(completion.content.as_deref(), Some("Hello!"));
+        assert_eq!(completion.usage.unwrap().total_tokens, 15);
+
+        let request = captured.lock().unwrap()[0].clone();
+        assert_eq!(request.request_line, "POST /api/generate HTTP/1.1");
+        let body: serde_json::Value = serde_json::from_str(&request.body).unwrap();
+        assert_eq!(body["prompt"], "<|im_start|>user\nHi<|im_end|>\n<|im_start|>assistant\n");
+        assert_eq!(body["stream"], false);
+    }
+
+    #[tokio::test]
+    async fn proxy_keeps_config_headers() {
+        std::env::set_var("MINI_AGENT_TEST_PROXY_TRACE", "trace-456");
+        let (proxy_url, captured) = mock_http::serve(vec![
+            (200, mock_http::chat_completion("one")),
+            (200, mock_http::chat_completion("two")),
+        ])
+        .await;
+        let config = || mini_agent::ProviderConfig {
+            model: "llama3".into(),
+            base_url: Some("http://ollama.internal:11434".into()),
+            timeout: Some(std::time::Duration::from_secs(5)),
+            env_headers: [("X-Trace-Id".to_string(), "MINI_AGENT_TEST_PROXY_TRACE".to_string())].into(),
+            ..Default::default()
+        };
+
+        let chained = OllamaProvider::from_config(config()).unwrap().with_proxy(&proxy_url).unwrap();
+        chained.complete(&[Message::user("hi")], &[], "").await.unwrap();
+        let configured =
+            OllamaProvider::from_config(mini_agent::ProviderConfig { proxy: Some(proxy_url), ..config() }).unwrap();
+        configured.complete(&[Message::user("hi")], &[], "").await.unwrap();
+
+        let requests = captured.lock().unwrap().clone();
+        assert_eq!(requests.len(), 2);
+        for request in &requests {
+            assert_eq!(request.request_line, "POST http://ollama.internal:11434/v1/chat/completions HTTP/1.1");
+            assert_eq!(request.header("x-trace-id"), Some("trace-456"));
+        }
     }
 }
 
 // ─────────────────────────────────────────────────────────────────────────────
-// Error type tests
+// Streaming tests
 // ─────────────────────────────────────────────────────────────────────────────
 
 #[cfg(test)]
-mod error_tests {
-    use mini_agent::AgentError;
+mod streaming_tests {
+    use mini_agent::providers::anthropic::AnthropicStream;
+    use mini_agent::providers::sse::{SseDecoder, SseEvent};
+    use mini_agent::providers::OpenAiStream;
+    use mini_agent::{LlmProvider, Message, OpenAiProvider, Usage};
+    use serde_json::json;
+
+    const ANTHROPIC_EVENTS: &str = "event: message_start\n\
+data: {\"type\":\"message_start\",\"message\":{\"id\":\"msg_1\",\"content\":[],\"usage\":{\"input_tokens\":25,\"output_tokens\":1}}}\n\
+\n\
+event: content_block_start\n\
+data: {\"type\":\"content_block_start\",\"index\":0,\"content_block\":{\"type\":\"text\",\"text\":\"\"}}\n\
+\n\
+event: content_block_delta\n\
+data: {\"type\":\"content_block_delta\",\"index\":0,\"delta\":{\"type\":\"text_delta\",\"text\":\"Let me \"}}\n\
+\n\
+event: content_block_delta\n\
+data: {\"type\":\"content_block_delta\",\"index\":0,\"delta\":{\"type\":\"text_delta\",\"text\":\"add that.\"}}\n\
+\n\
+event: content_block_stop\n\
+data: {\"type\":\"content_block_stop\",\"index\":0}\n\
+\n\
+event: content_block_start\n\
+data: {\"type\":\"content_block_start\",\"index\":1,\"content_block\":{\"type\":\"tool_use\",\"id\":\"toolu_1\",\"name\":\"add_numbers\",\"input\":{}}}\n\
+\n\
+event: content_block_delta\n\
+data: {\"type\":\"content_block_delta\",\"index\":1,\"delta\":{\"type\":\"input_json_delta\",\"partial_json\":\"{\\\"a\\\": 2\"}}\n\
+\n\
+event: content_block_delta\n\
+data: {\"type\":\"content_block_delta\",\"index\":1,\"delta\":{\"type\":\"input_json_delta\",\"partial_json\":\", \\\"b\\\": 3}\"}}\n\
+\n\
+event: content_block_stop\n\
+data: {\"type\":\"content_block_stop\",\"index\":1}\n\
+\n\
+event: message_delta\n\
+data: {\"type\":\"message_delta\",\"delta\":{\"stop_reason\":\"tool_use\"},\"usage\":{\"output_tokens\":15}}\n\
+\n\
+event: message_stop\n\
+data: {\"type\":\"message_stop\"}\n\
+\n";
 
     #[test]
-    fn tool_not_found_display() {
-        let err = AgentError::ToolNotFound("my_tool".to_string());
-        assert!(err.to_string().contains("my_tool"));
+    fn sse_decoder_handles_events_split_across_chunks() {
+        let mut decoder = SseDecoder::new();
+        let mut events = decoder.push(b"event: ping\ndata: {\"a\"");
+        assert!(events.is_empty());
+        events.extend(decoder.push(b":1}\r\n\r\ndata: second\n\n"));
+
+        assert_eq!(
+            events,
+            vec![
+                SseEvent { event: Some("ping".into()), data: "{\"a\":1}".into() },
+                SseEvent { event: None, data: "second".into() },
+            ]
+        );
     }
 
     #[test]
-    fn tool_error_display() {
-        let err = AgentError::ToolError("bad input".to_string());
-        assert!(err.to_string().contains("bad input"));
+    fn sse_decoder_joins_utf8_characters_split_across_chunks() {
+        let body = "data: caf\u{e9} \u{1f600}\n\n".as_bytes();
+        // Split inside the two-byte é, and again inside the four-byte emoji
+        let e_acute = body.iter().position(|&b| b == 0xc3).unwrap();
+        let emoji = body.iter().position(|&b| b == 0xf0).unwrap();
+        let mut decoder = SseDecoder::new();
+        let mut events = decoder.push(&body[..e_acute + 1]);
+        events.extend(decoder.push(&body[e_acute + 1..emoji + 2]));
+        events.extend(decoder.push(&body[emoji + 2..]));
+
+        assert_eq!(events, vec![SseEvent { event: None, data: "caf\u{e9} \u{1f600}".into() }]);
+
+        // Bytes that can never be valid are still replaced, not held back
+        let events = SseDecoder::new().push(b"data: a\xffb\n\n");
+        assert_eq!(events[0].data, "a\u{fffd}b");
     }
 
     #[test]
-    fn max_iterations_display() {
-        let err = AgentError::MaxIterations;
-        assert!(!err.to_string().is_empty());
+    fn anthropic_stream_reassembles_text_and_tool_args() {
+        let mut decoder = SseDecoder::new();
+        let mut stream = AnthropicStream::default();
+        let mut deltas = vec![];
+
+        // Feed in small uneven chunks to exercise buffering
+        for chunk in ANTHROPIC_EVENTS.as_bytes().chunks(37) {
+            for event in decoder.push(chunk) {
+                if let Some(delta) = stream.push(&event).unwrap() {
+                    deltas.push(delta);
+                }
+            }
+        }
+        let completion = stream.finish().unwrap();
+
+        assert_eq!(deltas, vec!["Let me ", "add that."]);
+        assert_eq!(completion.content.as_deref(), Some("Let me add that."));
+        assert_eq!(completion.tool_calls.len(), 1);
+        assert_eq!(completion.tool_calls[0].id, "toolu_1");
+        assert_eq!(completion.tool_calls[0].name, "add_numbers");
+        assert_eq!(completion.tool_calls[0].args, json!({ "a": 2, "b": 3 }));
+        assert_eq!(completion.raw_tool_calls.unwrap()[0]["function"]["arguments"], "{\"a\":2,\"b\":3}");
+        let usage = completion.usage.unwrap();
+        assert_eq!((usage.prompt_tokens, usage.completion_tokens, usage.total_tokens), (25, 15, 40));
     }
 
     #[test]
-    fn invalid_response_display() {
-        let err = AgentError::InvalidResponse("missing field".to_string());
-        assert!(err.to_string().contains("missing field"));
+    fn anthropic_stream_error_event_is_reported() {
+        let mut stream = AnthropicStream::default();
+        let event = SseEvent {
+            event: Some("error".into()),
+            data: json!({ "type": "error", "error": { "type": "overloaded_error", "message": "Overloaded" } }).to_string(),
+        };
+        let err = stream.push(&event).unwrap_err();
+        assert!(err.to_string().contains("Overloaded"));
+    }
+
+
+    fn openai_chunks() -> String {
+        let chunks = [
+            json!({ "choices": [{ "index": 0, "delta": { "role": "assistant", "content": "" } }] }),
+            json!({ "choices": [{ "index": 0, "delta": { "content": "Hel" } }] }),
+            json!({ "choices": [{ "index": 0, "delta": { "content": "lo" } }] }),
+            json!({ "choices": [{ "index": 0, "delta": {}, "finish_reason": "stop" }] }),
+            json!({ "choices": [], "usage": { "prompt_tokens": 12, "completion_tokens": 2, "total_tokens": 14 } }),
+        ];
+        let mut body: String = chunks.iter().map(|c| format!("data: {c}\n\n")).collect();
+        body.push_str("data: [DONE]\n\n");
+        body
     }
 
     #[test]
-    fn provider_error_display() {
-        let err = AgentError::ProviderError("timeout".to_string());
-        assert!(err.to_string().contains("timeout"));
+    fn openai_stream_captures_usage_from_final_chunk() {
+        let mut decoder = SseDecoder::new();
+        let mut stream = OpenAiStream::default();
+        let mut deltas = vec![];
+        for event in decoder.push(openai_chunks().as_bytes()) {
+            deltas.extend(stream.push(&event).unwrap());
+        }
+        let completion = stream.finish().unwrap();
+
+        assert_eq!(deltas, vec!["Hel", "lo"]);
+        assert_eq!(completion.content.as_deref(), Some("Hello"));
+        assert_eq!(
+            completion.usage,
+            Some(Usage { prompt_tokens: 12, completion_tokens: 2, total_tokens: 14 })
+        );
     }
 
     #[test]
-    fn json_error_from_serde() {
-        let bad_json = "{ not valid }";
-        let serde_err = serde_json::from_str::<serde_json::Value>(bad_json).unwrap_err();
-        let agent_err: AgentError = serde_err.into();
-        assert!(matches!(agent_err, AgentError::Json(_)));
+    fn openai_stream_assembles_tool_call_fragments() {
+        let chunks = [
+            json!({ "choices": [{ "delta": { "tool_calls": [{ "index": 0, "id": "call_9", "type": "function", "function": { "name": "add_numbers", "arguments": "" } }] } }] }),
+            json!({ "choices": [{ "delta": { "tool_calls": [{ "index": 0, "function": { "arguments": "{\"a\":1," } }] } }] }),
+            json!({ "choices": [{ "delta": { "tool_calls": [{ "index": 0, "function": { "arguments": "\"b\":2}" } }] } }] }),
+        ];
+        let mut stream = OpenAiStream::default();
+        for chunk in chunks {
+            stream.push(&SseEvent { event: None, data: chunk.to_string() }).unwrap();
+        }
+        let completion = stream.finish().unwrap();
+
+        assert!(completion.content.is_none());
+        assert_eq!(completion.tool_calls[0].id, "call_9");
+        assert_eq!(completion.tool_calls[0].args, json!({ "a": 1, "b": 2 }));
+        assert!(completion.usage.is_none());
+    }
+
+    #[tokio::test]
+    async fn openai_complete_stream_requests_usage() {
+        let (base_url, captured) = super::mock_http::serve(vec![(200, openai_chunks())]).await;
+        let provider = OpenAiProvider::new("key", "gpt-4o-mini").with_base_url(base_url);
+
+        let mut streamed = String::new();
+        let completion = provider
+            .complete_stream(&[Message::user("hi")], &[], "", &mut |d: &str| streamed.push_str(d))
+            .await
+            .unwrap();
+
+        assert_eq!(streamed, "Hello");
+        assert_eq!(completion.usage.unwrap().total_tokens, 14);
+        let body: serde_json::Value = serde_json::from_str(&captured.lock().unwrap()[0].body).unwrap();
+        assert_eq!(body["stream"], true);
+        assert_eq!(body["stream_options"], json!({ "include_usage": true }));
+    }
+
+    #[test]
+    fn partial_json_parses_grow_monotonically() {
+        let document = r#"```json
+{"name": "Ada \"Countess\" Lovelace", "born": 1815, "fields": ["math", "poetry"], "meta": {"verified": true}}
+```"#;
+        let mut json = mini_agent::PartialJson::new();
+        let mut parses = vec![];
+        for chunk in document.as_bytes().chunks(4) {
+            if let Some(value) = json.push(std::str::from_utf8(chunk).unwrap()) {
+                parses.push(value);
+            }
+        }
+
+        assert_eq!(parses.first(), Some(&json!({})));
+        for pair in parses.windows(2) {
+            let (before, after) = (pair[0].as_object().unwrap(), pair[1].as_object().unwrap());
+            assert!(before.keys().all(|k| after.contains_key(k)), "{before:?} -> {after:?}");
+            if let (Some(a), Some(b)) = (before.get("name").and_then(|n| n.as_str()), after.get("name").and_then(|n| n.as_str())) {
+                assert!(b.starts_with(a), "{a:?} -> {b:?}");
+            }
+        }
+        assert_eq!(
+            json.value(),
+            Some(json!({
+                "name": "Ada \"Countess\" Lovelace",
+                "born": 1815,
+                "fields": ["math", "poetry"],
+                "meta": { "verified": true }
+            }))
+        );
+    }
+
+    #[test]
+    fn partial_json_is_none_before_document_starts() {
+        let mut json = mini_agent::PartialJson::new();
+        assert_eq!(json.push("Sure, here"), None);
+        assert_eq!(json.push(" it is: [1, 2"), Some(json!([1, 2])));
+        assert_eq!(json.push(", tr"), Some(json!([1, 2])));
+        assert_eq!(json.push("ue]"), Some(json!([1, 2, true])));
+    }
+}
+
+// ─────────────────────────────────────────────────────────────────────────────
+// Cassette (record/replay) provider tests
+// ─────────────────────────────────────────────────────────────────────────────
+
+#[cfg(all(test, feature = "testing"))]
+mod cassette_tests {
+    use mini_agent::{AgentError, CassetteProvider, Completion, LlmProvider, Message, Tool, ToolCall};
+    use async_trait::async_trait;
+    use serde_json::json;
+    use std::sync::{Arc, Mutex};
+
+    /// Stands in for a real API: answers with a tool call and counts requests.
+    struct LiveProvider {
+        requests: Arc<Mutex<usize>>,
+    }
+
+    #[async_trait]
+    impl LlmProvider for LiveProvider {
+        fn provider_name(&self) -> &str { "Live" }
+
+        async fn complete(
+            &self,
+            messages: &[Message],
+            _tools: &[&dyn Tool],
+            _model: &str,
+        ) -> Result<Completion, AgentError> {
+            *self.requests.lock().unwrap() += 1;
+            Ok(Completion {
+                content: Some(format!("re: {}", messages.last().unwrap().content)),
+                tool_calls: vec![ToolCall { id: "call_1".into(), name: "add_numbers".into(), args: json!({ "a": 1 }) }],
+                usage: Some(mini_agent::Usage { prompt_tokens: 3, completion_tokens: 2, total_tokens: 5 }),
+                ..Default::default()
+            })
+        }
+    }
+
+    fn cassette_path(name: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("mini-agent-{name}-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[tokio::test]
+    async fn records_once_then_replays_offline() {
+        let path = cassette_path("cassette");
+        let requests = Arc::new(Mutex::new(0));
+        let recorder =
+            CassetteProvider::new(Box::new(LiveProvider { requests: requests.clone() }), &path).unwrap();
+
+        let first = recorder.complete(&[Message::user("hello")], &[], "m").await.unwrap();
+        let again = recorder.complete(&[Message::user("hello")], &[], "m").await.unwrap();
+        assert_eq!(*requests.lock().unwrap(), 1);
+        assert_eq!(again.content, first.content);
+
+        // A fresh replay-only provider answers from the file alone
+        let replayer = CassetteProvider::replay(&path).unwrap();
+        let replayed = replayer.complete(&[Message::user("hello")], &[], "m").await.unwrap();
+        assert_eq!(replayed.content.as_deref(), Some("re: hello"));
+        assert_eq!(replayed.tool_calls[0].args, json!({ "a": 1 }));
+        assert_eq!(replayed.usage.unwrap().total_tokens, 5);
+
+        let missing = replayer.complete(&[Message::user("something else")], &[], "m").await;
+        assert!(matches!(missing, Err(AgentError::ProviderError(msg)) if msg.contains("no recorded response")));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn images_are_part_of_the_request_key() {
+        let path = cassette_path("images");
+        let requests = Arc::new(Mutex::new(0));
+        let recorder =
+            CassetteProvider::new(Box::new(LiveProvider { requests: requests.clone() }), &path).unwrap();
+        let with_image = |data: &str| {
+            let mut message = Message::user("what is this?");
+            message.images.push(mini_agent::ImageContent { media_type: "image/png".into(), data_base64: data.into() });
+            message
+        };
+
+        recorder.complete(&[with_image("AAAA")], &[], "m").await.unwrap();
+        recorder.complete(&[with_image("BBBB")], &[], "m").await.unwrap();
+        assert_eq!(*requests.lock().unwrap(), 2, "a different image must not replay the first answer");
+        recorder.complete(&[with_image("AAAA")], &[], "m").await.unwrap();
+        assert_eq!(*requests.lock().unwrap(), 2);
+        std::fs::remove_file(&path).unwrap();
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn replay_requires_an_existing_cassette() {
+        let path = cassette_path("missing");
+        assert!(matches!(CassetteProvider::replay(&path), Err(AgentError::Io(_))));
+    }
+}